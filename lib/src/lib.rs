@@ -1,14 +1,78 @@
 #![crate_type = "lib"]
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 pub const MEMORY_MAP_SIZE: usize = 1024;
 
+/// Size of a page in the UEFI memory map.
+pub const UEFI_PAGE_SIZE: u64 = 4096;
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct SikiOSArguments {
     pub frame_buffer_info: FrameBufferInfo,
     pub mode_info: ModeInfo,
     pub memory_map: MemoryMap,
+    pub kernel_image: KernelImageInfo,
+    pub command_line: CommandLine,
+    /// Physical address of the ACPI RSDP, or 0 if the firmware has none.
+    pub rsdp_address: u64,
+}
+
+pub const COMMAND_LINE_SIZE: usize = 256;
+
+/// Kernel command line read by the loader, as whitespace separated options
+/// such as `memtest` or `memtest=continue`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CommandLine {
+    pub bytes: [u8; COMMAND_LINE_SIZE],
+    pub len: usize,
+}
+
+impl CommandLine {
+    /// Copies `s`, truncated to `COMMAND_LINE_SIZE` bytes.
+    pub fn new(s: &[u8]) -> Self {
+        let len = s.len().min(COMMAND_LINE_SIZE);
+        let mut bytes = [0; COMMAND_LINE_SIZE];
+        bytes[..len].copy_from_slice(&s[..len]);
+        CommandLine {
+            bytes: bytes,
+            len: len,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // 途中で切れた文字があれば、そこまでを使う
+        match core::str::from_utf8(&self.bytes[..self.len]) {
+            Ok(s) => s,
+            Err(e) => core::str::from_utf8(&self.bytes[..e.valid_up_to()]).unwrap(),
+        }
+    }
+
+    pub fn options(&self) -> core::str::SplitWhitespace<'_> {
+        self.as_str().split_whitespace()
+    }
+
+    /// Whether the option `name` is present, with or without a value.
+    pub fn contains(&self, name: &str) -> bool {
+        self.options()
+            .any(|option| option.split('=').next() == Some(name))
+    }
+
+    /// The value of the last `name=value` option.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.options()
+            .filter_map(|option| option.split_once('='))
+            .filter(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+            .next_back()
+    }
+}
+
+impl Default for CommandLine {
+    fn default() -> Self {
+        CommandLine::new(&[])
+    }
 }
 
 #[repr(C)]
@@ -18,6 +82,36 @@ pub struct FrameBufferInfo {
     pub size: usize,
 }
 
+// フレームバッファは物理アドレスを指しているだけなので、コア間で共有してよい
+unsafe impl Send for FrameBufferInfo {}
+unsafe impl Sync for FrameBufferInfo {}
+
+pub const KERNEL_SEGMENT_MAX: usize = 16;
+
+/// Segment permission bits, same values as the ELF `p_flags`.
+pub const SEGMENT_FLAG_EXECUTE: u32 = 0x1;
+pub const SEGMENT_FLAG_WRITE: u32 = 0x2;
+pub const SEGMENT_FLAG_READ: u32 = 0x4;
+
+/// Physical pages the loader allocated for the kernel image.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KernelImageInfo {
+    pub physical_start: u64,
+    pub number_of_pages: u64,
+    pub segments: [KernelSegment; KERNEL_SEGMENT_MAX],
+    pub segments_len: usize,
+}
+
+/// A `PT_LOAD` segment of the kernel image.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct KernelSegment {
+    pub virtual_start: u64,
+    pub memory_size: u64,
+    pub flags: u32,
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum PixelFormat {
@@ -78,44 +172,6 @@ impl From<uefi::proto::console::gop::ModeInfo> for ModeInfo {
     }
 }
 
-// #[repr(C)]
-// #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-// pub struct MemoryMapIter<'buffer> {
-//     descriptor: &'buffer [MemoryDescriptor; MEMORY_MAP_SIZE],
-//     index: usize,
-// }
-
-// impl<'buffer> Iterator for MemoryMapIter<'buffer> {
-//     type Item = MemoryDescriptor;
-
-//     fn next(&mut self) -> Option<Self::Item> {
-//         self.index += 1;
-
-//         if self.index >= self.descriptor.len() {
-//             None
-//         } else {
-//             Some(self.descriptor[self.index])
-//         }
-//     }
-// }
-
-// #[cfg(feature = "uefi-feature")]
-// impl<'buffer> From<uefi::table::boot::MemoryMapIter<'buffer>> for MemoryMapIter<'buffer> {
-//     fn from(value: uefi::table::boot::MemoryMapIter<'buffer>) -> Self {
-//         let mut _descriptor: &'buffer [MemoryDescriptor; MEMORY_MAP_SIZE] =
-//             &[Default::default(); MEMORY_MAP_SIZE];
-
-//         for (i, val) in value.enumerate() {
-//             _descriptor[i] = (*val).into();
-//         }
-
-//         MemoryMapIter {
-//             descriptor: &_descriptor,
-//             index: 0,
-//         }
-//     }
-// }
-
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct MemoryMap {
@@ -133,6 +189,119 @@ pub struct MemoryDescriptor {
     pub attribute: u64,
 }
 
+impl MemoryMap {
+    /// Descriptors actually filled in by the loader.
+    pub fn descriptors(&self) -> &[MemoryDescriptor] {
+        &self.map[..self.len]
+    }
+
+    pub fn iter(&self) -> core::slice::Iter<'_, MemoryDescriptor> {
+        self.descriptors().iter()
+    }
+
+    /// Descriptors of the given type.
+    pub fn iter_by_type(
+        &self,
+        memory_type: MemoryType,
+    ) -> impl Iterator<Item = &MemoryDescriptor> + '_ {
+        self.iter()
+            .filter(move |descriptor| descriptor.memory_type == memory_type)
+    }
+
+    /// Physically adjacent descriptors of the same type, merged into one region.
+    ///
+    /// Descriptors are merged in map order, so the map should be sorted by address
+    /// (UEFI firmware returns it sorted).
+    pub fn merged_regions(&self) -> MergedRegions<'_> {
+        MergedRegions {
+            descriptors: self.descriptors(),
+            index: 0,
+        }
+    }
+
+    /// The descriptor whose range contains `addr`.
+    pub fn find(&self, addr: u64) -> Option<&MemoryDescriptor> {
+        self.iter().find(|descriptor| descriptor.contains(addr))
+    }
+
+    /// Bytes of memory that are free when the kernel starts.
+    pub fn total_usable_bytes(&self) -> u64 {
+        self.iter_by_type(MemoryType::CONVENTIONAL)
+            .map(|descriptor| descriptor.size())
+            .sum()
+    }
+}
+
+impl<'a> IntoIterator for &'a MemoryMap {
+    type Item = &'a MemoryDescriptor;
+    type IntoIter = core::slice::Iter<'a, MemoryDescriptor>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl MemoryDescriptor {
+    pub fn size(&self) -> u64 {
+        self.number_of_pages * UEFI_PAGE_SIZE
+    }
+
+    /// End of the range (exclusive).
+    pub fn physical_end(&self) -> u64 {
+        self.physical_start + self.size()
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        self.physical_start <= addr && addr < self.physical_end()
+    }
+}
+
+/// A physical range `[start, end)` made of one or more descriptors of the same type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub memory_type: MemoryType,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl MemoryRegion {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MergedRegions<'a> {
+    descriptors: &'a [MemoryDescriptor],
+    index: usize,
+}
+
+impl<'a> Iterator for MergedRegions<'a> {
+    type Item = MemoryRegion;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = self.descriptors.get(self.index)?;
+        let mut region = MemoryRegion {
+            memory_type: first.memory_type,
+            start: first.physical_start,
+            end: first.physical_end(),
+        };
+        self.index += 1;
+
+        while let Some(descriptor) = self.descriptors.get(self.index) {
+            if descriptor.memory_type != region.memory_type
+                || descriptor.physical_start != region.end
+            {
+                break;
+            }
+            region.end = descriptor.physical_end();
+            self.index += 1;
+        }
+
+        Some(region)
+    }
+}
+
 #[cfg(feature = "uefi-feature")]
 impl From<uefi::table::boot::MemoryDescriptor> for MemoryDescriptor {
     fn from(value: uefi::table::boot::MemoryDescriptor) -> Self {
@@ -146,7 +315,7 @@ impl From<uefi::table::boot::MemoryDescriptor> for MemoryDescriptor {
     }
 }
 
-#[repr(C)]
+#[repr(u32)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
 pub enum MemoryType {
     /// This enum variant is not used.
@@ -186,28 +355,254 @@ pub enum MemoryType {
     PAL_CODE = 13,
     /// Memory region which is usable and is also non-volatile.
     PERSISTENT_MEMORY = 14,
+    /// OEM, OS-reserved or otherwise unknown type, with its raw value.
+    ///
+    /// The kernel must treat it as reserved.
+    Other(u32),
+}
+
+impl MemoryType {
+    /// The raw `EFI_MEMORY_TYPE` value.
+    pub fn raw(&self) -> u32 {
+        match *self {
+            MemoryType::RESERVED => 0,
+            MemoryType::LOADER_CODE => 1,
+            MemoryType::LOADER_DATA => 2,
+            MemoryType::BOOT_SERVICES_CODE => 3,
+            MemoryType::BOOT_SERVICES_DATA => 4,
+            MemoryType::RUNTIME_SERVICES_CODE => 5,
+            MemoryType::RUNTIME_SERVICES_DATA => 6,
+            MemoryType::CONVENTIONAL => 7,
+            MemoryType::UNUSABLE => 8,
+            MemoryType::ACPI_RECLAIM => 9,
+            MemoryType::ACPI_NON_VOLATILE => 10,
+            MemoryType::MMIO => 11,
+            MemoryType::MMIO_PORT_SPACE => 12,
+            MemoryType::PAL_CODE => 13,
+            MemoryType::PERSISTENT_MEMORY => 14,
+            MemoryType::Other(value) => value,
+        }
+    }
+}
+
+impl From<u32> for MemoryType {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => Self::RESERVED,
+            1 => Self::LOADER_CODE,
+            2 => Self::LOADER_DATA,
+            3 => Self::BOOT_SERVICES_CODE,
+            4 => Self::BOOT_SERVICES_DATA,
+            5 => Self::RUNTIME_SERVICES_CODE,
+            6 => Self::RUNTIME_SERVICES_DATA,
+            7 => Self::CONVENTIONAL,
+            8 => Self::UNUSABLE,
+            9 => Self::ACPI_RECLAIM,
+            10 => Self::ACPI_NON_VOLATILE,
+            11 => Self::MMIO,
+            12 => Self::MMIO_PORT_SPACE,
+            13 => Self::PAL_CODE,
+            14 => Self::PERSISTENT_MEMORY,
+            other => Self::Other(other),
+        }
+    }
 }
 
 #[cfg(feature = "uefi-feature")]
 impl From<uefi::table::boot::MemoryType> for MemoryType {
     fn from(value: uefi::table::boot::MemoryType) -> Self {
-        match value {
-            uefi::table::boot::MemoryType::RESERVED => Self::RESERVED,
-            uefi::table::boot::MemoryType::LOADER_CODE => Self::LOADER_CODE,
-            uefi::table::boot::MemoryType::LOADER_DATA => Self::LOADER_DATA,
-            uefi::table::boot::MemoryType::BOOT_SERVICES_CODE => Self::BOOT_SERVICES_CODE,
-            uefi::table::boot::MemoryType::BOOT_SERVICES_DATA => Self::BOOT_SERVICES_DATA,
-            uefi::table::boot::MemoryType::RUNTIME_SERVICES_CODE => Self::RUNTIME_SERVICES_CODE,
-            uefi::table::boot::MemoryType::RUNTIME_SERVICES_DATA => Self::RUNTIME_SERVICES_DATA,
-            uefi::table::boot::MemoryType::CONVENTIONAL => Self::CONVENTIONAL,
-            uefi::table::boot::MemoryType::UNUSABLE => Self::UNUSABLE,
-            uefi::table::boot::MemoryType::ACPI_RECLAIM => Self::ACPI_RECLAIM,
-            uefi::table::boot::MemoryType::ACPI_NON_VOLATILE => Self::ACPI_NON_VOLATILE,
-            uefi::table::boot::MemoryType::MMIO => Self::MMIO,
-            uefi::table::boot::MemoryType::MMIO_PORT_SPACE => Self::MMIO_PORT_SPACE,
-            uefi::table::boot::MemoryType::PAL_CODE => Self::PAL_CODE,
-            uefi::table::boot::MemoryType::PERSISTENT_MEMORY => Self::PERSISTENT_MEMORY,
-            _ => panic!(),
+        // 標準以外の値 (OEMやOS用の予約値) もそのまま残す
+        Self::from(value.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(memory_type: MemoryType, physical_start: u64, pages: u64) -> MemoryDescriptor {
+        MemoryDescriptor {
+            memory_type: memory_type,
+            physical_start: physical_start,
+            virtual_start: 0,
+            number_of_pages: pages,
+            attribute: 0,
         }
     }
+
+    fn memory_map(descriptors: &[MemoryDescriptor]) -> MemoryMap {
+        let mut map = [MemoryDescriptor::default(); MEMORY_MAP_SIZE];
+        map[..descriptors.len()].copy_from_slice(descriptors);
+        MemoryMap {
+            map: map,
+            len: descriptors.len(),
+        }
+    }
+
+    fn sample_map() -> MemoryMap {
+        memory_map(&[
+            descriptor(MemoryType::BOOT_SERVICES_CODE, 0x0, 1),
+            descriptor(MemoryType::CONVENTIONAL, 0x1000, 0x9f),
+            descriptor(MemoryType::CONVENTIONAL, 0x100000, 0x100),
+            descriptor(MemoryType::CONVENTIONAL, 0x200000, 0x200),
+            descriptor(MemoryType::LOADER_DATA, 0x400000, 0x10),
+            descriptor(MemoryType::ACPI_RECLAIM, 0x410000, 0x4),
+            descriptor(MemoryType::CONVENTIONAL, 0x414000, 0x10),
+        ])
+    }
+
+    #[test]
+    fn memory_type_keeps_unknown_raw_values() {
+        for raw in 0..15 {
+            let memory_type = MemoryType::from(raw);
+            assert!(!matches!(memory_type, MemoryType::Other(_)));
+            assert_eq!(memory_type.raw(), raw);
+        }
+        assert_eq!(MemoryType::from(7), MemoryType::CONVENTIONAL);
+        assert_eq!(MemoryType::from(15), MemoryType::Other(15));
+        assert_eq!(
+            MemoryType::from(0x7000_0000),
+            MemoryType::Other(0x7000_0000)
+        );
+        assert_eq!(MemoryType::from(0x8000_0001).raw(), 0x8000_0001);
+    }
+
+    #[test]
+    fn other_memory_types_are_not_usable() {
+        let map = memory_map(&[
+            descriptor(MemoryType::CONVENTIONAL, 0x100000, 0x10),
+            descriptor(MemoryType::Other(0x8000_0000), 0x110000, 0x10),
+            descriptor(MemoryType::Other(0x8000_0000), 0x120000, 0x10),
+        ]);
+        assert_eq!(map.total_usable_bytes(), 0x10 * UEFI_PAGE_SIZE);
+        assert_eq!(
+            map.merged_regions().nth(1),
+            Some(MemoryRegion {
+                memory_type: MemoryType::Other(0x8000_0000),
+                start: 0x110000,
+                end: 0x130000,
+            })
+        );
+    }
+
+    #[test]
+    fn command_line_options() {
+        let command_line = CommandLine::new(b"memtest=continue  memtest.seed=42\nquiet\n");
+        assert!(command_line.contains("memtest"));
+        assert!(command_line.contains("quiet"));
+        assert!(!command_line.contains("memtest.seed=42"));
+        assert!(!command_line.contains("mem"));
+        assert_eq!(command_line.get("memtest"), Some("continue"));
+        assert_eq!(command_line.get("memtest.seed"), Some("42"));
+        assert_eq!(command_line.get("quiet"), None);
+        assert_eq!(command_line.options().count(), 3);
+    }
+
+    #[test]
+    fn command_line_is_truncated() {
+        let long = [b'a'; COMMAND_LINE_SIZE + 10];
+        let command_line = CommandLine::new(&long);
+        assert_eq!(command_line.len, COMMAND_LINE_SIZE);
+        assert_eq!(command_line.as_str().len(), COMMAND_LINE_SIZE);
+
+        // マルチバイト文字の途中で切れても、そこまでは読める
+        let mut bytes = [b'a'; COMMAND_LINE_SIZE];
+        bytes[COMMAND_LINE_SIZE - 1] = 0xe3;
+        let command_line = CommandLine::new(&bytes);
+        assert_eq!(command_line.as_str().len(), COMMAND_LINE_SIZE - 1);
+
+        assert_eq!(CommandLine::default().options().count(), 0);
+    }
+
+    #[test]
+    fn iter_stops_at_len() {
+        let map = sample_map();
+        assert_eq!(map.iter().count(), 7);
+        assert_eq!((&map).into_iter().count(), 7);
+        assert_eq!(memory_map(&[]).iter().count(), 0);
+    }
+
+    #[test]
+    fn iter_by_type() {
+        let map = sample_map();
+        let starts: Vec<u64> = map
+            .iter_by_type(MemoryType::CONVENTIONAL)
+            .map(|descriptor| descriptor.physical_start)
+            .collect();
+        assert_eq!(starts, [0x1000, 0x100000, 0x200000, 0x414000]);
+        assert_eq!(map.iter_by_type(MemoryType::MMIO).count(), 0);
+    }
+
+    #[test]
+    fn merged_regions_join_adjacent_descriptors_of_same_type() {
+        let map = sample_map();
+        let regions: Vec<MemoryRegion> = map.merged_regions().collect();
+        assert_eq!(
+            regions,
+            [
+                MemoryRegion {
+                    memory_type: MemoryType::BOOT_SERVICES_CODE,
+                    start: 0x0,
+                    end: 0x1000,
+                },
+                MemoryRegion {
+                    memory_type: MemoryType::CONVENTIONAL,
+                    start: 0x1000,
+                    end: 0xa0000,
+                },
+                MemoryRegion {
+                    memory_type: MemoryType::CONVENTIONAL,
+                    start: 0x100000,
+                    end: 0x400000,
+                },
+                MemoryRegion {
+                    memory_type: MemoryType::LOADER_DATA,
+                    start: 0x400000,
+                    end: 0x410000,
+                },
+                MemoryRegion {
+                    memory_type: MemoryType::ACPI_RECLAIM,
+                    start: 0x410000,
+                    end: 0x414000,
+                },
+                MemoryRegion {
+                    memory_type: MemoryType::CONVENTIONAL,
+                    start: 0x414000,
+                    end: 0x424000,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn merged_regions_of_empty_map() {
+        assert_eq!(memory_map(&[]).merged_regions().next(), None);
+    }
+
+    #[test]
+    fn find_region_containing_address() {
+        let map = sample_map();
+        assert_eq!(
+            map.find(0x0).unwrap().memory_type,
+            MemoryType::BOOT_SERVICES_CODE
+        );
+        assert_eq!(map.find(0x1fffff).unwrap().physical_start, 0x100000);
+        assert_eq!(map.find(0x200000).unwrap().physical_start, 0x200000);
+        assert_eq!(
+            map.find(0x413fff).unwrap().memory_type,
+            MemoryType::ACPI_RECLAIM
+        );
+        assert_eq!(map.find(0xa0000), None);
+        assert_eq!(map.find(0x424000), None);
+    }
+
+    #[test]
+    fn total_usable_bytes_counts_conventional_memory() {
+        let map = sample_map();
+        assert_eq!(
+            map.total_usable_bytes(),
+            (0x9f + 0x100 + 0x200 + 0x10) * UEFI_PAGE_SIZE
+        );
+        assert_eq!(memory_map(&[]).total_usable_bytes(), 0);
+    }
 }
@@ -0,0 +1,485 @@
+pub mod aml;
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+use alloc::vec::Vec;
+use core::{mem, ptr, slice, str};
+
+use once_cell::sync::OnceCell;
+use x86_64::VirtAddr;
+
+use crate::acpi::fadt::{Fadt, FADT_SIGNATURE};
+use crate::acpi::hpet::HpetTable;
+use crate::acpi::madt::Madt;
+use crate::acpi::mcfg::Mcfg;
+use crate::mmio;
+use crate::paging::{self, CacheType, PAGE_SIZE};
+use crate::print_serial;
+use crate::write::write_to;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// ACPI 1.0のRSDPはxsdt_addressより前の20バイトだけ
+const RSDP_V1_LENGTH: usize = 20;
+
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct Rsdp {
+    pub signature: [u8; 8],
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub revision: u8,
+    pub rsdt_address: u32,
+    // 以降はrevisionが2以上のときだけ有効
+    pub length: u32,
+    pub xsdt_address: u64,
+    pub extended_checksum: u8,
+    pub reserved: [u8; 3],
+}
+
+// すべてのシステム記述テーブルに共通するヘッダ
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    pub fn signature(&self) -> &str {
+        str::from_utf8(&self.signature).unwrap_or("????")
+    }
+}
+
+// Generic Address Structure (レジスタの場所を表す12バイトの構造)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const LENGTH: usize = 12;
+
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+
+    pub fn parse(data: &[u8], offset: usize) -> Self {
+        GenericAddress {
+            address_space: data[offset],
+            bit_width: data[offset + 1],
+            bit_offset: data[offset + 2],
+            access_size: data[offset + 3],
+            address: u64_at(data, offset + 4),
+        }
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.address != 0
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct AcpiTable {
+    pub physical_address: u64,
+    pub virtual_address: u64,
+    pub header: SdtHeader,
+}
+
+impl AcpiTable {
+    // ヘッダを含むテーブル全体
+    pub fn data(&self) -> &'static [u8] {
+        unsafe {
+            slice::from_raw_parts(
+                self.virtual_address as *const u8,
+                self.header.length as usize,
+            )
+        }
+    }
+
+    // ヘッダの後ろの本体
+    pub fn body(&self) -> &'static [u8] {
+        &self.data()[mem::size_of::<SdtHeader>()..]
+    }
+}
+
+struct Acpi {
+    revision: u8,
+    tables: Vec<AcpiTable>,
+}
+
+static ACPI: OnceCell<Acpi> = OnceCell::new();
+
+// RSDPからRSDTまたはXSDTをたどり、チェックサムが正しいテーブルを集める
+pub fn initialize(rsdp_address: u64) -> Result<(), &'static str> {
+    if rsdp_address == 0 {
+        return Err("RSDP is not found");
+    }
+
+    let rsdp_virtual = map_physical(rsdp_address, RSDP_V1_LENGTH as u64)?;
+    let rsdp = unsafe { ptr::read_unaligned(rsdp_virtual as *const Rsdp) };
+    if &rsdp.signature != RSDP_SIGNATURE {
+        return Err("Invalid RSDP signature");
+    }
+    if !is_valid_checksum(rsdp_virtual, RSDP_V1_LENGTH) {
+        return Err("Invalid RSDP checksum");
+    }
+
+    // ACPI 2.0以降ならXSDTの64ビットのアドレスを使う
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        let rsdp_virtual = map_physical(rsdp_address, rsdp.length as u64)?;
+        if !is_valid_checksum(rsdp_virtual, rsdp.length as usize) {
+            return Err("Invalid RSDP extended checksum");
+        }
+        (load_table(rsdp.xsdt_address)?, 8)
+    } else {
+        (load_table(rsdp.rsdt_address as u64)?, 4)
+    };
+
+    let mut tables = Vec::new();
+    for entry in root.body().chunks_exact(entry_size) {
+        let address = if entry_size == 8 {
+            u64_at(entry, 0)
+        } else {
+            u32_at(entry, 0) as u64
+        };
+        match load_table(address) {
+            Ok(table) => tables.push(table),
+            Err(reason) => {
+                let mut buf = [0u8; 128];
+                let _s: &str = write_to::show(
+                    &mut buf,
+                    format_args!("acpi: skipped table at {:016x}: {}\n", address, reason),
+                )
+                .unwrap();
+                print_serial(_s);
+            }
+        }
+    }
+
+    // DSDTはXSDTに載っていないので、FADTからたどって加える
+    let fadt = tables
+        .iter()
+        .find(|table| &table.header.signature == FADT_SIGNATURE)
+        .map(Fadt::parse);
+    if let Some(Ok(fadt)) = fadt {
+        match load_table(fadt.dsdt) {
+            Ok(table) => tables.push(table),
+            Err(reason) => {
+                let mut buf = [0u8; 128];
+                let _s: &str = write_to::show(
+                    &mut buf,
+                    format_args!("acpi: skipped DSDT at {:016x}: {}\n", fadt.dsdt, reason),
+                )
+                .unwrap();
+                print_serial(_s);
+            }
+        }
+    }
+
+    ACPI.set(Acpi {
+        revision: rsdp.revision,
+        tables: tables,
+    })
+    .map_err(|_| "ACPI is already initialized")
+}
+
+pub fn revision() -> Option<u8> {
+    ACPI.get().map(|acpi| acpi.revision)
+}
+
+pub fn tables() -> &'static [AcpiTable] {
+    ACPI.get().map_or(&[], |acpi| acpi.tables.as_slice())
+}
+
+pub fn find_table(signature: &[u8; 4]) -> Option<AcpiTable> {
+    tables()
+        .iter()
+        .find(|table| &table.header.signature == signature)
+        .copied()
+}
+
+// SSDTのように同じシグネチャが複数あるテーブル
+pub fn find_tables(signature: &[u8; 4]) -> impl Iterator<Item = &'static AcpiTable> + '_ {
+    tables()
+        .iter()
+        .filter(move |table| &table.header.signature == signature)
+}
+
+// 見つけたテーブルと、解釈できるテーブルの中身をシリアルに出す
+pub fn dump() {
+    let mut buf = [0u8; 128];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "acpi: revision {}, {} tables\n",
+            revision().unwrap_or(0),
+            tables().len()
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+
+    for table in tables() {
+        let header = table.header;
+        let mut buf = [0u8; 128];
+        let _s: &str = write_to::show(
+            &mut buf,
+            format_args!(
+                "  {} {:016x} length {:5} revision {} OEM {}\n",
+                header.signature(),
+                table.physical_address,
+                { header.length },
+                header.revision,
+                str::from_utf8(&header.oem_id).unwrap_or("??????")
+            ),
+        )
+        .unwrap();
+        print_serial(_s);
+    }
+
+    match Madt::find() {
+        Ok(madt) => dump_madt(&madt),
+        Err(reason) => print_error(reason),
+    }
+    match Fadt::find() {
+        Ok(fadt) => dump_fadt(&fadt),
+        Err(reason) => print_error(reason),
+    }
+    match HpetTable::find() {
+        Ok(hpet) => {
+            let mut buf = [0u8; 128];
+            let _s: &str = write_to::show(
+                &mut buf,
+                format_args!(
+                    "HPET: block id {:08x}, base {:016x}, number {}, minimum tick {}\n",
+                    hpet.event_timer_block_id,
+                    hpet.base_address.address,
+                    hpet.hpet_number,
+                    hpet.minimum_tick
+                ),
+            )
+            .unwrap();
+            print_serial(_s);
+        }
+        Err(reason) => print_error(reason),
+    }
+    match Mcfg::find() {
+        Ok(mcfg) => {
+            for entry in mcfg.entries.iter() {
+                let mut buf = [0u8; 128];
+                let _s: &str = write_to::show(
+                    &mut buf,
+                    format_args!(
+                        "MCFG: segment {} bus {:02x}-{:02x} at {:016x}\n",
+                        entry.segment_group, entry.start_bus, entry.end_bus, entry.base_address
+                    ),
+                )
+                .unwrap();
+                print_serial(_s);
+            }
+        }
+        Err(reason) => print_error(reason),
+    }
+}
+
+fn dump_madt(madt: &Madt) {
+    let mut buf = [0u8; 128];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "MADT: local APIC {:016x}, 8259 PIC {}\n",
+            madt.local_apic_address,
+            if madt.pcat_compat {
+                "present"
+            } else {
+                "absent"
+            }
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+
+    for cpu in madt.local_apics.iter() {
+        let mut buf = [0u8; 128];
+        let _s: &str = write_to::show(
+            &mut buf,
+            format_args!(
+                "  CPU uid {} APIC id {}{}\n",
+                cpu.processor_uid,
+                cpu.apic_id,
+                if cpu.enabled {
+                    ""
+                } else if cpu.online_capable {
+                    " (online capable)"
+                } else {
+                    " (disabled)"
+                }
+            ),
+        )
+        .unwrap();
+        print_serial(_s);
+    }
+    for io_apic in madt.io_apics.iter() {
+        let mut buf = [0u8; 128];
+        let _s: &str = write_to::show(
+            &mut buf,
+            format_args!(
+                "  I/O APIC id {} at {:08x}, GSI base {}\n",
+                io_apic.id, io_apic.address, io_apic.gsi_base
+            ),
+        )
+        .unwrap();
+        print_serial(_s);
+    }
+    for o in madt.overrides.iter() {
+        let mut buf = [0u8; 128];
+        let _s: &str = write_to::show(
+            &mut buf,
+            format_args!(
+                "  IRQ {} -> GSI {}, flags {:04x}\n",
+                o.source, o.gsi, o.flags
+            ),
+        )
+        .unwrap();
+        print_serial(_s);
+    }
+}
+
+fn dump_fadt(fadt: &Fadt) {
+    let mut buf = [0u8; 256];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "FADT: DSDT {:016x}, FACS {:016x}, SCI {}, SMI port {:#x} (enable {:#04x}), flags {:08x}\n",
+            fadt.dsdt,
+            fadt.firmware_ctrl,
+            fadt.sci_interrupt,
+            fadt.smi_command_port,
+            fadt.acpi_enable,
+            fadt.flags
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+
+    let registers = [
+        ("PM1a event", fadt.pm1a_event_block),
+        ("PM1b event", fadt.pm1b_event_block),
+        ("PM1a control", fadt.pm1a_control_block),
+        ("PM1b control", fadt.pm1b_control_block),
+        ("PM timer", fadt.pm_timer_block),
+        ("GPE0", fadt.gpe0_block),
+    ];
+    for (name, gas) in registers.iter().filter(|(_, gas)| gas.is_present()) {
+        let mut buf = [0u8; 128];
+        let _s: &str = write_to::show(
+            &mut buf,
+            format_args!(
+                "  {}: {} {:#x}, {} bits\n",
+                name,
+                if gas.address_space == GenericAddress::SYSTEM_IO {
+                    "port"
+                } else {
+                    "memory"
+                },
+                gas.address,
+                gas.bit_width
+            ),
+        )
+        .unwrap();
+        print_serial(_s);
+    }
+
+    let mut buf = [0u8; 128];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "  century CMOS index {:#04x}, boot flags {:04x}, reset register {}\n",
+            fadt.century,
+            fadt.iapc_boot_arch,
+            match fadt.reset_register {
+                Some(_) => "present",
+                None => "absent",
+            }
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+}
+
+fn print_error(reason: &str) {
+    let mut buf = [0u8; 128];
+    let _s: &str = write_to::show(&mut buf, format_args!("acpi: {}\n", reason)).unwrap();
+    print_serial(_s);
+}
+
+fn load_table(physical_address: u64) -> Result<AcpiTable, &'static str> {
+    if physical_address == 0 {
+        return Err("Table address is null");
+    }
+
+    let header_address = map_physical(physical_address, mem::size_of::<SdtHeader>() as u64)?;
+    let header = unsafe { ptr::read_unaligned(header_address as *const SdtHeader) };
+    if (header.length as usize) < mem::size_of::<SdtHeader>() {
+        return Err("Invalid table length");
+    }
+
+    let virtual_address = map_physical(physical_address, header.length as u64)?;
+    if !is_valid_checksum(virtual_address, header.length as usize) {
+        return Err("Invalid table checksum");
+    }
+
+    Ok(AcpiTable {
+        physical_address: physical_address,
+        virtual_address: virtual_address,
+        header: header,
+    })
+}
+
+// ストレートマップされていればそのまま使い、されていなければMMIO領域にマップする
+// (テーブルは最後まで使うのでアンマップしない)
+fn map_physical(physical_address: u64, len: u64) -> Result<u64, &'static str> {
+    let start = physical_address / PAGE_SIZE * PAGE_SIZE;
+    let mut addr = start;
+    while addr < physical_address + len {
+        if paging::translate(VirtAddr::new(addr)).map(|addr| addr.as_u64()) != Some(addr) {
+            let region =
+                mmio::map_mmio_with_cache(physical_address, len as usize, CacheType::WriteBack)
+                    .map_err(|_| "Failed to map ACPI table")?;
+            let virtual_address = region.virtual_address().as_u64();
+            mem::forget(region);
+            return Ok(virtual_address);
+        }
+        addr += PAGE_SIZE;
+    }
+    Ok(physical_address)
+}
+
+fn is_valid_checksum(address: u64, len: usize) -> bool {
+    let bytes = unsafe { slice::from_raw_parts(address as *const u8, len) };
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+pub fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+pub fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
@@ -0,0 +1,390 @@
+pub mod handler;
+mod interpreter;
+pub mod name;
+pub mod namespace;
+pub mod value;
+
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use critical_section::Mutex;
+
+use crate::acpi;
+use crate::acpi::aml::handler::KernelHandler;
+use crate::acpi::aml::interpreter::Interpreter;
+use crate::acpi::aml::name::AmlName;
+use crate::acpi::aml::namespace::Namespace;
+use crate::acpi::aml::value::AmlValue;
+use crate::print_serial;
+use crate::write::write_to;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AmlError {
+    UnexpectedEnd,
+    InvalidOpcode(u16),
+    InvalidName,
+    ObjectNotFound,
+    TypeMismatch,
+    InvalidArgument,
+    DivideByZero,
+    Unsupported(&'static str),
+    RecursionLimit,
+    LoopTimeout,
+    Fatal,
+    NotInitialized,
+}
+
+// _STAがないデバイスは存在して有効で動いているとみなす
+const DEFAULT_DEVICE_STATUS: u32 = 0x0f;
+
+// PCIのルートブリッジのEISA ID (PNP0A03とPNP0A08)
+const PCI_ROOT_BRIDGE_IDS: [u64; 2] = [0x030ad041, 0x080ad041];
+
+// リソーステンプレートの割り込みの記述子
+const SMALL_IRQ_TAG: u8 = 0x04;
+const SMALL_END_TAG: u8 = 0x0f;
+const LARGE_EXTENDED_INTERRUPT_TAG: u8 = 0x89;
+
+// メソッドはSleepやStallで待ち、長いループも回るので、評価はクリティカルセクションの外で行う
+// その間は名前空間を取り出しておき、ほかのCPUは戻されるまで待つ
+static NAMESPACE: Mutex<RefCell<Option<Namespace>>> = Mutex::new(RefCell::new(None));
+static LOADED: AtomicBool = AtomicBool::new(false);
+
+// _PRTの1つの要素
+#[derive(Debug, Clone)]
+pub struct PciRoute {
+    // PCIデバイスの番号 (関数は常にすべて)
+    pub device: u16,
+    // 0: INTA# ... 3: INTD#
+    pub pin: u8,
+    // リンクデバイス (なければgsiに直接GSIが書かれている)
+    pub link: Option<AmlName>,
+    pub gsi: Option<u32>,
+}
+
+// DSDTとSSDTを読み込んで名前空間を作る
+pub fn initialize() -> Result<(), AmlError> {
+    let dsdt = acpi::find_table(b"DSDT").ok_or(AmlError::ObjectNotFound)?;
+    let mut namespace = Namespace::new(dsdt.header.revision);
+    let handler = KernelHandler;
+
+    let mut loaded = 0;
+    for table in acpi::find_tables(b"DSDT").chain(acpi::find_tables(b"SSDT")) {
+        let mut interpreter = Interpreter::new(&mut namespace, &handler);
+        match interpreter.load(table.body()) {
+            Ok(()) => loaded += 1,
+            // 解釈できないテーブルがあっても、それまでに作った名前は残す
+            Err(error) => {
+                let mut buf = [0u8; 128];
+                let _s: &str = write_to::show(
+                    &mut buf,
+                    format_args!(
+                        "aml: failed to load {} at {:016x}: {:?}\n",
+                        table.header.signature(),
+                        table.physical_address,
+                        error
+                    ),
+                )
+                .unwrap();
+                print_serial(_s);
+            }
+        }
+    }
+
+    // 割り込みの経路をAPICで選んだことをファームウェアに伝える
+    let pic = AmlName::from_str("_PIC")?;
+    if namespace.contains(&pic) {
+        let mut interpreter = Interpreter::new(&mut namespace, &handler);
+        if let Err(error) = interpreter.evaluate(&pic, alloc::vec![AmlValue::Integer(1)]) {
+            let mut buf = [0u8; 128];
+            let _s: &str =
+                write_to::show(&mut buf, format_args!("aml: \\_PIC failed: {:?}\n", error))
+                    .unwrap();
+            print_serial(_s);
+        }
+    }
+
+    let mut buf = [0u8; 128];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "aml: loaded {} objects from {} tables\n",
+            namespace.len(),
+            loaded
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+
+    critical_section::with(|cs| {
+        *NAMESPACE.borrow_ref_mut(cs) = Some(namespace);
+    });
+    LOADED.store(true, Ordering::Release);
+    Ok(())
+}
+
+// 名前空間を取り出してfを実行し、終わったら戻す
+// 割り込みを止めたまま呼ぶと、メソッドの中の待ちでタイマーが進まないので注意
+fn with_namespace<R>(f: impl FnOnce(&mut Namespace) -> R) -> Result<R, AmlError> {
+    let mut namespace = loop {
+        if !LOADED.load(Ordering::Acquire) {
+            return Err(AmlError::NotInitialized);
+        }
+        if let Some(namespace) = critical_section::with(|cs| NAMESPACE.borrow_ref_mut(cs).take()) {
+            break namespace;
+        }
+        core::hint::spin_loop();
+    };
+
+    let result = f(&mut namespace);
+    critical_section::with(|cs| {
+        *NAMESPACE.borrow_ref_mut(cs) = Some(namespace);
+    });
+    Ok(result)
+}
+
+// pathのオブジェクトを評価する (メソッドなら呼び出す)
+pub fn evaluate(path: &str, args: Vec<AmlValue>) -> Result<AmlValue, AmlError> {
+    let path = AmlName::from_str(path)?;
+    evaluate_name(&path, args)
+}
+
+fn evaluate_name(path: &AmlName, args: Vec<AmlValue>) -> Result<AmlValue, AmlError> {
+    with_namespace(|namespace| {
+        let handler = KernelHandler;
+        Interpreter::new(namespace, &handler).evaluate(path, args)
+    })?
+}
+
+// \_Sxのパッケージの最初の2つの要素 (PM1aとPM1bのSLP_TYP)
+pub fn sleep_type(state: u8) -> Option<(u8, u8)> {
+    let path = [b'_', b'S', b'0' + state, b'_'];
+    let value = evaluate_name(&AmlName::root().child(path), Vec::new()).ok()?;
+    match value {
+        AmlValue::Package(elements) if elements.len() >= 2 => Some((
+            elements[0].as_integer().ok()? as u8,
+            elements[1].as_integer().ok()? as u8,
+        )),
+        _ => None,
+    }
+}
+
+// デバイスの_STA (存在、有効、表示、動作のビット)
+pub fn device_status(path: &str) -> u32 {
+    match AmlName::from_str(path) {
+        Ok(path) => device_status_of(&path),
+        Err(_) => 0,
+    }
+}
+
+// PCIのルートブリッジなどの_PRTを評価し、リンクデバイスのGSIも求める
+pub fn pci_routing_table(path: &str) -> Result<Vec<PciRoute>, AmlError> {
+    let path = AmlName::from_str(path)?;
+    pci_routes(&path)
+}
+
+fn pci_routes(path: &AmlName) -> Result<Vec<PciRoute>, AmlError> {
+    let entries = match evaluate_name(&path.child(*b"_PRT"), Vec::new())? {
+        AmlValue::Package(entries) => entries,
+        _ => return Err(AmlError::TypeMismatch),
+    };
+
+    let mut routes = Vec::new();
+    for entry in entries {
+        // Package {アドレス, ピン, ソース, ソースの番号}
+        let entry = match entry {
+            AmlValue::Package(entry) if entry.len() >= 4 => entry,
+            _ => return Err(AmlError::TypeMismatch),
+        };
+        let address = entry[0].as_integer()?;
+        let pin = entry[1].as_integer()? as u8;
+        let index = entry[3].as_integer()? as u32;
+        let (link, gsi) = match &entry[2] {
+            AmlValue::Reference(link) => (Some(link.clone()), link_gsi(link)),
+            // 文字列でリンクデバイスのパスが書かれていることもある
+            AmlValue::String(link) => match AmlName::from_str(link) {
+                Ok(link) => {
+                    let gsi = link_gsi(&link);
+                    (Some(link), gsi)
+                }
+                Err(_) => (None, None),
+            },
+            _ => (None, Some(index)),
+        };
+        routes.push(PciRoute {
+            device: (address >> 16) as u16,
+            pin: pin,
+            link: link,
+            gsi: gsi,
+        });
+    }
+    Ok(routes)
+}
+
+// リンクデバイスの_CRSから、いま割り当てられている割り込みを読む
+fn link_gsi(link: &AmlName) -> Option<u32> {
+    let resources = match evaluate_name(&link.child(*b"_CRS"), Vec::new()).ok()? {
+        AmlValue::Buffer(bytes) => bytes,
+        _ => return None,
+    };
+
+    let mut offset = 0;
+    while offset < resources.len() {
+        let tag = resources[offset];
+        if tag & 0x80 == 0 {
+            // 小さいリソース (下位3ビットが長さ)
+            let length = (tag & 0x7) as usize;
+            match (tag >> 3) & 0xf {
+                SMALL_IRQ_TAG => {
+                    let mask = *resources.get(offset + 1)? as u16
+                        | (*resources.get(offset + 2)? as u16) << 8;
+                    if mask != 0 {
+                        return Some(mask.trailing_zeros());
+                    }
+                }
+                SMALL_END_TAG => return None,
+                _ => {}
+            }
+            offset += 1 + length;
+        } else {
+            // 大きいリソース (続く2バイトが長さ)
+            let length =
+                *resources.get(offset + 1)? as usize | (*resources.get(offset + 2)? as usize) << 8;
+            // フラグと割り込みの数の後ろに最初の割り込み番号
+            if tag == LARGE_EXTENDED_INTERRUPT_TAG {
+                let bytes = resources.get(offset + 5..offset + 9)?;
+                return Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+            }
+            offset += 3 + length;
+        }
+    }
+    None
+}
+
+// 名前空間の木と、\_S5とPCIのルートブリッジの_PRTをシリアルに出す
+pub fn dump_namespace() {
+    let objects: Vec<(AmlName, AmlValue)> = with_namespace(|namespace| {
+        namespace
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    })
+    .unwrap_or_default();
+    if objects.is_empty() {
+        print_serial("aml: namespace is not loaded\n");
+        return;
+    }
+
+    let mut buf = [0u8; 64];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!("aml: namespace ({} objects)\n", objects.len()),
+    )
+    .unwrap();
+    print_serial(_s);
+    for (name, value) in objects.iter().skip(1) {
+        let segment = name.last().unwrap();
+        let indent = name.depth() * 2;
+        let mut buf = [0u8; 128];
+        let _s: &str = match value {
+            AmlValue::Integer(integer) => write_to::show(
+                &mut buf,
+                format_args!(
+                    "{:indent$}{} {} = {:#x}\n",
+                    "",
+                    core::str::from_utf8(&segment).unwrap_or("????"),
+                    value.type_name(),
+                    integer,
+                    indent = indent
+                ),
+            ),
+            _ => write_to::show(
+                &mut buf,
+                format_args!(
+                    "{:indent$}{} {}\n",
+                    "",
+                    core::str::from_utf8(&segment).unwrap_or("????"),
+                    value.type_name(),
+                    indent = indent
+                ),
+            ),
+        }
+        .unwrap_or("  ...\n");
+        print_serial(_s);
+    }
+
+    let mut buf = [0u8; 64];
+    let _s: &str = match sleep_type(5) {
+        Some((a, b)) => {
+            write_to::show(&mut buf, format_args!("aml: \\_S5 = ({}, {})\n", a, b)).unwrap()
+        }
+        None => "aml: \\_S5 is not found\n",
+    };
+    print_serial(_s);
+
+    for (name, value) in objects.iter() {
+        if !matches!(value, AmlValue::Device) || !is_pci_root_bridge(name) {
+            continue;
+        }
+        let mut buf = [0u8; 128];
+        let _s: &str = write_to::show(
+            &mut buf,
+            format_args!("aml: {} _STA {:#x} _PRT\n", name, device_status_of(name)),
+        )
+        .unwrap();
+        print_serial(_s);
+        match pci_routes(name) {
+            Ok(routes) => {
+                for route in routes {
+                    let mut buf = [0u8; 128];
+                    let _s: &str = match (&route.link, route.gsi) {
+                        (Some(link), gsi) => write_to::show(
+                            &mut buf,
+                            format_args!(
+                                "  device {:2} INT{} -> {} (GSI {:?})\n",
+                                route.device,
+                                (b'A' + route.pin) as char,
+                                link,
+                                gsi
+                            ),
+                        ),
+                        (None, gsi) => write_to::show(
+                            &mut buf,
+                            format_args!(
+                                "  device {:2} INT{} -> GSI {:?}\n",
+                                route.device,
+                                (b'A' + route.pin) as char,
+                                gsi
+                            ),
+                        ),
+                    }
+                    .unwrap_or("  ...\n");
+                    print_serial(_s);
+                }
+            }
+            Err(error) => {
+                let mut buf = [0u8; 64];
+                let _s: &str = write_to::show(&mut buf, format_args!("  {:?}\n", error)).unwrap();
+                print_serial(_s);
+            }
+        }
+    }
+}
+
+fn device_status_of(path: &AmlName) -> u32 {
+    match evaluate_name(&path.child(*b"_STA"), Vec::new()) {
+        Ok(value) => value.as_integer().map_or(0, |status| status as u32),
+        Err(_) => DEFAULT_DEVICE_STATUS,
+    }
+}
+
+fn is_pci_root_bridge(path: &AmlName) -> bool {
+    [*b"_HID", *b"_CID"].iter().any(|segment| {
+        match evaluate_name(&path.child(*segment), Vec::new()) {
+            Ok(AmlValue::Integer(id)) => PCI_ROOT_BRIDGE_IDS.contains(&id),
+            Ok(AmlValue::String(id)) => id == "PNP0A03" || id == "PNP0A08",
+            _ => false,
+        }
+    })
+}
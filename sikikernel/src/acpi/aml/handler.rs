@@ -0,0 +1,185 @@
+use core::ptr::{read_volatile, write_volatile};
+use core::time::Duration;
+
+use x86_64::instructions::port::Port;
+use x86_64::VirtAddr;
+
+use crate::acpi::aml::AmlError;
+use crate::mmio::{self, MmioRegion};
+use crate::paging::{self, PAGE_SIZE};
+use crate::timer;
+
+const PCI_CONFIG_ADDRESS: u16 = 0xcf8;
+const PCI_CONFIG_DATA: u16 = 0xcfc;
+
+// 物理アドレスは52ビットまで
+const MAX_PHYSICAL_ADDRESS: u64 = 1 << 52;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+// SystemMemoryのリージョンをマップしたもの (ドロップするとアンマップする)
+#[derive(Debug)]
+pub struct MemoryMapping {
+    virtual_address: u64,
+    // 恒等マップをそのまま使うときはNone
+    _region: Option<MmioRegion>,
+}
+
+impl MemoryMapping {
+    pub fn virtual_address(&self) -> u64 {
+        self.virtual_address
+    }
+}
+
+// オペレーションリージョンの読み書きと待ちをカーネルに頼むためのトレイト
+// widthはビット数 (8, 16, 32, 64)
+pub trait Handler {
+    fn map_memory(&self, address: u64, length: u64) -> Result<MemoryMapping, AmlError>;
+    // addressはmap_memoryで得た仮想アドレス
+    fn read_memory(&self, address: u64, width: u64) -> u64;
+    fn write_memory(&self, address: u64, width: u64, value: u64);
+    fn read_io(&self, port: u16, width: u64) -> u64;
+    fn write_io(&self, port: u16, width: u64, value: u64);
+    fn read_pci(&self, address: PciAddress, offset: u16, width: u64) -> u64;
+    fn write_pci(&self, address: PciAddress, offset: u16, width: u64, value: u64);
+    fn sleep(&self, ms: u64);
+    fn stall(&self, us: u64);
+}
+
+pub struct KernelHandler;
+
+impl Handler for KernelHandler {
+    fn map_memory(&self, address: u64, length: u64) -> Result<MemoryMapping, AmlError> {
+        let end = address
+            .checked_add(length)
+            .filter(|end| *end <= MAX_PHYSICAL_ADDRESS)
+            .ok_or(AmlError::InvalidArgument)?;
+        // 恒等マップ済みのRAMやNVSを別のキャッシュ属性でマップし直すと
+        // メモリタイプのエイリアスになるので、そのまま使う
+        let mut page = address / PAGE_SIZE * PAGE_SIZE;
+        while page < end {
+            let physical_address = VirtAddr::try_new(page)
+                .ok()
+                .and_then(paging::translate)
+                .map(|addr| addr.as_u64());
+            if physical_address != Some(page) {
+                let region = mmio::map_mmio(address, length as usize)
+                    .map_err(|_| AmlError::Unsupported("operation region mapping"))?;
+                return Ok(MemoryMapping {
+                    virtual_address: region.virtual_address().as_u64(),
+                    _region: Some(region),
+                });
+            }
+            page += PAGE_SIZE;
+        }
+        Ok(MemoryMapping {
+            virtual_address: address,
+            _region: None,
+        })
+    }
+
+    fn read_memory(&self, address: u64, width: u64) -> u64 {
+        unsafe {
+            match width {
+                8 => read_volatile(address as *const u8) as u64,
+                16 => read_volatile(address as *const u16) as u64,
+                32 => read_volatile(address as *const u32) as u64,
+                _ => read_volatile(address as *const u64),
+            }
+        }
+    }
+
+    fn write_memory(&self, address: u64, width: u64, value: u64) {
+        unsafe {
+            match width {
+                8 => write_volatile(address as *mut u8, value as u8),
+                16 => write_volatile(address as *mut u16, value as u16),
+                32 => write_volatile(address as *mut u32, value as u32),
+                _ => write_volatile(address as *mut u64, value),
+            }
+        }
+    }
+
+    fn read_io(&self, port: u16, width: u64) -> u64 {
+        unsafe {
+            match width {
+                8 => Port::<u8>::new(port).read() as u64,
+                16 => Port::<u16>::new(port).read() as u64,
+                32 => Port::<u32>::new(port).read() as u64,
+                // I/Oポートに64ビットのアクセスはないので2回に分ける
+                _ => {
+                    Port::<u32>::new(port).read() as u64
+                        | (Port::<u32>::new(port + 4).read() as u64) << 32
+                }
+            }
+        }
+    }
+
+    fn write_io(&self, port: u16, width: u64, value: u64) {
+        unsafe {
+            match width {
+                8 => Port::<u8>::new(port).write(value as u8),
+                16 => Port::<u16>::new(port).write(value as u16),
+                32 => Port::<u32>::new(port).write(value as u32),
+                _ => {
+                    Port::<u32>::new(port).write(value as u32);
+                    Port::<u32>::new(port + 4).write((value >> 32) as u32);
+                }
+            }
+        }
+    }
+
+    // セグメント0だけをI/Oポート経由で読む
+    fn read_pci(&self, address: PciAddress, offset: u16, width: u64) -> u64 {
+        if address.segment != 0 || offset >= 0x100 {
+            return u64::MAX;
+        }
+        let value = unsafe {
+            Port::<u32>::new(PCI_CONFIG_ADDRESS).write(pci_config_address(address, offset));
+            Port::<u32>::new(PCI_CONFIG_DATA).read()
+        };
+        let shift = (offset & 3) * 8;
+        match width {
+            8 => (value >> shift) as u8 as u64,
+            16 => (value >> shift) as u16 as u64,
+            _ => value as u64,
+        }
+    }
+
+    fn write_pci(&self, address: PciAddress, offset: u16, width: u64, value: u64) {
+        if address.segment != 0 || offset >= 0x100 {
+            return;
+        }
+        unsafe {
+            Port::<u32>::new(PCI_CONFIG_ADDRESS).write(pci_config_address(address, offset));
+            let port = PCI_CONFIG_DATA + (offset & 3);
+            match width {
+                8 => Port::<u8>::new(port).write(value as u8),
+                16 => Port::<u16>::new(port).write(value as u16),
+                _ => Port::<u32>::new(PCI_CONFIG_DATA).write(value as u32),
+            }
+        }
+    }
+
+    fn sleep(&self, ms: u64) {
+        timer::sleep(Duration::from_millis(ms));
+    }
+
+    fn stall(&self, us: u64) {
+        timer::sleep(Duration::from_micros(us));
+    }
+}
+
+fn pci_config_address(address: PciAddress, offset: u16) -> u32 {
+    (1 << 31)
+        | (address.bus as u32) << 16
+        | (address.device as u32 & 0x1f) << 11
+        | (address.function as u32 & 0x7) << 8
+        | (offset as u32 & 0xfc)
+}
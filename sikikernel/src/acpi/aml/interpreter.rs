@@ -0,0 +1,1779 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem;
+
+use crate::acpi::aml::handler::{Handler, PciAddress};
+use crate::acpi::aml::name::{is_lead_char, is_name_char, AmlName, NameSeg, NameString};
+use crate::acpi::aml::namespace::Namespace;
+use crate::acpi::aml::value::{
+    AmlValue, FieldKind, FieldUnit, Method, OpRegion, RegionSpace, UpdateRule,
+};
+use crate::acpi::aml::AmlError;
+use crate::print_serial;
+use crate::timer;
+use crate::write::write_to;
+
+// メソッドの呼び出しの深さとWhileの繰り返しの上限
+const MAX_DEPTH: usize = 64;
+const MAX_LOOP_ITERATIONS: usize = 1_000_000;
+
+// AMLの整数で指定されるバッファのバイト数とパッケージの要素数の上限
+const MAX_BUFFER_SIZE: usize = 1 << 20;
+const MAX_PACKAGE_ELEMENTS: usize = 1 << 16;
+
+const ARG_COUNT: usize = 7;
+const LOCAL_COUNT: usize = 8;
+
+// RevisionOpが返すインタプリタのリビジョン
+const INTERPRETER_REVISION: u64 = 1;
+
+// 名前の接頭辞
+const ROOT_CHAR: u8 = b'\\';
+const PARENT_PREFIX_CHAR: u8 = b'^';
+const DUAL_NAME_PREFIX: u8 = 0x2e;
+const MULTI_NAME_PREFIX: u8 = 0x2f;
+const NULL_NAME: u8 = 0x00;
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const ALIAS_OP: u8 = 0x06;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const STRING_PREFIX: u8 = 0x0d;
+const QWORD_PREFIX: u8 = 0x0e;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const PACKAGE_OP: u8 = 0x12;
+const VAR_PACKAGE_OP: u8 = 0x13;
+const METHOD_OP: u8 = 0x14;
+const EXTERNAL_OP: u8 = 0x15;
+const EXT_OP_PREFIX: u8 = 0x5b;
+const LOCAL0_OP: u8 = 0x60;
+const LOCAL7_OP: u8 = 0x67;
+const ARG0_OP: u8 = 0x68;
+const ARG6_OP: u8 = 0x6e;
+const STORE_OP: u8 = 0x70;
+const REF_OF_OP: u8 = 0x71;
+const ADD_OP: u8 = 0x72;
+const CONCAT_OP: u8 = 0x73;
+const SUBTRACT_OP: u8 = 0x74;
+const INCREMENT_OP: u8 = 0x75;
+const DECREMENT_OP: u8 = 0x76;
+const MULTIPLY_OP: u8 = 0x77;
+const DIVIDE_OP: u8 = 0x78;
+const SHIFT_LEFT_OP: u8 = 0x79;
+const SHIFT_RIGHT_OP: u8 = 0x7a;
+const AND_OP: u8 = 0x7b;
+const NAND_OP: u8 = 0x7c;
+const OR_OP: u8 = 0x7d;
+const NOR_OP: u8 = 0x7e;
+const XOR_OP: u8 = 0x7f;
+const NOT_OP: u8 = 0x80;
+const FIND_SET_LEFT_BIT_OP: u8 = 0x81;
+const FIND_SET_RIGHT_BIT_OP: u8 = 0x82;
+const DEREF_OF_OP: u8 = 0x83;
+const MOD_OP: u8 = 0x85;
+const NOTIFY_OP: u8 = 0x86;
+const SIZE_OF_OP: u8 = 0x87;
+const INDEX_OP: u8 = 0x88;
+const MATCH_OP: u8 = 0x89;
+const CREATE_DWORD_FIELD_OP: u8 = 0x8a;
+const CREATE_WORD_FIELD_OP: u8 = 0x8b;
+const CREATE_BYTE_FIELD_OP: u8 = 0x8c;
+const CREATE_BIT_FIELD_OP: u8 = 0x8d;
+const OBJECT_TYPE_OP: u8 = 0x8e;
+const CREATE_QWORD_FIELD_OP: u8 = 0x8f;
+const LAND_OP: u8 = 0x90;
+const LOR_OP: u8 = 0x91;
+const LNOT_OP: u8 = 0x92;
+const LEQUAL_OP: u8 = 0x93;
+const LGREATER_OP: u8 = 0x94;
+const LLESS_OP: u8 = 0x95;
+const TO_BUFFER_OP: u8 = 0x96;
+const TO_DECIMAL_STRING_OP: u8 = 0x97;
+const TO_HEX_STRING_OP: u8 = 0x98;
+const TO_INTEGER_OP: u8 = 0x99;
+const TO_STRING_OP: u8 = 0x9c;
+const COPY_OBJECT_OP: u8 = 0x9d;
+const MID_OP: u8 = 0x9e;
+const CONTINUE_OP: u8 = 0x9f;
+const IF_OP: u8 = 0xa0;
+const ELSE_OP: u8 = 0xa1;
+const WHILE_OP: u8 = 0xa2;
+const NOOP_OP: u8 = 0xa3;
+const RETURN_OP: u8 = 0xa4;
+const BREAK_OP: u8 = 0xa5;
+const BREAKPOINT_OP: u8 = 0xcc;
+const ONES_OP: u8 = 0xff;
+
+// 0x5bに続く拡張オペコード
+const MUTEX_OP: u8 = 0x01;
+const EVENT_OP: u8 = 0x02;
+const COND_REF_OF_OP: u8 = 0x12;
+const CREATE_FIELD_OP: u8 = 0x13;
+const LOAD_TABLE_OP: u8 = 0x1f;
+const LOAD_OP: u8 = 0x20;
+const STALL_OP: u8 = 0x21;
+const SLEEP_OP: u8 = 0x22;
+const ACQUIRE_OP: u8 = 0x23;
+const SIGNAL_OP: u8 = 0x24;
+const WAIT_OP: u8 = 0x25;
+const RESET_OP: u8 = 0x26;
+const RELEASE_OP: u8 = 0x27;
+const FROM_BCD_OP: u8 = 0x28;
+const TO_BCD_OP: u8 = 0x29;
+const UNLOAD_OP: u8 = 0x2a;
+const REVISION_OP: u8 = 0x30;
+const DEBUG_OP: u8 = 0x31;
+const FATAL_OP: u8 = 0x32;
+const TIMER_OP: u8 = 0x33;
+const OP_REGION_OP: u8 = 0x80;
+const FIELD_OP: u8 = 0x81;
+const DEVICE_OP: u8 = 0x82;
+const PROCESSOR_OP: u8 = 0x83;
+const POWER_RES_OP: u8 = 0x84;
+const THERMAL_ZONE_OP: u8 = 0x85;
+const INDEX_FIELD_OP: u8 = 0x86;
+const BANK_FIELD_OP: u8 = 0x87;
+
+// フィールドリストの要素
+const RESERVED_FIELD: u8 = 0x00;
+const ACCESS_FIELD: u8 = 0x01;
+const CONNECT_FIELD: u8 = 0x02;
+const EXTENDED_ACCESS_FIELD: u8 = 0x03;
+
+// 項の実行の結果、どこへ進むか
+enum Flow {
+    Normal,
+    Return(AmlValue),
+    Break,
+    Continue,
+}
+
+// 値を書き込む先
+#[derive(Debug, Clone)]
+enum Target {
+    None,
+    Debug,
+    Local(usize),
+    Arg(usize),
+    Name(AmlName),
+    // CondRefOfで見つからなかった名前
+    Missing,
+    Index(Box<Target>, u64),
+}
+
+// メソッドの実行の状態
+struct Frame {
+    scope: AmlName,
+    args: Vec<AmlValue>,
+    locals: Vec<AmlValue>,
+    // メソッドの中で作った名前 (終わったら消す)
+    created: Vec<AmlName>,
+    in_method: bool,
+}
+
+impl Frame {
+    fn new(scope: AmlName, args: Vec<AmlValue>, in_method: bool) -> Self {
+        let mut args = args;
+        args.resize(ARG_COUNT, AmlValue::Uninitialized);
+        Frame {
+            scope: scope,
+            args: args,
+            locals: vec![AmlValue::Uninitialized; LOCAL_COUNT],
+            created: Vec::new(),
+            in_method: in_method,
+        }
+    }
+}
+
+struct Cursor {
+    data: &'static [u8],
+    pos: usize,
+}
+
+impl Cursor {
+    fn peek(&self) -> Result<u8, AmlError> {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, offset: usize) -> Result<u8, AmlError> {
+        self.data
+            .get(self.pos + offset)
+            .copied()
+            .ok_or(AmlError::UnexpectedEnd)
+    }
+
+    fn byte(&mut self) -> Result<u8, AmlError> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'static [u8], AmlError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(AmlError::UnexpectedEnd)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn integer(&mut self, len: usize) -> Result<u64, AmlError> {
+        let bytes = self.bytes(len)?;
+        Ok(bytes
+            .iter()
+            .enumerate()
+            .fold(0, |value, (i, byte)| value | (*byte as u64) << (i * 8)))
+    }
+
+    // PkgLengthの値 (先頭バイトの上位2ビットが後続のバイト数)
+    fn pkg_length_value(&mut self) -> Result<usize, AmlError> {
+        let lead = self.byte()?;
+        let count = (lead >> 6) as usize;
+        if count == 0 {
+            return Ok((lead & 0x3f) as usize);
+        }
+        let mut length = (lead & 0x0f) as usize;
+        for i in 0..count {
+            length |= (self.byte()? as usize) << (4 + i * 8);
+        }
+        Ok(length)
+    }
+
+    // PkgLengthを読み、それが覆う範囲の終わりの位置を返す
+    fn pkg_end(&mut self) -> Result<usize, AmlError> {
+        let start = self.pos;
+        let end = start + self.pkg_length_value()?;
+        if end > self.data.len() {
+            return Err(AmlError::UnexpectedEnd);
+        }
+        Ok(end)
+    }
+
+    fn name_seg(&mut self) -> Result<NameSeg, AmlError> {
+        let bytes = self.bytes(4)?;
+        if !is_lead_char(bytes[0]) || !bytes[1..].iter().all(|byte| is_name_char(*byte)) {
+            return Err(AmlError::InvalidName);
+        }
+        Ok([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    fn name_string(&mut self) -> Result<NameString, AmlError> {
+        let mut name = NameString {
+            root: false,
+            parents: 0,
+            segments: Vec::new(),
+        };
+        if self.peek()? == ROOT_CHAR {
+            self.pos += 1;
+            name.root = true;
+        } else {
+            while self.peek()? == PARENT_PREFIX_CHAR {
+                self.pos += 1;
+                name.parents += 1;
+            }
+        }
+
+        let count = match self.peek()? {
+            NULL_NAME => {
+                self.pos += 1;
+                0
+            }
+            DUAL_NAME_PREFIX => {
+                self.pos += 1;
+                2
+            }
+            MULTI_NAME_PREFIX => {
+                self.pos += 1;
+                self.byte()? as usize
+            }
+            _ => 1,
+        };
+        for _ in 0..count {
+            name.segments.push(self.name_seg()?);
+        }
+        Ok(name)
+    }
+
+    fn is_name_start(&self) -> bool {
+        match self.peek() {
+            Ok(byte) => {
+                is_lead_char(byte)
+                    || byte == ROOT_CHAR
+                    || byte == PARENT_PREFIX_CHAR
+                    || byte == DUAL_NAME_PREFIX
+                    || byte == MULTI_NAME_PREFIX
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+pub struct Interpreter<'a> {
+    namespace: &'a mut Namespace,
+    handler: &'a dyn Handler,
+    depth: usize,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(namespace: &'a mut Namespace, handler: &'a dyn Handler) -> Self {
+        Interpreter {
+            namespace: namespace,
+            handler: handler,
+            depth: 0,
+        }
+    }
+
+    // DSDTやSSDTの本体を実行して名前空間に読み込む
+    pub fn load(&mut self, aml: &'static [u8]) -> Result<(), AmlError> {
+        let mut frame = Frame::new(AmlName::root(), Vec::new(), false);
+        let mut cursor = Cursor { data: aml, pos: 0 };
+        self.execute_term_list(&mut frame, &mut cursor, aml.len())?;
+        Ok(())
+    }
+
+    // メソッドなら呼び出し、それ以外のオブジェクトなら値を読む
+    pub fn evaluate(&mut self, path: &AmlName, args: Vec<AmlValue>) -> Result<AmlValue, AmlError> {
+        match self.namespace.get(path) {
+            Some(AmlValue::Method(_)) => self.invoke(path, args),
+            Some(_) => self.read_name(path),
+            None => Err(AmlError::ObjectNotFound),
+        }
+    }
+
+    fn invoke(&mut self, path: &AmlName, args: Vec<AmlValue>) -> Result<AmlValue, AmlError> {
+        let method = match self.namespace.get(path) {
+            Some(AmlValue::Method(method)) => method.clone(),
+            _ => return Err(AmlError::TypeMismatch),
+        };
+        if let Some(native) = method.native {
+            return native(&args).map(|value| self.mask(value));
+        }
+        if self.depth >= MAX_DEPTH {
+            return Err(AmlError::RecursionLimit);
+        }
+
+        self.depth += 1;
+        let mut frame = Frame::new(path.clone(), args, true);
+        let mut cursor = Cursor {
+            data: method.code,
+            pos: 0,
+        };
+        let result = self.execute_term_list(&mut frame, &mut cursor, method.code.len());
+        self.depth -= 1;
+
+        for name in frame.created.iter() {
+            self.namespace.remove(name);
+        }
+        match result? {
+            Flow::Return(value) => Ok(value),
+            _ => Ok(AmlValue::Uninitialized),
+        }
+    }
+
+    fn mask(&self, value: AmlValue) -> AmlValue {
+        match value {
+            AmlValue::Integer(value) => AmlValue::Integer(value & self.namespace.ones()),
+            value => value,
+        }
+    }
+
+    fn add_object(&mut self, frame: &mut Frame, name: AmlName, value: AmlValue) {
+        if frame.in_method {
+            frame.created.push(name.clone());
+        }
+        self.namespace.insert(name, value);
+    }
+
+    fn execute_term_list(
+        &mut self,
+        frame: &mut Frame,
+        cursor: &mut Cursor,
+        end: usize,
+    ) -> Result<Flow, AmlError> {
+        while cursor.pos < end {
+            match self.execute_term(frame, cursor, end)? {
+                Flow::Normal => {}
+                flow => {
+                    cursor.pos = end;
+                    return Ok(flow);
+                }
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    // スコープを開くオブジェクトの中身を実行する
+    fn execute_in_scope(
+        &mut self,
+        frame: &mut Frame,
+        cursor: &mut Cursor,
+        scope: AmlName,
+        end: usize,
+    ) -> Result<Flow, AmlError> {
+        let saved = mem::replace(&mut frame.scope, scope);
+        let flow = self.execute_term_list(frame, cursor, end);
+        frame.scope = saved;
+        cursor.pos = end;
+        flow
+    }
+
+    fn execute_term(
+        &mut self,
+        frame: &mut Frame,
+        cursor: &mut Cursor,
+        list_end: usize,
+    ) -> Result<Flow, AmlError> {
+        match cursor.peek()? {
+            SCOPE_OP => {
+                cursor.pos += 1;
+                let end = cursor.pkg_end()?;
+                let scope = cursor.name_string()?.resolve(&frame.scope)?;
+                if !self.namespace.contains(&scope) {
+                    self.add_object(frame, scope.clone(), AmlValue::Scope);
+                }
+                self.execute_in_scope(frame, cursor, scope, end)
+            }
+            NAME_OP => {
+                cursor.pos += 1;
+                let name = cursor.name_string()?.resolve(&frame.scope)?;
+                let value = self.eval_term_arg(frame, cursor)?;
+                self.add_object(frame, name, value);
+                Ok(Flow::Normal)
+            }
+            METHOD_OP => {
+                cursor.pos += 1;
+                let end = cursor.pkg_end()?;
+                let name = cursor.name_string()?.resolve(&frame.scope)?;
+                let flags = cursor.byte()?;
+                if cursor.pos > end {
+                    return Err(AmlError::UnexpectedEnd);
+                }
+                let method = Method {
+                    code: &cursor.data[cursor.pos..end],
+                    arg_count: flags & 0x7,
+                    serialized: flags & 0x8 != 0,
+                    native: None,
+                };
+                cursor.pos = end;
+                self.add_object(frame, name, AmlValue::Method(method));
+                Ok(Flow::Normal)
+            }
+            ALIAS_OP => {
+                cursor.pos += 1;
+                let source = cursor.name_string()?;
+                let alias = cursor.name_string()?.resolve(&frame.scope)?;
+                // 参照ではなく、その時点の値を写す
+                let source = self.namespace.search(&source, &frame.scope)?;
+                let value = self.namespace.get(&source).cloned().unwrap();
+                self.add_object(frame, alias, value);
+                Ok(Flow::Normal)
+            }
+            EXTERNAL_OP => {
+                cursor.pos += 1;
+                cursor.name_string()?;
+                // オブジェクトの種類と引数の数
+                cursor.bytes(2)?;
+                Ok(Flow::Normal)
+            }
+            IF_OP => {
+                cursor.pos += 1;
+                let end = cursor.pkg_end()?;
+                let predicate = self.eval_integer(frame, cursor)?;
+                let flow = if predicate != 0 {
+                    self.execute_term_list(frame, cursor, end)?
+                } else {
+                    Flow::Normal
+                };
+                cursor.pos = end;
+
+                // Elseは同じリストの中でIfの直後に続く
+                if cursor.pos < list_end && cursor.peek()? == ELSE_OP {
+                    cursor.pos += 1;
+                    let else_end = cursor.pkg_end()?;
+                    if predicate == 0 {
+                        let flow = self.execute_term_list(frame, cursor, else_end)?;
+                        cursor.pos = else_end;
+                        return Ok(flow);
+                    }
+                    cursor.pos = else_end;
+                }
+                Ok(flow)
+            }
+            WHILE_OP => {
+                cursor.pos += 1;
+                let end = cursor.pkg_end()?;
+                let start = cursor.pos;
+                for _ in 0..MAX_LOOP_ITERATIONS {
+                    cursor.pos = start;
+                    if self.eval_integer(frame, cursor)? == 0 {
+                        cursor.pos = end;
+                        return Ok(Flow::Normal);
+                    }
+                    match self.execute_term_list(frame, cursor, end)? {
+                        Flow::Break => {
+                            cursor.pos = end;
+                            return Ok(Flow::Normal);
+                        }
+                        Flow::Return(value) => {
+                            cursor.pos = end;
+                            return Ok(Flow::Return(value));
+                        }
+                        Flow::Normal | Flow::Continue => {}
+                    }
+                }
+                Err(AmlError::LoopTimeout)
+            }
+            RETURN_OP => {
+                cursor.pos += 1;
+                let value = self.eval_term_arg(frame, cursor)?;
+                Ok(Flow::Return(value))
+            }
+            BREAK_OP => {
+                cursor.pos += 1;
+                Ok(Flow::Break)
+            }
+            CONTINUE_OP => {
+                cursor.pos += 1;
+                Ok(Flow::Continue)
+            }
+            NOOP_OP | BREAKPOINT_OP => {
+                cursor.pos += 1;
+                Ok(Flow::Normal)
+            }
+            NOTIFY_OP => {
+                // 通知を受け取るドライバはないので、評価だけして捨てる
+                cursor.pos += 1;
+                self.parse_target(frame, cursor)?;
+                self.eval_integer(frame, cursor)?;
+                Ok(Flow::Normal)
+            }
+            CREATE_DWORD_FIELD_OP
+            | CREATE_WORD_FIELD_OP
+            | CREATE_BYTE_FIELD_OP
+            | CREATE_BIT_FIELD_OP
+            | CREATE_QWORD_FIELD_OP => {
+                let op = cursor.byte()?;
+                let buffer = self.parse_buffer_name(frame, cursor)?;
+                let index = self.eval_integer(frame, cursor)?;
+                let (bit_offset, bit_length) = match op {
+                    CREATE_BIT_FIELD_OP => (index, 1),
+                    CREATE_BYTE_FIELD_OP => (index * 8, 8),
+                    CREATE_WORD_FIELD_OP => (index * 8, 16),
+                    CREATE_DWORD_FIELD_OP => (index * 8, 32),
+                    _ => (index * 8, 64),
+                };
+                let name = cursor.name_string()?.resolve(&frame.scope)?;
+                self.add_object(
+                    frame,
+                    name,
+                    AmlValue::BufferField {
+                        buffer: buffer,
+                        bit_offset: bit_offset,
+                        bit_length: bit_length,
+                    },
+                );
+                Ok(Flow::Normal)
+            }
+            EXT_OP_PREFIX => self.execute_ext_term(frame, cursor),
+            _ => {
+                self.eval_term_arg(frame, cursor)?;
+                Ok(Flow::Normal)
+            }
+        }
+    }
+
+    fn execute_ext_term(
+        &mut self,
+        frame: &mut Frame,
+        cursor: &mut Cursor,
+    ) -> Result<Flow, AmlError> {
+        match cursor.peek_at(1)? {
+            OP_REGION_OP => {
+                cursor.pos += 2;
+                let name = cursor.name_string()?.resolve(&frame.scope)?;
+                let space = RegionSpace::from_byte(cursor.byte()?);
+                let offset = self.eval_integer(frame, cursor)?;
+                let length = self.eval_integer(frame, cursor)?;
+                self.add_object(
+                    frame,
+                    name,
+                    AmlValue::OpRegion(OpRegion {
+                        space: space,
+                        offset: offset,
+                        length: length,
+                        mapping: None,
+                    }),
+                );
+            }
+            FIELD_OP => {
+                cursor.pos += 2;
+                let end = cursor.pkg_end()?;
+                let region = cursor.name_string()?;
+                let region = self.namespace.search(&region, &frame.scope)?;
+                let flags = cursor.byte()?;
+                self.parse_field_list(frame, cursor, end, FieldKind::Region(region), flags)?;
+            }
+            INDEX_FIELD_OP => {
+                cursor.pos += 2;
+                let end = cursor.pkg_end()?;
+                let index = cursor.name_string()?;
+                let index = self.namespace.search(&index, &frame.scope)?;
+                let data = cursor.name_string()?;
+                let data = self.namespace.search(&data, &frame.scope)?;
+                let flags = cursor.byte()?;
+                self.parse_field_list(
+                    frame,
+                    cursor,
+                    end,
+                    FieldKind::Index {
+                        index: index,
+                        data: data,
+                    },
+                    flags,
+                )?;
+            }
+            BANK_FIELD_OP => {
+                cursor.pos += 2;
+                let end = cursor.pkg_end()?;
+                let region = cursor.name_string()?;
+                let region = self.namespace.search(&region, &frame.scope)?;
+                let bank = cursor.name_string()?;
+                let bank = self.namespace.search(&bank, &frame.scope)?;
+                let value = self.eval_integer(frame, cursor)?;
+                let flags = cursor.byte()?;
+                self.parse_field_list(
+                    frame,
+                    cursor,
+                    end,
+                    FieldKind::Bank {
+                        region: region,
+                        bank: bank,
+                        value: value,
+                    },
+                    flags,
+                )?;
+            }
+            DEVICE_OP | PROCESSOR_OP | POWER_RES_OP | THERMAL_ZONE_OP => {
+                let op = cursor.peek_at(1)?;
+                cursor.pos += 2;
+                let end = cursor.pkg_end()?;
+                let name = cursor.name_string()?.resolve(&frame.scope)?;
+                let value = match op {
+                    DEVICE_OP => AmlValue::Device,
+                    PROCESSOR_OP => {
+                        let id = cursor.byte()?;
+                        // PBLKのアドレスと長さ
+                        cursor.bytes(5)?;
+                        AmlValue::Processor { id: id }
+                    }
+                    POWER_RES_OP => {
+                        // システムレベルとリソースの順序
+                        cursor.bytes(3)?;
+                        AmlValue::PowerResource
+                    }
+                    _ => AmlValue::ThermalZone,
+                };
+                self.add_object(frame, name.clone(), value);
+                return self.execute_in_scope(frame, cursor, name, end);
+            }
+            MUTEX_OP => {
+                cursor.pos += 2;
+                let name = cursor.name_string()?.resolve(&frame.scope)?;
+                // 同期レベル
+                cursor.byte()?;
+                self.add_object(frame, name, AmlValue::Mutex);
+            }
+            EVENT_OP => {
+                cursor.pos += 2;
+                let name = cursor.name_string()?.resolve(&frame.scope)?;
+                self.add_object(frame, name, AmlValue::Event);
+            }
+            CREATE_FIELD_OP => {
+                cursor.pos += 2;
+                let buffer = self.parse_buffer_name(frame, cursor)?;
+                let bit_offset = self.eval_integer(frame, cursor)?;
+                let bit_length = self.eval_integer(frame, cursor)?;
+                if bit_length / 8 > MAX_BUFFER_SIZE as u64 {
+                    return Err(AmlError::InvalidArgument);
+                }
+                let name = cursor.name_string()?.resolve(&frame.scope)?;
+                self.add_object(
+                    frame,
+                    name,
+                    AmlValue::BufferField {
+                        buffer: buffer,
+                        bit_offset: bit_offset,
+                        bit_length: bit_length,
+                    },
+                );
+            }
+            STALL_OP => {
+                cursor.pos += 2;
+                let us = self.eval_integer(frame, cursor)?;
+                self.handler.stall(us);
+            }
+            SLEEP_OP => {
+                cursor.pos += 2;
+                let ms = self.eval_integer(frame, cursor)?;
+                self.handler.sleep(ms);
+            }
+            SIGNAL_OP | RESET_OP | RELEASE_OP => {
+                // プロセッサは1つなので、ミューテックスとイベントは何もしない
+                cursor.pos += 2;
+                self.parse_target(frame, cursor)?;
+            }
+            FATAL_OP => {
+                cursor.pos += 2;
+                // 種類とコード
+                cursor.bytes(5)?;
+                self.eval_term_arg(frame, cursor)?;
+                return Err(AmlError::Fatal);
+            }
+            LOAD_OP | LOAD_TABLE_OP | UNLOAD_OP => {
+                return Err(AmlError::Unsupported("dynamic table loading"));
+            }
+            _ => {
+                self.eval_term_arg(frame, cursor)?;
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    // CreateXxxFieldの元のバッファ (名前のついたバッファだけを扱う)
+    fn parse_buffer_name(
+        &mut self,
+        frame: &mut Frame,
+        cursor: &mut Cursor,
+    ) -> Result<AmlName, AmlError> {
+        match self.parse_target(frame, cursor)? {
+            Target::Name(name) => Ok(name),
+            _ => Err(AmlError::Unsupported("buffer field on an unnamed buffer")),
+        }
+    }
+
+    fn parse_field_list(
+        &mut self,
+        frame: &mut Frame,
+        cursor: &mut Cursor,
+        end: usize,
+        kind: FieldKind,
+        flags: u8,
+    ) -> Result<(), AmlError> {
+        let mut access_bits = access_width(flags & 0xf);
+        let update = match (flags >> 5) & 0x3 {
+            1 => UpdateRule::WriteAsOnes,
+            2 => UpdateRule::WriteAsZeros,
+            _ => UpdateRule::Preserve,
+        };
+
+        let mut bit_offset = 0;
+        while cursor.pos < end {
+            match cursor.peek()? {
+                RESERVED_FIELD => {
+                    cursor.pos += 1;
+                    bit_offset += cursor.pkg_length_value()? as u64;
+                }
+                ACCESS_FIELD => {
+                    cursor.pos += 1;
+                    access_bits = access_width(cursor.byte()? & 0xf);
+                    // アクセス属性
+                    cursor.byte()?;
+                }
+                EXTENDED_ACCESS_FIELD => {
+                    cursor.pos += 1;
+                    access_bits = access_width(cursor.byte()? & 0xf);
+                    // アクセス属性と長さ
+                    cursor.bytes(2)?;
+                }
+                CONNECT_FIELD => return Err(AmlError::Unsupported("ConnectField")),
+                _ => {
+                    let segment = cursor.name_seg()?;
+                    let bit_length = cursor.pkg_length_value()? as u64;
+                    let name = frame.scope.child(segment);
+                    self.add_object(
+                        frame,
+                        name,
+                        AmlValue::Field(FieldUnit {
+                            kind: kind.clone(),
+                            bit_offset: bit_offset,
+                            bit_length: bit_length,
+                            access_bits: access_bits,
+                            update: update,
+                        }),
+                    );
+                    bit_offset += bit_length;
+                }
+            }
+        }
+        cursor.pos = end;
+        Ok(())
+    }
+
+    fn eval_integer(&mut self, frame: &mut Frame, cursor: &mut Cursor) -> Result<u64, AmlError> {
+        self.eval_term_arg(frame, cursor)?.as_integer()
+    }
+
+    fn eval_term_arg(
+        &mut self,
+        frame: &mut Frame,
+        cursor: &mut Cursor,
+    ) -> Result<AmlValue, AmlError> {
+        let ones = self.namespace.ones();
+        let op = cursor.peek()?;
+        if cursor.is_name_start() {
+            return self.eval_name(frame, cursor);
+        }
+
+        cursor.pos += 1;
+        let value = match op {
+            ZERO_OP => AmlValue::Integer(0),
+            ONE_OP => AmlValue::Integer(1),
+            ONES_OP => AmlValue::Integer(ones),
+            BYTE_PREFIX => AmlValue::Integer(cursor.integer(1)?),
+            WORD_PREFIX => AmlValue::Integer(cursor.integer(2)?),
+            DWORD_PREFIX => AmlValue::Integer(cursor.integer(4)?),
+            QWORD_PREFIX => AmlValue::Integer(cursor.integer(8)?),
+            STRING_PREFIX => {
+                let start = cursor.pos;
+                while cursor.byte()? != 0 {}
+                let bytes = &cursor.data[start..cursor.pos - 1];
+                AmlValue::String(String::from_utf8_lossy(bytes).into_owned())
+            }
+            BUFFER_OP => {
+                let end = cursor.pkg_end()?;
+                let size = self.eval_integer(frame, cursor)? as usize;
+                if cursor.pos > end {
+                    return Err(AmlError::UnexpectedEnd);
+                }
+                if size > MAX_BUFFER_SIZE {
+                    return Err(AmlError::InvalidArgument);
+                }
+                let mut bytes = cursor.data[cursor.pos..end].to_vec();
+                bytes.resize(size, 0);
+                cursor.pos = end;
+                AmlValue::Buffer(bytes)
+            }
+            PACKAGE_OP => {
+                let end = cursor.pkg_end()?;
+                let count = cursor.byte()? as usize;
+                AmlValue::Package(self.parse_package_elements(frame, cursor, end, count)?)
+            }
+            VAR_PACKAGE_OP => {
+                let end = cursor.pkg_end()?;
+                let count = self.eval_integer(frame, cursor)? as usize;
+                if cursor.pos > end {
+                    return Err(AmlError::UnexpectedEnd);
+                }
+                if count > MAX_PACKAGE_ELEMENTS {
+                    return Err(AmlError::InvalidArgument);
+                }
+                AmlValue::Package(self.parse_package_elements(frame, cursor, end, count)?)
+            }
+            LOCAL0_OP..=LOCAL7_OP => frame.locals[(op - LOCAL0_OP) as usize].clone(),
+            ARG0_OP..=ARG6_OP => match &frame.args[(op - ARG0_OP) as usize] {
+                // 参照で渡された引数は参照先の値を読む
+                AmlValue::Reference(name) => {
+                    let name = name.clone();
+                    self.read_name(&name)?
+                }
+                value => value.clone(),
+            },
+            STORE_OP => {
+                let value = self.eval_term_arg(frame, cursor)?;
+                let target = self.parse_target(frame, cursor)?;
+                self.store(frame, &target, value.clone())?;
+                value
+            }
+            COPY_OBJECT_OP => {
+                let value = self.eval_term_arg(frame, cursor)?;
+                let target = self.parse_target(frame, cursor)?;
+                if let Target::Name(name) = &target {
+                    self.namespace.insert(name.clone(), value.clone());
+                } else {
+                    self.store(frame, &target, value.clone())?;
+                }
+                value
+            }
+            REF_OF_OP => match self.parse_target(frame, cursor)? {
+                Target::Name(name) => AmlValue::Reference(name),
+                target => self.read_target(frame, &target)?,
+            },
+            ADD_OP | SUBTRACT_OP | MULTIPLY_OP | SHIFT_LEFT_OP | SHIFT_RIGHT_OP | AND_OP
+            | NAND_OP | OR_OP | NOR_OP | XOR_OP | MOD_OP => {
+                let a = self.eval_integer(frame, cursor)?;
+                let b = self.eval_integer(frame, cursor)?;
+                let result = match op {
+                    ADD_OP => a.wrapping_add(b),
+                    SUBTRACT_OP => a.wrapping_sub(b),
+                    MULTIPLY_OP => a.wrapping_mul(b),
+                    SHIFT_LEFT_OP => a.checked_shl(b as u32).unwrap_or(0),
+                    SHIFT_RIGHT_OP => a.checked_shr(b as u32).unwrap_or(0),
+                    AND_OP => a & b,
+                    NAND_OP => !(a & b),
+                    OR_OP => a | b,
+                    NOR_OP => !(a | b),
+                    XOR_OP => a ^ b,
+                    _ => a.checked_rem(b).ok_or(AmlError::DivideByZero)?,
+                };
+                let result = AmlValue::Integer(result & ones);
+                let target = self.parse_target(frame, cursor)?;
+                self.store(frame, &target, result.clone())?;
+                result
+            }
+            DIVIDE_OP => {
+                let a = self.eval_integer(frame, cursor)?;
+                let b = self.eval_integer(frame, cursor)?;
+                if b == 0 {
+                    return Err(AmlError::DivideByZero);
+                }
+                let remainder = self.parse_target(frame, cursor)?;
+                let quotient = self.parse_target(frame, cursor)?;
+                self.store(frame, &remainder, AmlValue::Integer(a % b))?;
+                self.store(frame, &quotient, AmlValue::Integer(a / b))?;
+                AmlValue::Integer(a / b)
+            }
+            NOT_OP | FIND_SET_LEFT_BIT_OP | FIND_SET_RIGHT_BIT_OP | FROM_BCD_OP => {
+                let a = self.eval_integer(frame, cursor)?;
+                let result = match op {
+                    NOT_OP => !a & ones,
+                    FIND_SET_LEFT_BIT_OP if a == 0 => 0,
+                    FIND_SET_LEFT_BIT_OP => 64 - a.leading_zeros() as u64,
+                    _ if a == 0 => 0,
+                    _ => a.trailing_zeros() as u64 + 1,
+                };
+                let target = self.parse_target(frame, cursor)?;
+                self.store(frame, &target, AmlValue::Integer(result))?;
+                AmlValue::Integer(result)
+            }
+            INCREMENT_OP | DECREMENT_OP => {
+                let target = self.parse_target(frame, cursor)?;
+                let value = self.read_target(frame, &target)?.as_integer()?;
+                let result = if op == INCREMENT_OP {
+                    value.wrapping_add(1)
+                } else {
+                    value.wrapping_sub(1)
+                };
+                let result = AmlValue::Integer(result & ones);
+                self.store(frame, &target, result.clone())?;
+                result
+            }
+            DEREF_OF_OP => match self.eval_term_arg(frame, cursor)? {
+                AmlValue::Reference(name) => self.read_name(&name)?,
+                // 文字列ならパスとして名前を引く
+                AmlValue::String(path) => {
+                    let name = AmlName::from_str(&path)?;
+                    self.read_name(&name)?
+                }
+                value => value,
+            },
+            CONCAT_OP => {
+                let a = self.eval_term_arg(frame, cursor)?;
+                let b = self.eval_term_arg(frame, cursor)?;
+                let result = self.concat(a, b)?;
+                let target = self.parse_target(frame, cursor)?;
+                self.store(frame, &target, result.clone())?;
+                result
+            }
+            SIZE_OF_OP => {
+                let target = self.parse_target(frame, cursor)?;
+                let size = match self.read_target(frame, &target)? {
+                    AmlValue::String(string) => string.len(),
+                    AmlValue::Buffer(bytes) => bytes.len(),
+                    AmlValue::Package(elements) => elements.len(),
+                    _ => return Err(AmlError::TypeMismatch),
+                };
+                AmlValue::Integer(size as u64)
+            }
+            INDEX_OP => {
+                let source = self.eval_term_arg(frame, cursor)?;
+                let index = self.eval_integer(frame, cursor)?;
+                let element = index_of(&source, index)?;
+                // 本来は要素への参照を書き込むが、値を写して済ませる
+                let target = self.parse_target(frame, cursor)?;
+                self.store(frame, &target, element.clone())?;
+                element
+            }
+            MATCH_OP => {
+                let package = match self.eval_term_arg(frame, cursor)? {
+                    AmlValue::Package(elements) => elements,
+                    _ => return Err(AmlError::TypeMismatch),
+                };
+                let op1 = cursor.byte()?;
+                let operand1 = self.eval_integer(frame, cursor)?;
+                let op2 = cursor.byte()?;
+                let operand2 = self.eval_integer(frame, cursor)?;
+                let start = self.eval_integer(frame, cursor)? as usize;
+                let found = package.iter().enumerate().skip(start).find(|(_, element)| {
+                    element.as_integer().map_or(false, |value| {
+                        matches(op1, value, operand1) && matches(op2, value, operand2)
+                    })
+                });
+                AmlValue::Integer(found.map_or(ones, |(i, _)| i as u64))
+            }
+            OBJECT_TYPE_OP => {
+                let target = self.parse_target(frame, cursor)?;
+                let object_type = match &target {
+                    Target::Name(name) => self
+                        .namespace
+                        .get(name)
+                        .map_or(0, |value| value.object_type()),
+                    Target::Debug => 16,
+                    target => self.read_target(frame, target)?.object_type(),
+                };
+                AmlValue::Integer(object_type)
+            }
+            LAND_OP | LOR_OP => {
+                let a = self.eval_integer(frame, cursor)? != 0;
+                let b = self.eval_integer(frame, cursor)? != 0;
+                let result = if op == LAND_OP { a && b } else { a || b };
+                AmlValue::Integer(if result { ones } else { 0 })
+            }
+            LNOT_OP => {
+                // LNotの後ろにLEqualなどが続くと、LNotEqualなどの1つのオペコードになる
+                let result = match cursor.peek()? {
+                    LEQUAL_OP | LGREATER_OP | LLESS_OP => {
+                        let compare = cursor.byte()?;
+                        let a = self.eval_term_arg(frame, cursor)?;
+                        let b = self.eval_term_arg(frame, cursor)?;
+                        !compare_values(compare, &a, &b)?
+                    }
+                    _ => self.eval_integer(frame, cursor)? == 0,
+                };
+                AmlValue::Integer(if result { ones } else { 0 })
+            }
+            LEQUAL_OP | LGREATER_OP | LLESS_OP => {
+                let a = self.eval_term_arg(frame, cursor)?;
+                let b = self.eval_term_arg(frame, cursor)?;
+                AmlValue::Integer(if compare_values(op, &a, &b)? { ones } else { 0 })
+            }
+            TO_BUFFER_OP | TO_DECIMAL_STRING_OP | TO_HEX_STRING_OP | TO_INTEGER_OP => {
+                let value = self.eval_term_arg(frame, cursor)?;
+                let integer_bytes = self.integer_bytes();
+                let result = match op {
+                    TO_BUFFER_OP => AmlValue::Buffer(value.as_bytes(integer_bytes)?),
+                    TO_DECIMAL_STRING_OP => AmlValue::String(to_string(&value, false)?),
+                    TO_HEX_STRING_OP => AmlValue::String(to_string(&value, true)?),
+                    _ => AmlValue::Integer(to_integer(&value)? & ones),
+                };
+                let target = self.parse_target(frame, cursor)?;
+                self.store(frame, &target, result.clone())?;
+                result
+            }
+            TO_STRING_OP => {
+                let bytes = self
+                    .eval_term_arg(frame, cursor)?
+                    .as_bytes(self.integer_bytes())?;
+                let length = self.eval_integer(frame, cursor)? as usize;
+                let end = bytes
+                    .iter()
+                    .position(|byte| *byte == 0)
+                    .unwrap_or(bytes.len())
+                    .min(length);
+                let result = AmlValue::String(String::from_utf8_lossy(&bytes[..end]).into_owned());
+                let target = self.parse_target(frame, cursor)?;
+                self.store(frame, &target, result.clone())?;
+                result
+            }
+            MID_OP => {
+                let source = self.eval_term_arg(frame, cursor)?;
+                let index = self.eval_integer(frame, cursor)? as usize;
+                let length = self.eval_integer(frame, cursor)? as usize;
+                let result = match &source {
+                    AmlValue::String(string) => {
+                        let bytes = string.as_bytes();
+                        let start = index.min(bytes.len());
+                        let end = index.saturating_add(length).min(bytes.len());
+                        AmlValue::String(String::from_utf8_lossy(&bytes[start..end]).into_owned())
+                    }
+                    value => {
+                        let bytes = value.as_bytes(self.integer_bytes())?;
+                        let start = index.min(bytes.len());
+                        let end = index.saturating_add(length).min(bytes.len());
+                        AmlValue::Buffer(bytes[start..end].to_vec())
+                    }
+                };
+                let target = self.parse_target(frame, cursor)?;
+                self.store(frame, &target, result.clone())?;
+                result
+            }
+            EXT_OP_PREFIX => {
+                let ext = cursor.byte()?;
+                match ext {
+                    COND_REF_OF_OP => {
+                        let source = self.parse_target(frame, cursor)?;
+                        let target = self.parse_target(frame, cursor)?;
+                        match source {
+                            Target::Missing => AmlValue::Integer(0),
+                            Target::Name(name) => {
+                                self.store(frame, &target, AmlValue::Reference(name))?;
+                                AmlValue::Integer(ones)
+                            }
+                            _ => AmlValue::Integer(ones),
+                        }
+                    }
+                    ACQUIRE_OP => {
+                        self.parse_target(frame, cursor)?;
+                        // タイムアウト
+                        cursor.integer(2)?;
+                        AmlValue::Integer(0)
+                    }
+                    WAIT_OP => {
+                        self.parse_target(frame, cursor)?;
+                        self.eval_integer(frame, cursor)?;
+                        AmlValue::Integer(0)
+                    }
+                    FROM_BCD_OP | TO_BCD_OP => {
+                        let value = self.eval_integer(frame, cursor)?;
+                        let result = if ext == FROM_BCD_OP {
+                            from_bcd(value)
+                        } else {
+                            to_bcd(value)
+                        };
+                        let target = self.parse_target(frame, cursor)?;
+                        self.store(frame, &target, AmlValue::Integer(result))?;
+                        AmlValue::Integer(result)
+                    }
+                    REVISION_OP => AmlValue::Integer(INTERPRETER_REVISION),
+                    DEBUG_OP => AmlValue::Uninitialized,
+                    // 100ns単位の単調増加のタイマー
+                    TIMER_OP => AmlValue::Integer(timer::uptime_ns() / 100),
+                    ext => return Err(AmlError::InvalidOpcode(0x5b00 | ext as u16)),
+                }
+            }
+            op => return Err(AmlError::InvalidOpcode(op as u16)),
+        };
+        Ok(value)
+    }
+
+    // 名前を評価する (メソッドなら引数を読んで呼び出す)
+    fn eval_name(&mut self, frame: &mut Frame, cursor: &mut Cursor) -> Result<AmlValue, AmlError> {
+        let name = cursor.name_string()?;
+        let path = self.namespace.search(&name, &frame.scope)?;
+        let arg_count = match self.namespace.get(&path) {
+            Some(AmlValue::Method(method)) => method.arg_count as usize,
+            _ => return self.read_name(&path),
+        };
+
+        let mut args = Vec::new();
+        for _ in 0..arg_count {
+            args.push(self.eval_term_arg(frame, cursor)?);
+        }
+        self.invoke(&path, args)
+    }
+
+    // パッケージの要素 (名前は評価せず参照として残す)
+    fn parse_package_elements(
+        &mut self,
+        frame: &mut Frame,
+        cursor: &mut Cursor,
+        end: usize,
+        count: usize,
+    ) -> Result<Vec<AmlValue>, AmlError> {
+        let mut elements = Vec::new();
+        while cursor.pos < end {
+            let element = if cursor.is_name_start() {
+                let name = cursor.name_string()?;
+                match self.namespace.search(&name, &frame.scope) {
+                    Ok(path) => AmlValue::Reference(path),
+                    Err(_) => AmlValue::Reference(name.resolve(&frame.scope)?),
+                }
+            } else {
+                self.eval_term_arg(frame, cursor)?
+            };
+            elements.push(element);
+        }
+        cursor.pos = end;
+        if elements.len() < count {
+            elements.resize(count, AmlValue::Uninitialized);
+        }
+        Ok(elements)
+    }
+
+    fn parse_target(&mut self, frame: &mut Frame, cursor: &mut Cursor) -> Result<Target, AmlError> {
+        if cursor.is_name_start() {
+            let name = cursor.name_string()?;
+            return Ok(match self.namespace.search(&name, &frame.scope) {
+                Ok(path) => Target::Name(path),
+                Err(_) => Target::Missing,
+            });
+        }
+
+        let op = cursor.byte()?;
+        match op {
+            NULL_NAME => Ok(Target::None),
+            LOCAL0_OP..=LOCAL7_OP => Ok(Target::Local((op - LOCAL0_OP) as usize)),
+            ARG0_OP..=ARG6_OP => Ok(Target::Arg((op - ARG0_OP) as usize)),
+            EXT_OP_PREFIX if cursor.peek()? == DEBUG_OP => {
+                cursor.pos += 1;
+                Ok(Target::Debug)
+            }
+            INDEX_OP => {
+                let source = self.parse_target(frame, cursor)?;
+                let index = self.eval_integer(frame, cursor)?;
+                self.parse_target(frame, cursor)?;
+                Ok(Target::Index(Box::new(source), index))
+            }
+            REF_OF_OP => self.parse_target(frame, cursor),
+            DEREF_OF_OP => match self.eval_term_arg(frame, cursor)? {
+                AmlValue::Reference(name) => Ok(Target::Name(name)),
+                _ => Err(AmlError::TypeMismatch),
+            },
+            op => Err(AmlError::InvalidOpcode(op as u16)),
+        }
+    }
+
+    fn read_target(&mut self, frame: &mut Frame, target: &Target) -> Result<AmlValue, AmlError> {
+        match target {
+            Target::None | Target::Debug => Ok(AmlValue::Uninitialized),
+            Target::Local(i) => Ok(frame.locals[*i].clone()),
+            Target::Arg(i) => match &frame.args[*i] {
+                AmlValue::Reference(name) => {
+                    let name = name.clone();
+                    self.read_name(&name)
+                }
+                value => Ok(value.clone()),
+            },
+            Target::Name(name) => self.read_name(name),
+            Target::Missing => Err(AmlError::ObjectNotFound),
+            Target::Index(source, index) => {
+                let source = self.read_target(frame, source)?;
+                index_of(&source, *index)
+            }
+        }
+    }
+
+    fn store(
+        &mut self,
+        frame: &mut Frame,
+        target: &Target,
+        value: AmlValue,
+    ) -> Result<(), AmlError> {
+        match target {
+            Target::None => Ok(()),
+            Target::Debug => {
+                let mut buf = [0u8; 128];
+                let _s: &str = write_to::show(&mut buf, format_args!("aml: debug: {:?}\n", value))
+                    .unwrap_or("aml: debug: ...\n");
+                print_serial(_s);
+                Ok(())
+            }
+            Target::Local(i) => {
+                frame.locals[*i] = value;
+                Ok(())
+            }
+            Target::Arg(i) => match &frame.args[*i] {
+                // 参照で渡された引数には参照先に書き込む
+                AmlValue::Reference(name) => {
+                    let name = name.clone();
+                    self.store_name(&name, value)
+                }
+                _ => {
+                    frame.args[*i] = value;
+                    Ok(())
+                }
+            },
+            Target::Name(name) => self.store_name(name, value),
+            Target::Missing => Err(AmlError::ObjectNotFound),
+            Target::Index(source, index) => {
+                let mut container = self.read_target(frame, source)?;
+                let index = *index as usize;
+                match &mut container {
+                    AmlValue::Package(elements) => {
+                        *elements.get_mut(index).ok_or(AmlError::InvalidArgument)? = value;
+                    }
+                    AmlValue::Buffer(bytes) => {
+                        *bytes.get_mut(index).ok_or(AmlError::InvalidArgument)? =
+                            value.as_integer()? as u8;
+                    }
+                    _ => return Err(AmlError::TypeMismatch),
+                }
+                let source = source.as_ref().clone();
+                self.store(frame, &source, container)
+            }
+        }
+    }
+
+    fn store_name(&mut self, name: &AmlName, value: AmlValue) -> Result<(), AmlError> {
+        let existing = self
+            .namespace
+            .get(name)
+            .cloned()
+            .ok_or(AmlError::ObjectNotFound)?;
+        match existing {
+            AmlValue::Field(field) => self.write_field(&field, &value),
+            AmlValue::BufferField {
+                buffer,
+                bit_offset,
+                bit_length,
+            } => {
+                let bytes = value.as_bytes(8)?;
+                match self.namespace.get_mut(&buffer) {
+                    Some(AmlValue::Buffer(data)) => {
+                        copy_bits(&bytes, 0, data, bit_offset as usize, bit_length as usize);
+                        Ok(())
+                    }
+                    _ => Err(AmlError::TypeMismatch),
+                }
+            }
+            // 整数の名前に書き込むときは整数に変換する
+            AmlValue::Integer(_) => {
+                let value = value.as_integer()? & self.namespace.ones();
+                self.namespace
+                    .insert(name.clone(), AmlValue::Integer(value));
+                Ok(())
+            }
+            AmlValue::Method(_)
+            | AmlValue::Device
+            | AmlValue::OpRegion(_)
+            | AmlValue::Scope
+            | AmlValue::Processor { .. }
+            | AmlValue::PowerResource
+            | AmlValue::ThermalZone => Err(AmlError::TypeMismatch),
+            _ => {
+                self.namespace.insert(name.clone(), value);
+                Ok(())
+            }
+        }
+    }
+
+    fn read_name(&mut self, name: &AmlName) -> Result<AmlValue, AmlError> {
+        let value = self
+            .namespace
+            .get(name)
+            .cloned()
+            .ok_or(AmlError::ObjectNotFound)?;
+        match value {
+            AmlValue::Field(field) => self.read_field(&field),
+            AmlValue::BufferField {
+                buffer,
+                bit_offset,
+                bit_length,
+            } => {
+                let data = match self.namespace.get(&buffer) {
+                    Some(AmlValue::Buffer(data)) => data,
+                    _ => return Err(AmlError::TypeMismatch),
+                };
+                let mut bytes = vec![0u8; (bit_length as usize + 7) / 8];
+                copy_bits(
+                    data,
+                    bit_offset as usize,
+                    &mut bytes,
+                    0,
+                    bit_length as usize,
+                );
+                Ok(bits_to_value(bytes, bit_length))
+            }
+            value => Ok(value),
+        }
+    }
+
+    fn read_field(&mut self, field: &FieldUnit) -> Result<AmlValue, AmlError> {
+        let width = field.access_bits;
+        let mut bytes = vec![0u8; (field.bit_length as usize + 7) / 8];
+        let mut bit = 0;
+        while bit < field.bit_length {
+            let absolute = field.bit_offset + bit;
+            let unit_offset = absolute / width * width;
+            let shift = absolute - unit_offset;
+            let count = (width - shift).min(field.bit_length - bit);
+
+            let unit = self.read_unit(field, unit_offset / 8)?;
+            let bits = (unit >> shift).to_le_bytes();
+            copy_bits(&bits, 0, &mut bytes, bit as usize, count as usize);
+            bit += count;
+        }
+        Ok(bits_to_value(bytes, field.bit_length))
+    }
+
+    fn write_field(&mut self, field: &FieldUnit, value: &AmlValue) -> Result<(), AmlError> {
+        let width = field.access_bits;
+        let mut bytes = value.as_bytes(8)?;
+        bytes.resize((field.bit_length as usize + 7) / 8, 0);
+        let mut bit = 0;
+        while bit < field.bit_length {
+            let absolute = field.bit_offset + bit;
+            let unit_offset = absolute / width * width;
+            let shift = absolute - unit_offset;
+            let count = (width - shift).min(field.bit_length - bit);
+            let mask = if count == 64 {
+                u64::MAX
+            } else {
+                ((1u64 << count) - 1) << shift
+            };
+
+            let base = if count == width {
+                0
+            } else {
+                match field.update {
+                    UpdateRule::Preserve => self.read_unit(field, unit_offset / 8)?,
+                    UpdateRule::WriteAsOnes => u64::MAX,
+                    UpdateRule::WriteAsZeros => 0,
+                }
+            };
+            let mut bits = [0u8; 8];
+            copy_bits(&bytes, bit as usize, &mut bits, 0, count as usize);
+            let unit = (base & !mask) | ((u64::from_le_bytes(bits) << shift) & mask);
+            self.write_unit(field, unit_offset / 8, unit)?;
+            bit += count;
+        }
+        Ok(())
+    }
+
+    fn read_unit(&mut self, field: &FieldUnit, byte_offset: u64) -> Result<u64, AmlError> {
+        match &field.kind {
+            FieldKind::Region(region) => self.read_region(region, byte_offset, field.access_bits),
+            FieldKind::Index { index, data } => {
+                self.store_name(index, AmlValue::Integer(byte_offset))?;
+                self.read_name(data)?.as_integer()
+            }
+            FieldKind::Bank {
+                region,
+                bank,
+                value,
+            } => {
+                self.store_name(bank, AmlValue::Integer(*value))?;
+                self.read_region(region, byte_offset, field.access_bits)
+            }
+        }
+    }
+
+    fn write_unit(
+        &mut self,
+        field: &FieldUnit,
+        byte_offset: u64,
+        value: u64,
+    ) -> Result<(), AmlError> {
+        match &field.kind {
+            FieldKind::Region(region) => {
+                self.write_region(region, byte_offset, field.access_bits, value)
+            }
+            FieldKind::Index { index, data } => {
+                self.store_name(index, AmlValue::Integer(byte_offset))?;
+                self.store_name(data, AmlValue::Integer(value))
+            }
+            FieldKind::Bank {
+                region,
+                bank,
+                value: bank_value,
+            } => {
+                self.store_name(bank, AmlValue::Integer(*bank_value))?;
+                self.write_region(region, byte_offset, field.access_bits, value)
+            }
+        }
+    }
+
+    fn read_region(
+        &mut self,
+        name: &AmlName,
+        byte_offset: u64,
+        width: u64,
+    ) -> Result<u64, AmlError> {
+        let region = self.region(name)?;
+        let address = region.offset + byte_offset;
+        let value = match region.space {
+            RegionSpace::SystemMemory => {
+                let address = self.memory_address(name, &region, byte_offset, width)?;
+                self.handler.read_memory(address, width)
+            }
+            RegionSpace::SystemIo => self.handler.read_io(address as u16, width),
+            RegionSpace::PciConfig => {
+                let pci = self.pci_address(name)?;
+                self.handler.read_pci(pci, address as u16, width)
+            }
+            RegionSpace::Other(_) => return Err(AmlError::Unsupported("operation region space")),
+        };
+        Ok(if width == 64 {
+            value
+        } else {
+            value & ((1 << width) - 1)
+        })
+    }
+
+    fn write_region(
+        &mut self,
+        name: &AmlName,
+        byte_offset: u64,
+        width: u64,
+        value: u64,
+    ) -> Result<(), AmlError> {
+        let region = self.region(name)?;
+        let address = region.offset + byte_offset;
+        match region.space {
+            RegionSpace::SystemMemory => {
+                let address = self.memory_address(name, &region, byte_offset, width)?;
+                self.handler.write_memory(address, width, value)
+            }
+            RegionSpace::SystemIo => self.handler.write_io(address as u16, width, value),
+            RegionSpace::PciConfig => {
+                let pci = self.pci_address(name)?;
+                self.handler.write_pci(pci, address as u16, width, value)
+            }
+            RegionSpace::Other(_) => return Err(AmlError::Unsupported("operation region space")),
+        }
+        Ok(())
+    }
+
+    // SystemMemoryのリージョンは最初のアクセスで一度だけマップして使い回す
+    fn memory_address(
+        &mut self,
+        name: &AmlName,
+        region: &OpRegion,
+        byte_offset: u64,
+        width: u64,
+    ) -> Result<u64, AmlError> {
+        if byte_offset
+            .checked_add(width / 8)
+            .map_or(true, |end| end > region.length)
+        {
+            return Err(AmlError::InvalidArgument);
+        }
+        let virtual_address = match &region.mapping {
+            Some(mapping) => mapping.virtual_address(),
+            None => {
+                let mapping = Arc::new(self.handler.map_memory(region.offset, region.length)?);
+                let virtual_address = mapping.virtual_address();
+                match self.namespace.get_mut(name) {
+                    Some(AmlValue::OpRegion(region)) => region.mapping = Some(mapping),
+                    _ => return Err(AmlError::TypeMismatch),
+                }
+                virtual_address
+            }
+        };
+        Ok(virtual_address + byte_offset)
+    }
+
+    fn region(&self, name: &AmlName) -> Result<OpRegion, AmlError> {
+        match self.namespace.get(name) {
+            Some(AmlValue::OpRegion(region)) => Ok(region.clone()),
+            _ => Err(AmlError::TypeMismatch),
+        }
+    }
+
+    // PCIコンフィギュレーション空間のリージョンが属するデバイスのアドレス
+    // (デバイスは_ADR、バスとセグメントはルートブリッジの_BBNと_SEGから求める)
+    fn pci_address(&mut self, region: &AmlName) -> Result<PciAddress, AmlError> {
+        let mut address = PciAddress {
+            segment: 0,
+            bus: 0,
+            device: 0,
+            function: 0,
+        };
+        let mut found_device = false;
+        let mut scope = region.parent();
+        while let Some(current) = scope {
+            if !found_device {
+                if let Ok(adr) = self.evaluate_child(&current, b"_ADR") {
+                    address.device = (adr >> 16) as u8;
+                    address.function = adr as u8;
+                    found_device = true;
+                }
+            }
+            if let Ok(bbn) = self.evaluate_child(&current, b"_BBN") {
+                address.bus = bbn as u8;
+            }
+            if let Ok(seg) = self.evaluate_child(&current, b"_SEG") {
+                address.segment = seg as u16;
+                break;
+            }
+            scope = current.parent();
+        }
+        Ok(address)
+    }
+
+    fn evaluate_child(&mut self, scope: &AmlName, segment: &NameSeg) -> Result<u64, AmlError> {
+        let path = scope.child(*segment);
+        self.evaluate(&path, Vec::new())?.as_integer()
+    }
+
+    fn integer_bytes(&self) -> usize {
+        (self.namespace.integer_bits() / 8) as usize
+    }
+
+    fn concat(&self, a: AmlValue, b: AmlValue) -> Result<AmlValue, AmlError> {
+        let integer_bytes = self.integer_bytes();
+        match a {
+            AmlValue::String(mut string) => {
+                let b = match b {
+                    AmlValue::String(b) => b,
+                    b => to_string(&b, true)?,
+                };
+                string.push_str(&b);
+                Ok(AmlValue::String(string))
+            }
+            a => {
+                let mut bytes = a.as_bytes(integer_bytes)?;
+                bytes.extend_from_slice(&b.as_bytes(integer_bytes)?);
+                Ok(AmlValue::Buffer(bytes))
+            }
+        }
+    }
+}
+
+// フィールドのアクセス幅 (Anyとバッファは1バイトずつ)
+fn access_width(access_type: u8) -> u64 {
+    match access_type {
+        2 => 16,
+        3 => 32,
+        4 => 64,
+        _ => 8,
+    }
+}
+
+fn index_of(source: &AmlValue, index: u64) -> Result<AmlValue, AmlError> {
+    let index = index as usize;
+    match source {
+        AmlValue::Package(elements) => elements
+            .get(index)
+            .cloned()
+            .ok_or(AmlError::InvalidArgument),
+        AmlValue::Buffer(bytes) => bytes
+            .get(index)
+            .map(|byte| AmlValue::Integer(*byte as u64))
+            .ok_or(AmlError::InvalidArgument),
+        AmlValue::String(string) => string
+            .as_bytes()
+            .get(index)
+            .map(|byte| AmlValue::Integer(*byte as u64))
+            .ok_or(AmlError::InvalidArgument),
+        _ => Err(AmlError::TypeMismatch),
+    }
+}
+
+// Matchの比較 (0: 常に真, 1: ==, 2: <=, 3: <, 4: >=, 5: >)
+fn matches(op: u8, value: u64, operand: u64) -> bool {
+    match op {
+        0 => true,
+        1 => value == operand,
+        2 => value <= operand,
+        3 => value < operand,
+        4 => value >= operand,
+        5 => value > operand,
+        _ => false,
+    }
+}
+
+// 文字列とバッファはバイト列として、それ以外は整数として比べる
+fn compare_values(op: u8, a: &AmlValue, b: &AmlValue) -> Result<bool, AmlError> {
+    let ordering = match a {
+        AmlValue::String(_) | AmlValue::Buffer(_) => {
+            let a = a.as_bytes(8)?;
+            let b = match (a.is_empty(), b) {
+                (_, AmlValue::Integer(value)) => value.to_le_bytes().to_vec(),
+                (_, b) => b.as_bytes(8)?,
+            };
+            a.cmp(&b)
+        }
+        a => a.as_integer()?.cmp(&b.as_integer()?),
+    };
+    Ok(match op {
+        LEQUAL_OP => ordering.is_eq(),
+        LGREATER_OP => ordering.is_gt(),
+        _ => ordering.is_lt(),
+    })
+}
+
+fn to_string(value: &AmlValue, hex: bool) -> Result<String, AmlError> {
+    use core::fmt::Write;
+
+    let mut string = String::new();
+    match value {
+        AmlValue::String(s) => string.push_str(s),
+        AmlValue::Integer(n) if hex => write!(string, "0x{:X}", n).unwrap(),
+        AmlValue::Integer(n) => write!(string, "{}", n).unwrap(),
+        AmlValue::Buffer(bytes) => {
+            for (i, byte) in bytes.iter().enumerate() {
+                if i > 0 {
+                    string.push(',');
+                }
+                if hex {
+                    write!(string, "0x{:02X}", byte).unwrap();
+                } else {
+                    write!(string, "{}", byte).unwrap();
+                }
+            }
+        }
+        _ => return Err(AmlError::TypeMismatch),
+    }
+    Ok(string)
+}
+
+// ToIntegerは文字列を"0x"があれば16進数、なければ10進数として読む
+fn to_integer(value: &AmlValue) -> Result<u64, AmlError> {
+    match value {
+        AmlValue::String(string) => {
+            let string = string.trim();
+            let (digits, radix) = match string
+                .strip_prefix("0x")
+                .or_else(|| string.strip_prefix("0X"))
+            {
+                Some(digits) => (digits, 16),
+                None => (string, 10),
+            };
+            u64::from_str_radix(digits, radix).map_err(|_| AmlError::InvalidArgument)
+        }
+        value => value.as_integer(),
+    }
+}
+
+fn from_bcd(value: u64) -> u64 {
+    let mut result = 0;
+    let mut scale = 1;
+    let mut value = value;
+    while value != 0 {
+        result += (value & 0xf) * scale;
+        scale *= 10;
+        value >>= 4;
+    }
+    result
+}
+
+fn to_bcd(value: u64) -> u64 {
+    let mut result = 0;
+    let mut shift = 0;
+    let mut value = value;
+    while value != 0 && shift < 64 {
+        result |= (value % 10) << shift;
+        shift += 4;
+        value /= 10;
+    }
+    result
+}
+
+// 64ビット以下なら整数、それより長ければバッファ
+fn bits_to_value(bytes: Vec<u8>, bit_length: u64) -> AmlValue {
+    if bit_length <= 64 {
+        let mut integer = [0u8; 8];
+        integer[..bytes.len()].copy_from_slice(&bytes);
+        AmlValue::Integer(u64::from_le_bytes(integer))
+    } else {
+        AmlValue::Buffer(bytes)
+    }
+}
+
+// sourceのsource_bitからcountビットをdestinationのdestination_bitに写す
+fn copy_bits(
+    source: &[u8],
+    source_bit: usize,
+    destination: &mut [u8],
+    destination_bit: usize,
+    count: usize,
+) {
+    for i in 0..count {
+        let from = source_bit + i;
+        let to = destination_bit + i;
+        if to / 8 >= destination.len() {
+            break;
+        }
+        let bit = source
+            .get(from / 8)
+            .map_or(0, |byte| (byte >> (from % 8)) & 1);
+        destination[to / 8] = (destination[to / 8] & !(1 << (to % 8))) | (bit << (to % 8));
+    }
+}
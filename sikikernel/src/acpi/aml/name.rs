@@ -0,0 +1,107 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::acpi::aml::AmlError;
+
+pub type NameSeg = [u8; 4];
+
+// ルートからの絶対パス
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AmlName(Vec<NameSeg>);
+
+impl AmlName {
+    pub fn root() -> Self {
+        AmlName(Vec::new())
+    }
+
+    // 「\_SB.PCI0」のような表記から作る (4文字に満たないセグメントは'_'で埋める)
+    pub fn from_str(path: &str) -> Result<Self, AmlError> {
+        let path = path.strip_prefix('\\').unwrap_or(path);
+        let mut segments = Vec::new();
+        for segment in path.split('.').filter(|segment| !segment.is_empty()) {
+            let bytes = segment.as_bytes();
+            if bytes.len() > 4 || !is_lead_char(bytes[0]) {
+                return Err(AmlError::InvalidName);
+            }
+            let mut seg = [b'_'; 4];
+            seg[..bytes.len()].copy_from_slice(bytes);
+            segments.push(seg);
+        }
+        Ok(AmlName(segments))
+    }
+
+    pub fn depth(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn last(&self) -> Option<NameSeg> {
+        self.0.last().copied()
+    }
+
+    pub fn parent(&self) -> Option<Self> {
+        match self.0.len() {
+            0 => None,
+            len => Some(AmlName(self.0[..len - 1].to_vec())),
+        }
+    }
+
+    pub fn child(&self, segment: NameSeg) -> Self {
+        let mut segments = self.0.clone();
+        segments.push(segment);
+        AmlName(segments)
+    }
+
+    pub fn is_descendant_of(&self, ancestor: &AmlName) -> bool {
+        self.0.len() > ancestor.0.len() && self.0.starts_with(&ancestor.0)
+    }
+}
+
+impl fmt::Display for AmlName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\\")?;
+        for (i, segment) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            f.write_str(core::str::from_utf8(segment).unwrap_or("????"))?;
+        }
+        Ok(())
+    }
+}
+
+// AMLに書かれたままの名前 (スコープを基準に解決する前)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameString {
+    pub root: bool,
+    // 先頭の'^'の数
+    pub parents: usize,
+    pub segments: Vec<NameSeg>,
+}
+
+impl NameString {
+    // 接頭辞のない1セグメントの名前は、見つかるまで親のスコープをさかのぼって探す
+    pub fn uses_search_rules(&self) -> bool {
+        !self.root && self.parents == 0 && self.segments.len() == 1
+    }
+
+    pub fn resolve(&self, scope: &AmlName) -> Result<AmlName, AmlError> {
+        let mut segments = if self.root {
+            Vec::new()
+        } else {
+            if self.parents > scope.0.len() {
+                return Err(AmlError::InvalidName);
+            }
+            scope.0[..scope.0.len() - self.parents].to_vec()
+        };
+        segments.extend_from_slice(&self.segments);
+        Ok(AmlName(segments))
+    }
+}
+
+pub fn is_lead_char(byte: u8) -> bool {
+    byte == b'_' || byte.is_ascii_uppercase()
+}
+
+pub fn is_name_char(byte: u8) -> bool {
+    is_lead_char(byte) || byte.is_ascii_digit()
+}
@@ -0,0 +1,139 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+
+use crate::acpi::aml::name::{AmlName, NameString};
+use crate::acpi::aml::value::{AmlValue, Method, NativeMethod};
+use crate::acpi::aml::AmlError;
+
+// \_OSIで「対応している」と答える文字列 (ファームウェアはWindowsを前提に書かれていることが多い)
+const SUPPORTED_INTERFACES: &[&str] = &[
+    "Windows 2000",
+    "Windows 2001",
+    "Windows 2001 SP1",
+    "Windows 2001 SP2",
+    "Windows 2006",
+    "Windows 2009",
+    "Windows 2012",
+    "Windows 2015",
+    "Module Device",
+    "Processor Device",
+    "Processor Aggregator Device",
+    "3.0 _SCP Extensions",
+];
+
+pub struct Namespace {
+    // 名前の順に並べると、スコープの直後に子が並ぶ
+    objects: BTreeMap<AmlName, AmlValue>,
+    // DSDTのリビジョンが2未満なら整数は32ビット
+    integer_bits: u32,
+}
+
+impl Namespace {
+    pub fn new(dsdt_revision: u8) -> Self {
+        let mut namespace = Namespace {
+            objects: BTreeMap::new(),
+            integer_bits: if dsdt_revision < 2 { 32 } else { 64 },
+        };
+
+        namespace.insert(AmlName::root(), AmlValue::Scope);
+        for scope in ["_GPE", "_PR_", "_SB_", "_SI_", "_TZ_"] {
+            namespace.insert(predefined(scope), AmlValue::Scope);
+        }
+        namespace.insert(predefined("_GL_"), AmlValue::Mutex);
+        namespace.insert(
+            predefined("_OS_"),
+            AmlValue::String(String::from("Microsoft Windows NT")),
+        );
+        namespace.insert(predefined("_REV"), AmlValue::Integer(2));
+        namespace.insert(predefined("_OSI"), native_method(1, osi));
+        namespace
+    }
+
+    pub fn integer_bits(&self) -> u32 {
+        self.integer_bits
+    }
+
+    // 整数の最大値 (AMLのOnes)
+    pub fn ones(&self) -> u64 {
+        u64::MAX >> (64 - self.integer_bits)
+    }
+
+    pub fn insert(&mut self, name: AmlName, value: AmlValue) {
+        self.objects.insert(name, value);
+    }
+
+    pub fn get(&self, name: &AmlName) -> Option<&AmlValue> {
+        self.objects.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &AmlName) -> Option<&mut AmlValue> {
+        self.objects.get_mut(name)
+    }
+
+    pub fn contains(&self, name: &AmlName) -> bool {
+        self.objects.contains_key(name)
+    }
+
+    // nameとその子をすべて消す
+    pub fn remove(&mut self, name: &AmlName) {
+        self.objects.remove(name);
+        self.objects
+            .retain(|object, _| !object.is_descendant_of(name));
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&AmlName, &AmlValue)> {
+        self.objects.iter()
+    }
+
+    // 名前をscopeから解決し、存在するオブジェクトの絶対パスを返す
+    pub fn search(&self, name: &NameString, scope: &AmlName) -> Result<AmlName, AmlError> {
+        if name.uses_search_rules() {
+            let mut scope = Some(scope.clone());
+            while let Some(current) = scope {
+                let candidate = current.child(name.segments[0]);
+                if self.contains(&candidate) {
+                    return Ok(candidate);
+                }
+                scope = current.parent();
+            }
+            return Err(AmlError::ObjectNotFound);
+        }
+
+        let path = name.resolve(scope)?;
+        if self.contains(&path) {
+            Ok(path)
+        } else {
+            Err(AmlError::ObjectNotFound)
+        }
+    }
+}
+
+fn predefined(name: &str) -> AmlName {
+    AmlName::from_str(name).unwrap()
+}
+
+fn native_method(arg_count: u8, native: NativeMethod) -> AmlValue {
+    AmlValue::Method(Method {
+        code: &[],
+        arg_count: arg_count,
+        serialized: false,
+        native: Some(native),
+    })
+}
+
+fn osi(args: &[AmlValue]) -> Result<AmlValue, AmlError> {
+    match args.first() {
+        Some(AmlValue::String(interface)) => Ok(AmlValue::Integer(
+            if SUPPORTED_INTERFACES.contains(&interface.as_str()) {
+                u64::MAX
+            } else {
+                0
+            },
+        )),
+        _ => Err(AmlError::TypeMismatch),
+    }
+}
@@ -0,0 +1,189 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::acpi::aml::handler::MemoryMapping;
+use crate::acpi::aml::name::AmlName;
+use crate::acpi::aml::AmlError;
+
+// ACPIのネイティブなメソッド (\_OSIなど)
+pub type NativeMethod = fn(&[AmlValue]) -> Result<AmlValue, AmlError>;
+
+#[derive(Debug, Clone)]
+pub struct Method {
+    // テーブルの中のメソッドの本体 (テーブルは最後までマップしたまま)
+    pub code: &'static [u8],
+    pub arg_count: u8,
+    pub serialized: bool,
+    pub native: Option<NativeMethod>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RegionSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+impl RegionSpace {
+    pub fn from_byte(space: u8) -> Self {
+        match space {
+            0 => RegionSpace::SystemMemory,
+            1 => RegionSpace::SystemIo,
+            2 => RegionSpace::PciConfig,
+            space => RegionSpace::Other(space),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OpRegion {
+    pub space: RegionSpace,
+    pub offset: u64,
+    pub length: u64,
+    // SystemMemoryのリージョンを最初のアクセスでマップしたもの
+    pub mapping: Option<Arc<MemoryMapping>>,
+}
+
+#[derive(Debug, Clone)]
+pub enum FieldKind {
+    Region(AmlName),
+    // indexにオフセットを書いてからdataを読み書きする
+    Index {
+        index: AmlName,
+        data: AmlName,
+    },
+    // bankにvalueを書いてからregionを読み書きする
+    Bank {
+        region: AmlName,
+        bank: AmlName,
+        value: u64,
+    },
+}
+
+// フィールドの更新規則
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UpdateRule {
+    Preserve,
+    WriteAsOnes,
+    WriteAsZeros,
+}
+
+#[derive(Debug, Clone)]
+pub struct FieldUnit {
+    pub kind: FieldKind,
+    pub bit_offset: u64,
+    pub bit_length: u64,
+    // 1回のアクセスのビット数 (8, 16, 32, 64)
+    pub access_bits: u64,
+    pub update: UpdateRule,
+}
+
+#[derive(Debug, Clone)]
+pub enum AmlValue {
+    Uninitialized,
+    Integer(u64),
+    String(String),
+    Buffer(Vec<u8>),
+    Package(Vec<AmlValue>),
+    // パッケージに書かれた名前やRefOfの結果
+    Reference(AmlName),
+    Method(Method),
+    Scope,
+    Device,
+    Processor {
+        id: u8,
+    },
+    PowerResource,
+    ThermalZone,
+    Mutex,
+    Event,
+    OpRegion(OpRegion),
+    Field(FieldUnit),
+    BufferField {
+        buffer: AmlName,
+        bit_offset: u64,
+        bit_length: u64,
+    },
+}
+
+impl AmlValue {
+    pub fn as_integer(&self) -> Result<u64, AmlError> {
+        match self {
+            AmlValue::Uninitialized => Ok(0),
+            AmlValue::Integer(value) => Ok(*value),
+            AmlValue::Buffer(bytes) => Ok(bytes
+                .iter()
+                .take(8)
+                .enumerate()
+                .fold(0, |value, (i, byte)| value | (*byte as u64) << (i * 8))),
+            // 文字列から整数への暗黙の変換は16進数として読む
+            AmlValue::String(string) => {
+                let digits = string
+                    .strip_prefix("0x")
+                    .or_else(|| string.strip_prefix("0X"))
+                    .unwrap_or(string);
+                let digits = digits
+                    .find(|c: char| !c.is_ascii_hexdigit())
+                    .map_or(digits, |end| &digits[..end]);
+                Ok(u64::from_str_radix(digits, 16).unwrap_or(0))
+            }
+            _ => Err(AmlError::TypeMismatch),
+        }
+    }
+
+    // integer_bytesは整数の幅 (DSDTのリビジョンが2未満なら4)
+    pub fn as_bytes(&self, integer_bytes: usize) -> Result<Vec<u8>, AmlError> {
+        match self {
+            AmlValue::Integer(value) => Ok(value.to_le_bytes()[..integer_bytes].to_vec()),
+            AmlValue::Buffer(bytes) => Ok(bytes.clone()),
+            AmlValue::String(string) => Ok(string.as_bytes().to_vec()),
+            AmlValue::Uninitialized => Ok(Vec::new()),
+            _ => Err(AmlError::TypeMismatch),
+        }
+    }
+
+    // ObjectTypeが返す値
+    pub fn object_type(&self) -> u64 {
+        match self {
+            AmlValue::Uninitialized | AmlValue::Scope | AmlValue::Reference(_) => 0,
+            AmlValue::Integer(_) => 1,
+            AmlValue::String(_) => 2,
+            AmlValue::Buffer(_) => 3,
+            AmlValue::Package(_) => 4,
+            AmlValue::Field(_) => 5,
+            AmlValue::Device => 6,
+            AmlValue::Event => 7,
+            AmlValue::Method(_) => 8,
+            AmlValue::Mutex => 9,
+            AmlValue::OpRegion(_) => 10,
+            AmlValue::PowerResource => 11,
+            AmlValue::Processor { .. } => 12,
+            AmlValue::ThermalZone => 13,
+            AmlValue::BufferField { .. } => 14,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            AmlValue::Uninitialized => "Uninitialized",
+            AmlValue::Integer(_) => "Integer",
+            AmlValue::String(_) => "String",
+            AmlValue::Buffer(_) => "Buffer",
+            AmlValue::Package(_) => "Package",
+            AmlValue::Reference(_) => "Reference",
+            AmlValue::Method(_) => "Method",
+            AmlValue::Scope => "Scope",
+            AmlValue::Device => "Device",
+            AmlValue::Processor { .. } => "Processor",
+            AmlValue::PowerResource => "PowerResource",
+            AmlValue::ThermalZone => "ThermalZone",
+            AmlValue::Mutex => "Mutex",
+            AmlValue::Event => "Event",
+            AmlValue::OpRegion(_) => "OperationRegion",
+            AmlValue::Field(_) => "Field",
+            AmlValue::BufferField { .. } => "BufferField",
+        }
+    }
+}
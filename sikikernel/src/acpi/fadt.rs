@@ -0,0 +1,163 @@
+use crate::acpi::{self, u16_at, u32_at, u64_at, AcpiTable, GenericAddress};
+
+pub const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+
+// オフセットは仕様書に合わせてヘッダの先頭から数える
+// ACPI 1.0のFADTはフラグまでの116バイト
+const FADT_V1_LENGTH: usize = 116;
+const FADT_RESET_REGISTER: usize = 116;
+const FADT_RESET_VALUE: usize = 128;
+const FADT_X_FIRMWARE_CTRL: usize = 132;
+const FADT_X_DSDT: usize = 140;
+const FADT_X_PM1A_EVENT_BLOCK: usize = 148;
+const FADT_X_PM1B_EVENT_BLOCK: usize = 160;
+const FADT_X_PM1A_CONTROL_BLOCK: usize = 172;
+const FADT_X_PM1B_CONTROL_BLOCK: usize = 184;
+const FADT_X_PM_TIMER_BLOCK: usize = 208;
+const FADT_X_GPE0_BLOCK: usize = 220;
+
+// フラグのビット4: 電源ボタンは固定機能ではなく制御メソッドで扱う
+pub const FADT_FLAG_POWER_BUTTON: u32 = 1 << 4;
+// ビット8: PMタイマーが32ビット
+pub const FADT_FLAG_TIMER_32BIT: u32 = 1 << 8;
+// ビット10: リセットレジスタがある
+pub const FADT_FLAG_RESET_REGISTER: u32 = 1 << 10;
+// ビット20: ハードウェアの固定機能がない (PMレジスタなどを使えない)
+pub const FADT_FLAG_HARDWARE_REDUCED: u32 = 1 << 20;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Fadt {
+    pub firmware_ctrl: u64,
+    pub dsdt: u64,
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: GenericAddress,
+    pub pm1b_event_block: GenericAddress,
+    pub pm1a_control_block: GenericAddress,
+    pub pm1b_control_block: GenericAddress,
+    pub pm_timer_block: GenericAddress,
+    pub gpe0_block: GenericAddress,
+    // バイト数 (イベントブロックは前半がステータス、後半が有効化のレジスタ)
+    pub pm1_event_length: u8,
+    pub pm1_control_length: u8,
+    pub gpe0_block_length: u8,
+    // RTCの世紀のCMOSインデックス (0ならない)
+    pub century: u8,
+    pub iapc_boot_arch: u16,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    pub fn find() -> Result<Self, &'static str> {
+        let table = acpi::find_table(FADT_SIGNATURE).ok_or("FADT is not found")?;
+        Self::parse(&table)
+    }
+
+    pub fn parse(table: &AcpiTable) -> Result<Self, &'static str> {
+        let data = table.data();
+        if data.len() < FADT_V1_LENGTH {
+            return Err("FADT is too short");
+        }
+
+        let pm1_event_length = data[88];
+        let pm1_control_length = data[89];
+        let pm_timer_length = data[91];
+        let gpe0_block_length = data[92];
+        let mut fadt = Fadt {
+            firmware_ctrl: u32_at(data, 36) as u64,
+            dsdt: u32_at(data, 40) as u64,
+            sci_interrupt: u16_at(data, 46),
+            smi_command_port: u32_at(data, 48),
+            acpi_enable: data[52],
+            acpi_disable: data[53],
+            pm1a_event_block: io_block(u32_at(data, 56), pm1_event_length),
+            pm1b_event_block: io_block(u32_at(data, 60), pm1_event_length),
+            pm1a_control_block: io_block(u32_at(data, 64), pm1_control_length),
+            pm1b_control_block: io_block(u32_at(data, 68), pm1_control_length),
+            pm_timer_block: io_block(u32_at(data, 76), pm_timer_length),
+            gpe0_block: io_block(u32_at(data, 80), gpe0_block_length),
+            pm1_event_length: pm1_event_length,
+            pm1_control_length: pm1_control_length,
+            gpe0_block_length: gpe0_block_length,
+            century: data[108],
+            iapc_boot_arch: u16_at(data, 109),
+            flags: u32_at(data, 112),
+            reset_register: None,
+            reset_value: 0,
+        };
+
+        if data.len() > FADT_RESET_VALUE && fadt.flags & FADT_FLAG_RESET_REGISTER != 0 {
+            fadt.reset_register = Some(GenericAddress::parse(data, FADT_RESET_REGISTER));
+            fadt.reset_value = data[FADT_RESET_VALUE];
+        }
+
+        // ACPI 2.0以降の64ビットのフィールドがあれば、そちらを優先する
+        let x_field = |offset: usize| {
+            if data.len() >= offset + GenericAddress::LENGTH {
+                Some(GenericAddress::parse(data, offset)).filter(|gas| gas.is_present())
+            } else {
+                None
+            }
+        };
+        if data.len() >= FADT_X_DSDT + 8 {
+            let x_firmware_ctrl = u64_at(data, FADT_X_FIRMWARE_CTRL);
+            let x_dsdt = u64_at(data, FADT_X_DSDT);
+            if x_firmware_ctrl != 0 {
+                fadt.firmware_ctrl = x_firmware_ctrl;
+            }
+            if x_dsdt != 0 {
+                fadt.dsdt = x_dsdt;
+            }
+        }
+        if let Some(gas) = x_field(FADT_X_PM1A_EVENT_BLOCK) {
+            fadt.pm1a_event_block = gas;
+        }
+        if let Some(gas) = x_field(FADT_X_PM1B_EVENT_BLOCK) {
+            fadt.pm1b_event_block = gas;
+        }
+        if let Some(gas) = x_field(FADT_X_PM1A_CONTROL_BLOCK) {
+            fadt.pm1a_control_block = gas;
+        }
+        if let Some(gas) = x_field(FADT_X_PM1B_CONTROL_BLOCK) {
+            fadt.pm1b_control_block = gas;
+        }
+        if let Some(gas) = x_field(FADT_X_PM_TIMER_BLOCK) {
+            fadt.pm_timer_block = gas;
+        }
+        if let Some(gas) = x_field(FADT_X_GPE0_BLOCK) {
+            fadt.gpe0_block = gas;
+        }
+
+        Ok(fadt)
+    }
+
+    pub fn is_hardware_reduced(&self) -> bool {
+        self.flags & FADT_FLAG_HARDWARE_REDUCED != 0
+    }
+
+    // PMタイマーの周波数は3.579545MHzで固定
+    pub fn pm_timer_bits(&self) -> Option<u8> {
+        if !self.pm_timer_block.is_present() {
+            None
+        } else if self.flags & FADT_FLAG_TIMER_32BIT != 0 {
+            Some(32)
+        } else {
+            Some(24)
+        }
+    }
+}
+
+// ACPI 1.0の32ビットのI/Oポート番号をGeneric Address Structureにする
+fn io_block(port: u32, length: u8) -> GenericAddress {
+    GenericAddress {
+        address_space: GenericAddress::SYSTEM_IO,
+        bit_width: length.wrapping_mul(8),
+        bit_offset: 0,
+        access_size: 0,
+        address: port as u64,
+    }
+}
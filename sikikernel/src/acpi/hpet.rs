@@ -0,0 +1,37 @@
+use crate::acpi::{self, u16_at, u32_at, AcpiTable, GenericAddress};
+
+const HPET_SIGNATURE: &[u8; 4] = b"HPET";
+const HPET_TABLE_LENGTH: usize = 20;
+
+#[derive(Debug, Copy, Clone)]
+pub struct HpetTable {
+    // ベンダIDやコンパレータの数など (能力レジスタの下位32ビットと同じ)
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    // 周期モードで割り込みを失わない最小のカウント数
+    pub minimum_tick: u16,
+    pub page_protection: u8,
+}
+
+impl HpetTable {
+    pub fn find() -> Result<Self, &'static str> {
+        let table = acpi::find_table(HPET_SIGNATURE).ok_or("HPET table is not found")?;
+        Self::parse(&table)
+    }
+
+    pub fn parse(table: &AcpiTable) -> Result<Self, &'static str> {
+        let body = table.body();
+        if body.len() < HPET_TABLE_LENGTH {
+            return Err("HPET table is too short");
+        }
+
+        Ok(HpetTable {
+            event_timer_block_id: u32_at(body, 0),
+            base_address: GenericAddress::parse(body, 4),
+            hpet_number: body[16],
+            minimum_tick: u16_at(body, 17),
+            page_protection: body[19],
+        })
+    }
+}
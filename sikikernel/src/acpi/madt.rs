@@ -0,0 +1,138 @@
+use alloc::vec::Vec;
+
+use crate::acpi::{self, u16_at, u32_at, u64_at, AcpiTable};
+
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+
+// ローカルAPICのアドレスとフラグの後ろにエントリが並ぶ
+const MADT_ENTRIES_OFFSET: usize = 8;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const ENTRY_LOCAL_X2APIC: u8 = 9;
+
+// フラグのビット0: 8259互換のPICがある
+const MADT_FLAG_PCAT_COMPAT: u32 = 1 << 0;
+// プロセッサのフラグのビット0: 有効, ビット1: 後から有効にできる
+const PROCESSOR_FLAG_ENABLED: u32 = 1 << 0;
+const PROCESSOR_FLAG_ONLINE_CAPABLE: u32 = 1 << 1;
+
+#[derive(Debug, Copy, Clone)]
+pub struct LocalApicEntry {
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+    pub online_capable: bool,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: u32,
+    // このI/O APICの最初の入力に対応するグローバルシステム割り込み番号
+    pub gsi_base: u32,
+}
+
+// ISAのIRQがI/O APICの別の入力につながっている場合の対応
+#[derive(Debug, Copy, Clone)]
+pub struct InterruptSourceOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    // ビット0-1: 極性, ビット2-3: トリガーモード
+    pub flags: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub pcat_compat: bool,
+    pub local_apics: Vec<LocalApicEntry>,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptSourceOverride>,
+}
+
+impl Madt {
+    pub fn find() -> Result<Self, &'static str> {
+        let table = acpi::find_table(MADT_SIGNATURE).ok_or("MADT is not found")?;
+        Self::parse(&table)
+    }
+
+    pub fn parse(table: &AcpiTable) -> Result<Self, &'static str> {
+        let body = table.body();
+        if body.len() < MADT_ENTRIES_OFFSET {
+            return Err("MADT is too short");
+        }
+
+        let mut madt = Madt {
+            local_apic_address: u32_at(body, 0) as u64,
+            pcat_compat: u32_at(body, 4) & MADT_FLAG_PCAT_COMPAT != 0,
+            local_apics: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+        };
+
+        let mut offset = MADT_ENTRIES_OFFSET;
+        while offset + 2 <= body.len() {
+            let entry_type = body[offset];
+            let length = body[offset + 1] as usize;
+            if length < 2 || offset + length > body.len() {
+                return Err("Invalid MADT entry length");
+            }
+            let entry = &body[offset..offset + length];
+
+            match entry_type {
+                ENTRY_LOCAL_APIC if length >= 8 => {
+                    let flags = u32_at(entry, 4);
+                    madt.local_apics.push(LocalApicEntry {
+                        processor_uid: entry[2] as u32,
+                        apic_id: entry[3] as u32,
+                        enabled: flags & PROCESSOR_FLAG_ENABLED != 0,
+                        online_capable: flags & PROCESSOR_FLAG_ONLINE_CAPABLE != 0,
+                    });
+                }
+                ENTRY_IO_APIC if length >= 12 => madt.io_apics.push(IoApicEntry {
+                    id: entry[2],
+                    address: u32_at(entry, 4),
+                    gsi_base: u32_at(entry, 8),
+                }),
+                ENTRY_INTERRUPT_SOURCE_OVERRIDE if length >= 10 => {
+                    madt.overrides.push(InterruptSourceOverride {
+                        bus: entry[2],
+                        source: entry[3],
+                        gsi: u32_at(entry, 4),
+                        flags: u16_at(entry, 8),
+                    })
+                }
+                ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE if length >= 12 => {
+                    madt.local_apic_address = u64_at(entry, 4);
+                }
+                ENTRY_LOCAL_X2APIC if length >= 16 => {
+                    let flags = u32_at(entry, 8);
+                    madt.local_apics.push(LocalApicEntry {
+                        processor_uid: u32_at(entry, 12),
+                        apic_id: u32_at(entry, 4),
+                        enabled: flags & PROCESSOR_FLAG_ENABLED != 0,
+                        online_capable: flags & PROCESSOR_FLAG_ONLINE_CAPABLE != 0,
+                    });
+                }
+                // その他のエントリは使わない
+                _ => {}
+            }
+
+            offset += length;
+        }
+
+        Ok(madt)
+    }
+
+    // ISAのIRQに対応するGSIとフラグ (上書きがなければIRQ番号そのままでエッジ, アクティブHigh)
+    pub fn isa_irq_to_gsi(&self, irq: u8) -> (u32, u16) {
+        self.overrides
+            .iter()
+            .find(|o| o.bus == 0 && o.source == irq)
+            .map_or((irq as u32, 0), |o| (o.gsi, o.flags))
+    }
+}
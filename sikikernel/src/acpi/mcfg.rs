@@ -0,0 +1,64 @@
+use alloc::vec::Vec;
+
+use crate::acpi::{self, u16_at, u64_at, AcpiTable};
+
+const MCFG_SIGNATURE: &[u8; 4] = b"MCFG";
+
+// 予約の8バイトの後ろに16バイトのエントリが並ぶ
+const MCFG_ENTRIES_OFFSET: usize = 8;
+const MCFG_ENTRY_LENGTH: usize = 16;
+
+// PCI Expressの拡張コンフィギュレーション空間 (ECAM) の場所
+#[derive(Debug, Copy, Clone)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl McfgEntry {
+    // バス, デバイス, ファンクションのコンフィギュレーション空間の物理アドレス
+    pub fn config_address(&self, bus: u8, device: u8, function: u8) -> Option<u64> {
+        if bus < self.start_bus || bus > self.end_bus || device >= 32 || function >= 8 {
+            return None;
+        }
+        Some(
+            self.base_address
+                + (((bus - self.start_bus) as u64) << 20)
+                + ((device as u64) << 15)
+                + ((function as u64) << 12),
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Mcfg {
+    pub entries: Vec<McfgEntry>,
+}
+
+impl Mcfg {
+    pub fn find() -> Result<Self, &'static str> {
+        let table = acpi::find_table(MCFG_SIGNATURE).ok_or("MCFG is not found")?;
+        Self::parse(&table)
+    }
+
+    pub fn parse(table: &AcpiTable) -> Result<Self, &'static str> {
+        let body = table.body();
+        if body.len() < MCFG_ENTRIES_OFFSET {
+            return Err("MCFG is too short");
+        }
+
+        let entries = body[MCFG_ENTRIES_OFFSET..]
+            .chunks_exact(MCFG_ENTRY_LENGTH)
+            .map(|entry| McfgEntry {
+                base_address: u64_at(entry, 0),
+                segment_group: u16_at(entry, 8),
+                start_bus: entry[10],
+                end_bus: entry[11],
+            })
+            .collect();
+
+        Ok(Mcfg { entries: entries })
+    }
+}
@@ -4,18 +4,27 @@ use bitvec::vec;
 use bitvec::{bitarr, bits, BitArr};
 use core::panic;
 use core::{
-    alloc::{GlobalAlloc, Layout},
-    cell::{Cell, UnsafeCell},
-    cmp, ptr,
+    alloc::{AllocError, GlobalAlloc, Layout},
+    cell::{Cell, RefCell, UnsafeCell},
+    cmp,
+    ptr::{self, NonNull},
 };
-use lib::{MemoryMap, MemoryType};
+use critical_section::Mutex;
 use num::Integer;
 
+use crate::memory_layout::{PhysicalMemoryLayout, PhysicalRegionKind};
 use crate::print_serial;
+use crate::swap;
 use crate::write::write_to;
 
 const ALLOC_FRAME_SIZE: usize = 4096;
-const ALLOC_FRAME_NUM: usize = 4096;
+
+// 管理できる物理メモリの上限 (16GiB)
+const MAX_PHYSICAL_MEMORY: usize = 16 * 1024 * 1024 * 1024;
+const ALLOC_FRAME_NUM: usize = MAX_PHYSICAL_MEMORY / ALLOC_FRAME_SIZE;
+
+// 1MiB未満はリアルモードのコードなどで使うため割り当てない
+const RESERVED_LOW_MEMORY: usize = 0x100000;
 
 // グローバルメモリアロケータの宣言
 #[global_allocator]
@@ -23,52 +32,213 @@ pub static ALLOC: SimpleAlloc = SimpleAlloc {
     start: UnsafeCell::new(0x0),
     end: UnsafeCell::new(0x0),
     total_pages: UnsafeCell::new(0x0),
-    memory_frame: UnsafeCell::new(MemoryFrame {
+    memory_frame: Mutex::new(RefCell::new(MemoryFrame {
         using_flag: bitarr![0; ALLOC_FRAME_NUM],
         once_frame_size: ALLOC_FRAME_SIZE,
         frame_num: ALLOC_FRAME_NUM,
         offset: 0,
-    }),
+    })),
     initialized: UnsafeCell::new(false),
 };
 
-// *シングル*コアシステム用のポインタを増加するだけのアロケータ
+// 物理フレームのビットマップで管理するアロケータ
+// ビットマップはページフォルトや割り込み、ほかのCPUからも変更されるので、
+// クリティカルセクションの中でだけ触る
 pub struct SimpleAlloc {
     start: UnsafeCell<usize>,
     end: UnsafeCell<usize>,
     total_pages: UnsafeCell<usize>,
-    memory_frame: UnsafeCell<MemoryFrame>,
+    memory_frame: Mutex<RefCell<MemoryFrame>>,
     initialized: UnsafeCell<bool>,
 }
 
 impl SimpleAlloc {
-    pub fn initialize(&self, memory_map: MemoryMap) {
-        let mut alloc_start: usize = 0;
+    // ビットマップをロックして操作する
+    fn with_frames<R>(&self, f: impl FnOnce(&mut MemoryFrame) -> R) -> R {
+        critical_section::with(|cs| f(&mut self.memory_frame.borrow_ref_mut(cs)))
+    }
+
+    // 確保できなければ、スワップでページを追い出してからもう一度だけ試す
+    fn with_frames_or_evict<R>(
+        &self,
+        f: impl Fn(&mut MemoryFrame) -> Result<R, &'static str>,
+    ) -> Result<R, &'static str> {
+        match self.with_frames(&f) {
+            Err(_) if swap::relieve_memory_pressure() => self.with_frames(&f),
+            result => result,
+        }
+    }
+
+    pub fn initialize(&self, layout: &PhysicalMemoryLayout) {
+        self.with_frames(|memory_frame| self.initialize_frames(memory_frame, layout))
+    }
+
+    fn initialize_frames(&self, memory_frame: &mut MemoryFrame, layout: &PhysicalMemoryLayout) {
+        let mut alloc_start: usize = usize::MAX;
         let mut alloc_end: usize = 0;
         let mut total_pages: usize = 0;
 
-        for i in 0..memory_map.len {
-            if memory_map.map[i].memory_type != MemoryType::CONVENTIONAL {
+        // 全フレームを使用中にしてから、空き領域だけを解放する
+        // (レイアウトの領域は重ならないので、予約済みの領域が解放されることはない)
+        memory_frame.using_flag.fill(true);
+
+        for region in layout.regions() {
+            if region.kind != PhysicalRegionKind::Usable {
                 continue;
             }
-            alloc_start = memory_map.map[i].physical_start as usize;
-            alloc_end = memory_map.map[i + 1].physical_start as usize - 1;
-            total_pages += memory_map.map[i].number_of_pages as usize;
+            let start = region.start as usize;
+            let pages = region.pages() as usize;
+
+            alloc_start = cmp::min(alloc_start, start);
+            alloc_end = cmp::max(alloc_end, start + pages * ALLOC_FRAME_SIZE - 1);
+            total_pages += memory_frame.set_range(start, pages, false);
         }
 
         unsafe {
             *self.start.get() = alloc_start;
             *self.end.get() = alloc_end;
-            (*self.memory_frame.get()).offset = alloc_start;
+            *self.total_pages.get() = total_pages;
             *self.initialized.get() = true;
         }
     }
+
+    // ブートサービスとローダーが使っていた領域をアロケータに返す
+    // ローダーから受け取った情報をすべてカーネル側にコピーしてから呼ぶこと
+    pub fn reclaim_boot_services_memory(&self, layout: &PhysicalMemoryLayout) -> usize {
+        self.with_frames(|memory_frame| {
+            let free_frames = memory_frame.free_frames();
+
+            // カーネルイメージと引数はレイアウト上で別の領域になっている
+            for region in layout.regions() {
+                if region.kind != PhysicalRegionKind::BootServices {
+                    continue;
+                }
+                memory_frame.set_range(region.start as usize, region.pages() as usize, false);
+            }
+
+            let reclaimed_frames = memory_frame.free_frames() - free_frames;
+            unsafe {
+                *self.total_pages.get() += reclaimed_frames;
+            }
+
+            reclaimed_frames
+        })
+    }
+
+    // 連続した物理フレームを確保し、先頭の物理アドレスを返す
+    pub fn allocate_frames(&self, num: usize) -> Result<usize, &'static str> {
+        self.with_frames_or_evict(|memory_frame| {
+            Ok(memory_frame.use_frame(num)? * memory_frame.once_frame_size + memory_frame.offset)
+        })
+    }
+
+    // DMA用に、アドレスの上限・アラインメント・境界の条件を満たす連続したフレームを確保する
+    pub fn allocate_frames_with_constraints(
+        &self,
+        num: usize,
+        align: usize,
+        limit: usize,
+        boundary: usize,
+    ) -> Result<usize, &'static str> {
+        self.with_frames_or_evict(|memory_frame| {
+            Ok(
+                memory_frame.use_frame_with_constraints(num, align, limit, boundary)?
+                    * memory_frame.once_frame_size
+                    + memory_frame.offset,
+            )
+        })
+    }
+
+    pub fn deallocate_frames(&self, addr: usize, num: usize) {
+        self.with_frames(|memory_frame| {
+            memory_frame.free_frame_with_physical_address(addr, num * memory_frame.once_frame_size)
+        })
+    }
 }
 
 unsafe impl Sync for SimpleAlloc {}
 
+#[derive(Debug, Copy, Clone)]
+pub struct AllocatorStatistics {
+    // アロケータが管理しているフレーム数
+    pub total_frames: usize,
+    pub free_frames: usize,
+    // 連続した空きフレームの最大数
+    pub largest_free_block: usize,
+}
+
+impl SimpleAlloc {
+    pub fn statistics(&self) -> AllocatorStatistics {
+        self.with_frames(|memory_frame| AllocatorStatistics {
+            total_frames: unsafe { *self.total_pages.get() },
+            free_frames: memory_frame.free_frames(),
+            largest_free_block: memory_frame.largest_free_block(),
+        })
+    }
+}
+
+// メモリ不足から回復できる呼び出し元向けの確保関数
+// (Box::try_newやVec::try_reserveも同じようにエラーを返す)
+pub fn try_alloc(layout: Layout) -> Result<NonNull<u8>, AllocError> {
+    if layout.size() == 0 {
+        return Err(AllocError);
+    }
+    NonNull::new(unsafe { ALLOC.alloc(layout) }).ok_or(AllocError)
+}
+
+pub fn try_alloc_zeroed(layout: Layout) -> Result<NonNull<u8>, AllocError> {
+    if layout.size() == 0 {
+        return Err(AllocError);
+    }
+    NonNull::new(unsafe { ALLOC.alloc_zeroed(layout) }).ok_or(AllocError)
+}
+
+// 失敗したときにパニックする確保 (Box::newなど) から呼ばれる
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    let statistics = ALLOC.statistics();
+    let frame_kib = ALLOC_FRAME_SIZE / 1024;
+
+    let mut buf = [0u8; 512];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "out of memory\nrequested size: {}, align: {}\ntotal: {}KiB, used: {}KiB, free: {}KiB, largest free block: {}KiB\n",
+            layout.size(),
+            layout.align(),
+            statistics.total_frames * frame_kib,
+            statistics.total_frames.saturating_sub(statistics.free_frames) * frame_kib,
+            statistics.free_frames * frame_kib,
+            statistics.largest_free_block * frame_kib
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+
+    panic!("Memory allocation of {} bytes failed", layout.size());
+}
+
 unsafe impl GlobalAlloc for SimpleAlloc {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // KASANが有効なら、前後にレッドゾーンを付けて確保する
+        #[cfg(feature = "kasan")]
+        return crate::kasan::allocate(self, layout);
+
+        #[cfg(not(feature = "kasan"))]
+        self.allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "kasan")]
+        crate::kasan::deallocate(self, ptr, layout);
+
+        #[cfg(not(feature = "kasan"))]
+        self.deallocate(ptr, layout)
+    }
+}
+
+impl SimpleAlloc {
+    pub unsafe fn allocate(&self, layout: Layout) -> *mut u8 {
         if !*self.initialized.get() {
             panic!("Alloc is not initialized!!")
         }
@@ -101,21 +271,32 @@ unsafe impl GlobalAlloc for SimpleAlloc {
         .unwrap();
         print_serial(_s);
 
-        (self
-            .memory_frame
-            .get()
-            .as_mut()
-            .unwrap()
-            .use_frame_with_physical_size(layout.size())
-            .unwrap()) as *mut u8
+        let result = self.with_frames_or_evict(|memory_frame| {
+            if layout.align() > memory_frame.once_frame_size {
+                memory_frame
+                    .use_frame_with_constraints(
+                        layout.size().div_ceil(memory_frame.once_frame_size),
+                        layout.align(),
+                        usize::MAX,
+                        0,
+                    )
+                    .map(|index| index * memory_frame.once_frame_size + memory_frame.offset)
+            } else {
+                memory_frame.use_frame_with_physical_size(layout.size())
+            }
+        });
+
+        // GlobalAllocの規約どおり、確保できなければnullを返す
+        match result {
+            Ok(addr) => addr as *mut u8,
+            Err(_) => ptr::null_mut(),
+        }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.memory_frame
-            .get()
-            .as_mut()
-            .unwrap()
-            .free_frame_with_physical_address(ptr.addr() as usize, layout.size())
+    pub unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
+        self.with_frames(|memory_frame| {
+            memory_frame.free_frame_with_physical_address(ptr.addr() as usize, layout.size())
+        })
     }
 }
 
@@ -141,6 +322,9 @@ impl MemoryFrame {
         let mut search_index = 0;
         let mut found_size = 0;
         loop {
+            if search_index == self.frame_num {
+                return Err("Memory not have enough of space");
+            }
             if self.get_flag(search_index).unwrap() == false {
                 found_size += 1;
             } else {
@@ -149,9 +333,6 @@ impl MemoryFrame {
             if found_size == size {
                 break;
             }
-            if search_index == self.frame_num {
-                return Err("Memory not have enough of space");
-            }
             search_index += 1;
         }
         let start = search_index - (found_size - 1);
@@ -178,6 +359,53 @@ impl MemoryFrame {
         Ok(start)
     }
 
+    pub fn use_frame_with_constraints(
+        &mut self,
+        size: usize,
+        align: usize,
+        limit: usize,
+        boundary: usize,
+    ) -> Result<usize, &'static str> {
+        let align_frames = cmp::max(align / self.once_frame_size, 1);
+        let boundary_frames = boundary / self.once_frame_size;
+        let limit_index = cmp::min(
+            limit.saturating_sub(self.offset) / self.once_frame_size,
+            self.frame_num,
+        );
+
+        if boundary_frames != 0 && size > boundary_frames {
+            return Err("Requested size crosses the boundary");
+        }
+
+        let mut start = 0;
+        while start + size <= limit_index {
+            // 境界をまたぐ場合は次の境界から探す
+            if boundary_frames != 0
+                && start / boundary_frames != (start + size - 1) / boundary_frames
+            {
+                start = ((start / boundary_frames + 1) * boundary_frames)
+                    .next_multiple_of(align_frames);
+                continue;
+            }
+
+            // 使用中のフレームがあればその次のアラインされた位置から探す
+            if let Some(used) = (start..start + size)
+                .rev()
+                .find(|i| self.get_flag(*i).unwrap() == true)
+            {
+                start = (used + 1).next_multiple_of(align_frames);
+                continue;
+            }
+
+            for i in start..start + size {
+                self.set_flag(i, true);
+            }
+            return Ok(start);
+        }
+
+        Err("Memory not have enough of space")
+    }
+
     pub fn use_frame_with_physical_size(&mut self, size: usize) -> Result<usize, &'static str> {
         // 必要なフレーム数をメモリサイズから計算
         let need_frame_size = size.div_ceil(self.once_frame_size);
@@ -226,6 +454,48 @@ impl MemoryFrame {
         self.free_frame(frame_index, frame_size);
     }
 
+    // 物理アドレスの範囲を使用中 / 空きに設定し、状態が変わったフレーム数を返す
+    pub fn set_range(&mut self, addr: usize, pages: usize, used: bool) -> usize {
+        let mut start = (addr.max(self.offset) - self.offset) / self.once_frame_size;
+        let end = cmp::min(
+            (addr + pages * self.once_frame_size).saturating_sub(self.offset)
+                / self.once_frame_size,
+            self.frame_num,
+        );
+
+        // 低位メモリは解放しない
+        if !used {
+            start = cmp::max(start, RESERVED_LOW_MEMORY / self.once_frame_size);
+        }
+
+        let mut changed = 0;
+        for i in start..end {
+            if self.get_flag(i).unwrap() != used {
+                self.set_flag(i, used);
+                changed += 1;
+            }
+        }
+        changed
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.using_flag[..self.frame_num].count_zeros()
+    }
+
+    pub fn largest_free_block(&self) -> usize {
+        let mut largest = 0;
+        let mut current = 0;
+        for used in self.using_flag[..self.frame_num].iter() {
+            if *used {
+                current = 0;
+            } else {
+                current += 1;
+                largest = cmp::max(largest, current);
+            }
+        }
+        largest
+    }
+
     pub fn set_offset_addr(&mut self, offset: usize) {
         self.offset = offset
     }
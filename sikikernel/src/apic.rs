@@ -0,0 +1,436 @@
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use once_cell::sync::OnceCell;
+use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
+
+use crate::acpi::madt::Madt;
+use crate::interrupts::{self, InterruptHandler};
+use crate::mmio::{self, MmioRegion};
+use crate::print_serial;
+use crate::write::write_to;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// x2APICではレジスタのオフセットを16で割った値を0x800に足したMSRで読み書きする
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+const LAPIC_ID: usize = 0x20;
+const LAPIC_VERSION: usize = 0x30;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SVR: usize = 0xf0;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+pub const LAPIC_LVT_TIMER: usize = 0x320;
+pub const LAPIC_TIMER_INITIAL_COUNT: usize = 0x380;
+pub const LAPIC_TIMER_CURRENT_COUNT: usize = 0x390;
+pub const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+
+const LAPIC_SVR_ENABLE: u32 = 1 << 8;
+
+// プロセッサ間割り込みの配送モードなど
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_TRIGGER_LEVEL: u32 = 1 << 15;
+const LAPIC_MMIO_SIZE: usize = 0x400;
+
+// ローカルAPICが割り込みを取り下げたときなどに届くベクタ (EOIは不要)
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// 8259 PICは使わないが、スプリアス割り込みが例外と重ならないように移してからマスクする
+const PIC_VECTOR_BASE: u8 = 0xe0;
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xa0;
+const PIC2_DATA: u16 = 0xa1;
+
+const IOAPIC_REGSEL: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_MMIO_SIZE: usize = 0x20;
+const IOAPIC_REG_VERSION: u32 = 0x01;
+const IOAPIC_REG_REDIRECTION: u32 = 0x10;
+
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+
+// MADTの割り込み上書きのフラグ
+const MPS_POLARITY_MASK: u16 = 0b11;
+const MPS_POLARITY_ACTIVE_LOW: u16 = 0b11;
+const MPS_TRIGGER_MASK: u16 = 0b11 << 2;
+const MPS_TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+enum LocalApic {
+    XApic(MmioRegion),
+    X2Apic,
+}
+
+impl LocalApic {
+    fn read(&self, offset: usize) -> u32 {
+        match self {
+            LocalApic::XApic(region) => region.read::<u32>(offset),
+            LocalApic::X2Apic => unsafe {
+                Msr::new(X2APIC_MSR_BASE + (offset >> 4) as u32).read() as u32
+            },
+        }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        match self {
+            LocalApic::XApic(region) => region.write::<u32>(offset, value),
+            LocalApic::X2Apic => unsafe {
+                Msr::new(X2APIC_MSR_BASE + (offset >> 4) as u32).write(value as u64)
+            },
+        }
+    }
+
+    fn id(&self) -> u32 {
+        match self {
+            LocalApic::XApic(_) => self.read(LAPIC_ID) >> 24,
+            LocalApic::X2Apic => self.read(LAPIC_ID),
+        }
+    }
+
+    fn send_ipi(&self, apic_id: u32, command: u32) {
+        match self {
+            // 上位に宛先を書いてから、下位に書いたときに送られる
+            LocalApic::XApic(region) => {
+                region.write::<u32>(LAPIC_ICR_HIGH, apic_id << 24);
+                region.write::<u32>(LAPIC_ICR_LOW, command);
+                while region.read::<u32>(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                    core::hint::spin_loop();
+                }
+            }
+            // x2APICのICRは1つの64ビットのMSRで、上位32ビットが宛先
+            LocalApic::X2Apic => unsafe {
+                Msr::new(X2APIC_MSR_BASE + (LAPIC_ICR_LOW >> 4) as u32)
+                    .write((apic_id as u64) << 32 | command as u64)
+            },
+        }
+    }
+}
+
+struct IoApic {
+    region: MmioRegion,
+    id: u8,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        self.region.write::<u32>(IOAPIC_REGSEL, register);
+        self.region.read::<u32>(IOAPIC_WINDOW)
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        self.region.write::<u32>(IOAPIC_REGSEL, register);
+        self.region.write::<u32>(IOAPIC_WINDOW, value);
+    }
+
+    fn write_redirection(&self, index: u32, value: u64) {
+        // 上位から書くと、マスクを外す前に宛先が揃う
+        self.write(IOAPIC_REG_REDIRECTION + index * 2 + 1, (value >> 32) as u32);
+        self.write(IOAPIC_REG_REDIRECTION + index * 2, value as u32);
+    }
+}
+
+struct IoApics {
+    io_apics: Vec<IoApic>,
+    madt: Madt,
+}
+
+impl IoApics {
+    // GSIを受け持つI/O APIC
+    fn find(&self, gsi: u32) -> Option<&IoApic> {
+        self.io_apics
+            .iter()
+            .find(|io_apic| io_apic.gsi_base <= gsi && gsi < io_apic.gsi_base + io_apic.entries)
+    }
+}
+
+static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::new();
+static IO_APICS: Mutex<RefCell<Option<IoApics>>> = Mutex::new(RefCell::new(None));
+
+// PICをマスクし、ローカルAPICとMADTに書かれたI/O APICを有効にする
+pub fn initialize() -> Result<(), &'static str> {
+    let madt = Madt::find()?;
+    disable_pic();
+
+    let local_apic = initialize_local_apic()?;
+    let x2apic = matches!(local_apic, LocalApic::X2Apic);
+    let bsp_id = local_apic.id();
+    let version = local_apic.read(LAPIC_VERSION) & 0xff;
+    LOCAL_APIC
+        .set(local_apic)
+        .map_err(|_| "Local APIC is already initialized")?;
+
+    let mut io_apics = Vec::new();
+    for entry in madt.io_apics.iter() {
+        let region = mmio::map_mmio(entry.address as u64, IOAPIC_MMIO_SIZE)
+            .map_err(|_| "Failed to map I/O APIC")?;
+        let mut io_apic = IoApic {
+            region: region,
+            id: entry.id,
+            gsi_base: entry.gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = ((io_apic.read(IOAPIC_REG_VERSION) >> 16) & 0xff) + 1;
+
+        // 割り込みはroute_irqで設定したものだけ届くようにする
+        for index in 0..io_apic.entries {
+            io_apic.write_redirection(index, REDIRECTION_MASKED);
+        }
+
+        let mut buf = [0u8; 128];
+        let _s: &str = write_to::show(
+            &mut buf,
+            format_args!(
+                "apic: I/O APIC {} at {:08x}, GSI {}-{}\n",
+                io_apic.id,
+                entry.address,
+                io_apic.gsi_base,
+                io_apic.gsi_base + io_apic.entries - 1
+            ),
+        )
+        .unwrap();
+        print_serial(_s);
+
+        io_apics.push(io_apic);
+    }
+
+    let mut buf = [0u8; 128];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "apic: local APIC {} version {:02x}{}, {} processors, {} overrides\n",
+            bsp_id,
+            version,
+            if x2apic { " (x2APIC)" } else { "" },
+            madt.local_apics.len(),
+            madt.overrides.len()
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+
+    critical_section::with(|cs| {
+        IO_APICS.borrow_ref_mut(cs).replace(IoApics {
+            io_apics: io_apics,
+            madt: madt,
+        });
+    });
+
+    Ok(())
+}
+
+fn disable_pic() {
+    let mut pic1_command = Port::<u8>::new(PIC1_COMMAND);
+    let mut pic1_data = Port::<u8>::new(PIC1_DATA);
+    let mut pic2_command = Port::<u8>::new(PIC2_COMMAND);
+    let mut pic2_data = Port::<u8>::new(PIC2_DATA);
+
+    unsafe {
+        // ICW1: 初期化, ICW4あり
+        pic1_command.write(0x11);
+        pic2_command.write(0x11);
+        // ICW2: ベクタのベース
+        pic1_data.write(PIC_VECTOR_BASE);
+        pic2_data.write(PIC_VECTOR_BASE + 8);
+        // ICW3: スレーブはマスタのIRQ2につながっている
+        pic1_data.write(1 << 2);
+        pic2_data.write(2);
+        // ICW4: 8086モード
+        pic1_data.write(0x01);
+        pic2_data.write(0x01);
+
+        pic1_data.write(0xff);
+        pic2_data.write(0xff);
+    }
+}
+
+fn initialize_local_apic() -> Result<LocalApic, &'static str> {
+    // CPUID.01h:EDX[9] APIC, ECX[21] x2APIC
+    let cpuid = unsafe { __cpuid(0x1) };
+    if cpuid.edx & (1 << 9) == 0 {
+        return Err("Local APIC is not supported");
+    }
+
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+    let mut value = unsafe { apic_base.read() } | APIC_BASE_ENABLE;
+    unsafe { apic_base.write(value) };
+
+    let local_apic = if cpuid.ecx & (1 << 21) != 0 {
+        // 無効の状態から直接x2APICにはできないので、xAPICを有効にしてから切り替える
+        value |= APIC_BASE_X2APIC_ENABLE;
+        unsafe { apic_base.write(value) };
+        LocalApic::X2Apic
+    } else {
+        let region = mmio::map_mmio(value & APIC_BASE_ADDRESS_MASK, LAPIC_MMIO_SIZE)
+            .map_err(|_| "Failed to map local APIC")?;
+        LocalApic::XApic(region)
+    };
+
+    enable_local_apic(&local_apic);
+    Ok(local_apic)
+}
+
+fn enable_local_apic(local_apic: &LocalApic) {
+    local_apic.write(LAPIC_TPR, 0);
+    local_apic.write(LAPIC_SVR, LAPIC_SVR_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+// APのローカルAPICをBSPと同じモードで有効にする
+// (xAPICのレジスタはどのCPUでも同じアドレスなので、BSPのマップを使う)
+pub fn initialize_ap() -> Result<(), &'static str> {
+    let local_apic = LOCAL_APIC.get().ok_or("Local APIC is not initialized")?;
+
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+    let mut value = unsafe { apic_base.read() } | APIC_BASE_ENABLE;
+    unsafe { apic_base.write(value) };
+    if let LocalApic::X2Apic = local_apic {
+        value |= APIC_BASE_X2APIC_ENABLE;
+        unsafe { apic_base.write(value) };
+    }
+
+    enable_local_apic(local_apic);
+    Ok(())
+}
+
+pub fn is_x2apic() -> bool {
+    matches!(LOCAL_APIC.get(), Some(LocalApic::X2Apic))
+}
+
+pub fn is_initialized() -> bool {
+    LOCAL_APIC.get().is_some()
+}
+
+pub fn local_apic_id() -> Option<u32> {
+    LOCAL_APIC.get().map(|local_apic| local_apic.id())
+}
+
+pub fn read_register(offset: usize) -> Option<u32> {
+    LOCAL_APIC.get().map(|local_apic| local_apic.read(offset))
+}
+
+pub fn write_register(offset: usize, value: u32) {
+    if let Some(local_apic) = LOCAL_APIC.get() {
+        local_apic.write(offset, value);
+    }
+}
+
+pub fn end_of_interrupt() {
+    write_register(LAPIC_EOI, 0);
+}
+
+// APをリセットしてSIPIを待つ状態にする
+pub fn send_init(apic_id: u32) -> Result<(), &'static str> {
+    let local_apic = LOCAL_APIC.get().ok_or("Local APIC is not initialized")?;
+    local_apic.send_ipi(
+        apic_id,
+        ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT | ICR_TRIGGER_LEVEL,
+    );
+    Ok(())
+}
+
+// APをリアルモードで物理アドレスpage * 4KiBから実行させる
+pub fn send_startup(apic_id: u32, page: u8) -> Result<(), &'static str> {
+    let local_apic = LOCAL_APIC.get().ok_or("Local APIC is not initialized")?;
+    local_apic.send_ipi(
+        apic_id,
+        ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | page as u32,
+    );
+    Ok(())
+}
+
+// ISAのIRQをベクタに割り当て、割り込みが届いたらhandlerを呼ぶ
+pub fn route_irq(irq: u8, vector: u8, handler: InterruptHandler) -> Result<(), &'static str> {
+    let (gsi, flags) = isa_irq_to_gsi(irq)?;
+    route(gsi, flags, vector, handler)
+}
+
+// GSIを直接ベクタに割り当てる (エッジトリガー, アクティブHigh)
+pub fn route_gsi(gsi: u32, vector: u8, handler: InterruptHandler) -> Result<(), &'static str> {
+    route(gsi, 0, vector, handler)
+}
+
+// ACPIのSCIを割り当てる
+// 上書きで極性やトリガーが決まっていなければ、SCIの既定のレベルトリガー, アクティブLowにする
+pub fn route_sci(irq: u8, vector: u8, handler: InterruptHandler) -> Result<(), &'static str> {
+    let (gsi, mut flags) = isa_irq_to_gsi(irq)?;
+    if flags & MPS_POLARITY_MASK == 0 {
+        flags |= MPS_POLARITY_ACTIVE_LOW;
+    }
+    if flags & MPS_TRIGGER_MASK == 0 {
+        flags |= MPS_TRIGGER_LEVEL;
+    }
+    route(gsi, flags, vector, handler)
+}
+
+// IRQをマスクしてハンドラの登録を外す
+pub fn unroute_irq(irq: u8, vector: u8) -> Result<(), &'static str> {
+    let (gsi, _) = isa_irq_to_gsi(irq)?;
+    unroute_gsi(gsi, vector)
+}
+
+pub fn unroute_gsi(gsi: u32, vector: u8) -> Result<(), &'static str> {
+    critical_section::with(|cs| {
+        let io_apics = IO_APICS.borrow_ref(cs);
+        let io_apics = io_apics.as_ref().ok_or("I/O APIC is not initialized")?;
+        let io_apic = io_apics.find(gsi).ok_or("No I/O APIC handles the GSI")?;
+        io_apic.write_redirection(gsi - io_apic.gsi_base, REDIRECTION_MASKED);
+
+        interrupts::unregister_handler(vector);
+        Ok(())
+    })
+}
+
+fn isa_irq_to_gsi(irq: u8) -> Result<(u32, u16), &'static str> {
+    critical_section::with(|cs| {
+        let io_apics = IO_APICS.borrow_ref(cs);
+        let io_apics = io_apics.as_ref().ok_or("I/O APIC is not initialized")?;
+        Ok(io_apics.madt.isa_irq_to_gsi(irq))
+    })
+}
+
+// flagsはMADTの割り込み上書きと同じ形式
+fn route(gsi: u32, flags: u16, vector: u8, handler: InterruptHandler) -> Result<(), &'static str> {
+    let bsp_id = local_apic_id().ok_or("Local APIC is not initialized")?;
+
+    critical_section::with(|cs| {
+        let io_apics = IO_APICS.borrow_ref(cs);
+        let io_apics = io_apics.as_ref().ok_or("I/O APIC is not initialized")?;
+        let io_apic = io_apics.find(gsi).ok_or("No I/O APIC handles the GSI")?;
+
+        interrupts::register_handler(vector, handler)?;
+
+        // 宛先は起動したプロセッサ, 固定配送, 物理宛先モード
+        let mut redirection = vector as u64 | ((bsp_id as u64 & 0xff) << 56);
+        if flags & MPS_POLARITY_MASK == MPS_POLARITY_ACTIVE_LOW {
+            redirection |= REDIRECTION_ACTIVE_LOW;
+        }
+        if flags & MPS_TRIGGER_MASK == MPS_TRIGGER_LEVEL {
+            redirection |= REDIRECTION_LEVEL_TRIGGERED;
+        }
+        io_apic.write_redirection(gsi - io_apic.gsi_base, redirection);
+
+        let mut buf = [0u8; 128];
+        let _s: &str = write_to::show(
+            &mut buf,
+            format_args!("apic: GSI {} -> vector {:#04x}\n", gsi, vector),
+        )
+        .unwrap();
+        print_serial(_s);
+
+        Ok(())
+    })
+}
@@ -1,10 +1,134 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use critical_section::RawRestoreState;
+use x86_64::instructions::interrupts;
+
+use crate::smp;
+use crate::write::write_to;
+use crate::{print_serial, print_serial_unlocked};
 
 struct MyCriticalSection;
 critical_section::set_impl!(MyCriticalSection);
 
+// 解放したときに割り込みを有効に戻す
+const STATE_INTERRUPTS_ENABLED: u8 = 1 << 0;
+// 同じCPUですでに入っていたので、解放してもロックを手放さない
+const STATE_NESTED: u8 = 1 << 1;
+
+const NO_OWNER: usize = usize::MAX;
+
+// クリティカルセクションはすべてのCPUで1つ
+// ロックを持っているCPUの番号と、そのCPUでの入れ子の深さ
+static OWNER: AtomicUsize = AtomicUsize::new(NO_OWNER);
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+// ロックを持ったまま例外に割り込まれたCPUの番号
+// ロックを持てるのは1つのCPUだけなので、変数も1つで足りる
+static INTERRUPTED_OWNER: AtomicUsize = AtomicUsize::new(NO_OWNER);
+
 unsafe impl critical_section::Impl for MyCriticalSection {
-    unsafe fn acquire() -> RawRestoreState {}
+    unsafe fn acquire() -> RawRestoreState {
+        // 割り込みを止めてからCPUの番号を読む (割り込まれて入れ子の判定が変わらないように)
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        let state = if enabled { STATE_INTERRUPTS_ENABLED } else { 0 };
+
+        let cpu = smp::current_index();
+        if OWNER.load(Ordering::Relaxed) == cpu {
+            // 割り込まれたコードが中で使っているデータを、例外ハンドラが二重に借りることになる
+            if INTERRUPTED_OWNER.load(Ordering::Relaxed) == cpu {
+                reentered_from_exception(cpu);
+            }
+
+            // ロックを持っているのは自分だけなので、深さはほかのCPUから変わらない
+            DEPTH.store(DEPTH.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+            return state | STATE_NESTED;
+        }
+
+        while OWNER
+            .compare_exchange_weak(NO_OWNER, cpu, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        DEPTH.store(1, Ordering::Relaxed);
+        state
+    }
+
+    unsafe fn release(restore_state: RawRestoreState) {
+        #[cfg(debug_assertions)]
+        if OWNER.load(Ordering::Relaxed) != smp::current_index() {
+            panic!("critical section released by a CPU that does not hold it");
+        }
+
+        let depth = DEPTH.load(Ordering::Relaxed) - 1;
+        DEPTH.store(depth, Ordering::Relaxed);
+        if restore_state & STATE_NESTED != 0 {
+            return;
+        }
+
+        OWNER.store(NO_OWNER, Ordering::Release);
+        if restore_state & STATE_INTERRUPTS_ENABLED != 0 {
+            interrupts::enable();
+        }
+    }
+}
+
+// パニックの表示もクリティカルセクションに入るので、ロックを取らずに出力して止める
+fn reentered_from_exception(cpu: usize) -> ! {
+    let mut buf = [0u8; 128];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "CPU {}: critical section re-entered from an exception handler\n",
+            cpu
+        ),
+    )
+    .unwrap();
+    print_serial_unlocked(_s);
+
+    loop {
+        interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
+
+// 例外のハンドラの間だけ持っておく
+// 割り込まれたコードがロックを持っていたら、その間はクリティカルセクションに入れない
+pub struct ExceptionContext {
+    // 入れ子になった例外から戻るときに元に戻す値 (ロックを持っていなかったときはNone)
+    previous: Option<usize>,
+}
+
+pub fn enter_exception() -> ExceptionContext {
+    let cpu = smp::current_index();
+    let previous = if OWNER.load(Ordering::Relaxed) == cpu {
+        Some(INTERRUPTED_OWNER.swap(cpu, Ordering::Relaxed))
+    } else {
+        None
+    };
+    ExceptionContext { previous: previous }
+}
+
+impl ExceptionContext {
+    pub fn interrupted_critical_section(&self) -> bool {
+        self.previous.is_some()
+    }
+
+    // シリアルのロックを取れないときは、ほかの出力と混ざってもそのまま送る
+    pub fn print(&self, s: &str) {
+        if self.previous.is_some() {
+            print_serial_unlocked(s);
+        } else {
+            print_serial(s);
+        }
+    }
+}
 
-    unsafe fn release(restore_state: RawRestoreState) {}
+impl Drop for ExceptionContext {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous {
+            INTERRUPTED_OWNER.store(previous, Ordering::Relaxed);
+        }
+    }
 }
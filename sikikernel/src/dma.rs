@@ -0,0 +1,85 @@
+use core::slice;
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::allocator::ALLOC;
+use crate::paging::PAGE_SIZE;
+
+// 32bitのDMAしかできないデバイス用のアドレスの上限
+pub const DMA_LIMIT_32BIT: u64 = 0x1_0000_0000;
+
+// DMAバッファはこの境界をまたがないように確保する
+const DMA_BOUNDARY: usize = 0x10000;
+
+// デバイスが直接読み書きする、物理的に連続したバッファ
+#[derive(Debug)]
+pub struct DmaBuffer {
+    virtual_address: VirtAddr,
+    physical_address: PhysAddr,
+    size: usize,
+    frames: usize,
+}
+
+impl DmaBuffer {
+    // sizeバイトの領域を、物理アドレスがlimit未満かつalignでアラインされた位置に確保する
+    pub fn new(size: usize, align: usize, limit: u64) -> Result<Self, &'static str> {
+        if size == 0 {
+            return Err("DMA buffer size is zero");
+        }
+        if !align.is_power_of_two() {
+            return Err("DMA buffer alignment is not a power of two");
+        }
+
+        let frames = size.div_ceil(PAGE_SIZE as usize);
+        let physical_address = ALLOC.allocate_frames_with_constraints(
+            frames,
+            align,
+            limit.min(usize::MAX as u64) as usize,
+            DMA_BOUNDARY,
+        )?;
+
+        // 物理メモリはストレートマップされている
+        let buffer = DmaBuffer {
+            virtual_address: VirtAddr::new(physical_address as u64),
+            physical_address: PhysAddr::new(physical_address as u64),
+            size: size,
+            frames: frames,
+        };
+        unsafe {
+            core::ptr::write_bytes(buffer.as_mut_ptr(), 0, frames * PAGE_SIZE as usize);
+        }
+
+        Ok(buffer)
+    }
+
+    pub fn virtual_address(&self) -> VirtAddr {
+        self.virtual_address
+    }
+
+    // デバイスに渡すアドレス
+    pub fn physical_address(&self) -> PhysAddr {
+        self.physical_address
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.virtual_address.as_mut_ptr()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_mut_ptr(), self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_mut_ptr(), self.size) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        ALLOC.deallocate_frames(self.physical_address.as_u64() as usize, self.frames);
+    }
+}
@@ -1,2 +1,4 @@
+pub mod block;
+pub mod hpet;
 pub mod pci;
 pub mod usb;
@@ -0,0 +1,2 @@
+pub mod ata;
+pub mod block;
@@ -0,0 +1,223 @@
+use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
+
+use crate::drivers::block::block::{BlockDevice, BlockDeviceError, SECTOR_SIZE};
+
+// レガシーIDEコントローラのI/Oポート (プライマリ, セカンダリ)
+const ATA_IO_BASE: [u16; 2] = [0x1f0, 0x170];
+const ATA_CONTROL_BASE: [u16; 2] = [0x3f6, 0x376];
+
+const ATA_COMMAND_READ_SECTORS: u8 = 0x20;
+const ATA_COMMAND_WRITE_SECTORS: u8 = 0x30;
+const ATA_COMMAND_FLUSH_CACHE: u8 = 0xe7;
+const ATA_COMMAND_IDENTIFY: u8 = 0xec;
+
+const ATA_STATUS_ERR: u8 = 1 << 0;
+const ATA_STATUS_DRQ: u8 = 1 << 3;
+const ATA_STATUS_DF: u8 = 1 << 5;
+const ATA_STATUS_BSY: u8 = 1 << 7;
+
+// 割り込みを使わないので、ステータスをポーリングする回数の上限
+const ATA_POLL_LIMIT: usize = 10_000_000;
+
+// LBA28で扱えるセクタ数の上限
+const ATA_LBA28_MAX: u64 = 1 << 28;
+
+// 割り込みを使わずにポーリングで読み書きするATAディスク (LBA28)
+#[derive(Debug)]
+pub struct AtaPio {
+    data: Port<u16>,
+    sector_count_register: PortWriteOnly<u8>,
+    lba_low: Port<u8>,
+    lba_mid: Port<u8>,
+    lba_high: Port<u8>,
+    drive_head: PortWriteOnly<u8>,
+    status: PortReadOnly<u8>,
+    command: PortWriteOnly<u8>,
+    alternate_status: PortReadOnly<u8>,
+    slave: bool,
+    sectors: u64,
+}
+
+impl AtaPio {
+    // bus: 0ならプライマリ, 1ならセカンダリ
+    pub fn new(bus: usize, slave: bool) -> Result<Self, BlockDeviceError> {
+        if bus >= ATA_IO_BASE.len() {
+            return Err(BlockDeviceError::DeviceNotFound);
+        }
+        let io_base = ATA_IO_BASE[bus];
+
+        let mut ata = AtaPio {
+            data: Port::new(io_base),
+            sector_count_register: PortWriteOnly::new(io_base + 2),
+            lba_low: Port::new(io_base + 3),
+            lba_mid: Port::new(io_base + 4),
+            lba_high: Port::new(io_base + 5),
+            drive_head: PortWriteOnly::new(io_base + 6),
+            status: PortReadOnly::new(io_base + 7),
+            command: PortWriteOnly::new(io_base + 7),
+            alternate_status: PortReadOnly::new(ATA_CONTROL_BASE[bus]),
+            slave: slave,
+            sectors: 0,
+        };
+        ata.identify()?;
+
+        Ok(ata)
+    }
+
+    fn identify(&mut self) -> Result<(), BlockDeviceError> {
+        unsafe {
+            self.drive_head.write(0xa0 | ((self.slave as u8) << 4));
+            self.wait_400ns();
+
+            self.sector_count_register.write(0);
+            self.lba_low.write(0);
+            self.lba_mid.write(0);
+            self.lba_high.write(0);
+            self.command.write(ATA_COMMAND_IDENTIFY);
+
+            // ステータスが0ならドライブがない (0xffならバスに何もつながっていない)
+            let status = self.status.read();
+            if status == 0 || status == 0xff {
+                return Err(BlockDeviceError::DeviceNotFound);
+            }
+            self.wait_not_busy()?;
+
+            // ATAPIなどATA以外のデバイスはシグネチャが入る
+            if self.lba_mid.read() != 0 || self.lba_high.read() != 0 {
+                return Err(BlockDeviceError::DeviceNotFound);
+            }
+        }
+        self.wait_data_request()?;
+
+        let mut identify = [0u16; 256];
+        for word in identify.iter_mut() {
+            *word = unsafe { self.data.read() };
+        }
+
+        // ワード60-61がLBA28でアクセスできるセクタ数
+        self.sectors = (identify[60] as u64) | ((identify[61] as u64) << 16);
+        if self.sectors == 0 {
+            return Err(BlockDeviceError::DeviceNotFound);
+        }
+
+        Ok(())
+    }
+
+    // ドライブの選択後はステータスが更新されるまで400ns待つ
+    fn wait_400ns(&mut self) {
+        for _ in 0..4 {
+            unsafe { self.alternate_status.read() };
+        }
+    }
+
+    fn wait_not_busy(&mut self) -> Result<u8, BlockDeviceError> {
+        for _ in 0..ATA_POLL_LIMIT {
+            let status = unsafe { self.status.read() };
+            if status & ATA_STATUS_BSY == 0 {
+                return Ok(status);
+            }
+        }
+        Err(BlockDeviceError::Timeout)
+    }
+
+    fn wait_data_request(&mut self) -> Result<(), BlockDeviceError> {
+        for _ in 0..ATA_POLL_LIMIT {
+            let status = self.wait_not_busy()?;
+            if status & (ATA_STATUS_ERR | ATA_STATUS_DF) != 0 {
+                return Err(BlockDeviceError::DeviceErr);
+            }
+            if status & ATA_STATUS_DRQ != 0 {
+                return Ok(());
+            }
+        }
+        Err(BlockDeviceError::Timeout)
+    }
+
+    fn check_range(&self, lba: u64, len: usize) -> Result<(), BlockDeviceError> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(BlockDeviceError::InvalidBuffer);
+        }
+        let count = (len / SECTOR_SIZE) as u64;
+        if lba + count > self.sectors || lba + count > ATA_LBA28_MAX {
+            return Err(BlockDeviceError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    // 1回のコマンドで扱えるのは255セクタまで
+    fn start_command(&mut self, command: u8, lba: u64, count: u8) -> Result<(), BlockDeviceError> {
+        self.wait_not_busy()?;
+        unsafe {
+            self.drive_head
+                .write(0xe0 | ((self.slave as u8) << 4) | ((lba >> 24) as u8 & 0x0f));
+            self.wait_400ns();
+            self.sector_count_register.write(count);
+            self.lba_low.write(lba as u8);
+            self.lba_mid.write((lba >> 8) as u8);
+            self.lba_high.write((lba >> 16) as u8);
+            self.command.write(command);
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AtaPio {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.check_range(lba, buf.len())?;
+
+        for (i, chunk) in buf.chunks_mut(SECTOR_SIZE * 255).enumerate() {
+            let sectors = chunk.len() / SECTOR_SIZE;
+            self.start_command(
+                ATA_COMMAND_READ_SECTORS,
+                lba + i as u64 * 255,
+                sectors as u8,
+            )?;
+
+            for sector in chunk.chunks_mut(SECTOR_SIZE) {
+                self.wait_data_request()?;
+                for word in sector.chunks_mut(2) {
+                    let value = unsafe { self.data.read() };
+                    word.copy_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockDeviceError> {
+        self.check_range(lba, buf.len())?;
+
+        for (i, chunk) in buf.chunks(SECTOR_SIZE * 255).enumerate() {
+            let sectors = chunk.len() / SECTOR_SIZE;
+            self.start_command(
+                ATA_COMMAND_WRITE_SECTORS,
+                lba + i as u64 * 255,
+                sectors as u8,
+            )?;
+
+            for sector in chunk.chunks(SECTOR_SIZE) {
+                self.wait_data_request()?;
+                for word in sector.chunks(2) {
+                    unsafe { self.data.write(u16::from_le_bytes([word[0], word[1]])) };
+                }
+            }
+        }
+
+        // 書き込みキャッシュをディスクに反映させる
+        self.wait_not_busy()?;
+        unsafe {
+            self.command.write(ATA_COMMAND_FLUSH_CACHE);
+        }
+        let status = self.wait_not_busy()?;
+        if status & (ATA_STATUS_ERR | ATA_STATUS_DF) != 0 {
+            return Err(BlockDeviceError::DeviceErr);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,20 @@
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Debug)]
+pub enum BlockDeviceError {
+    DeviceNotFound,
+    Timeout,
+    DeviceErr,
+    OutOfRange,
+    InvalidBuffer,
+}
+
+// セクタ単位で読み書きできるデバイス
+pub trait BlockDevice {
+    fn sector_count(&self) -> u64;
+
+    // bufの長さはSECTOR_SIZEの倍数であること
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockDeviceError>;
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockDeviceError>;
+}
@@ -0,0 +1,274 @@
+use core::cell::RefCell;
+use core::time::Duration;
+
+use critical_section::Mutex;
+use once_cell::sync::OnceCell;
+
+use crate::acpi::hpet::HpetTable;
+use crate::acpi::GenericAddress;
+use crate::apic;
+use crate::interrupts::InterruptHandler;
+use crate::mmio::{self, MmioRegion};
+use crate::print_serial;
+use crate::write::write_to;
+
+const HPET_MMIO_SIZE: usize = 0x400;
+
+const HPET_CAPABILITIES: usize = 0x000;
+const HPET_CONFIGURATION: usize = 0x010;
+const HPET_MAIN_COUNTER: usize = 0x0f0;
+
+const CAPABILITIES_COUNT_SIZE: u64 = 1 << 13;
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+
+const TIMER_CONFIGURATION_BASE: usize = 0x100;
+const TIMER_COMPARATOR_BASE: usize = 0x108;
+const TIMER_STRIDE: usize = 0x20;
+
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1f << TIMER_ROUTE_SHIFT;
+
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TimerMode {
+    OneShot,
+    Periodic,
+}
+
+struct Hpet {
+    region: MmioRegion,
+    // メインカウンタが1進む時間 (fs)
+    period_fs: u64,
+    timers: u8,
+    // 32ビットのカウンタでは上位が常に0になる
+    counter_mask: u64,
+}
+
+impl Hpet {
+    fn read(&self, offset: usize) -> u64 {
+        self.region.read::<u64>(offset)
+    }
+
+    fn write(&self, offset: usize, value: u64) {
+        self.region.write::<u64>(offset, value)
+    }
+
+    fn timer_configuration(index: u8) -> usize {
+        TIMER_CONFIGURATION_BASE + index as usize * TIMER_STRIDE
+    }
+
+    fn timer_comparator(index: u8) -> usize {
+        TIMER_COMPARATOR_BASE + index as usize * TIMER_STRIDE
+    }
+
+    fn ns_to_ticks(&self, ns: u64) -> u64 {
+        ((ns as u128 * 1_000_000 / self.period_fs as u128) as u64).max(1)
+    }
+}
+
+// コンパレータの割り込みを割り当てたGSIとベクタ
+#[derive(Debug, Copy, Clone)]
+struct TimerRoute {
+    gsi: u32,
+    vector: u8,
+}
+
+static HPET: OnceCell<Hpet> = OnceCell::new();
+static TIMER_ROUTES: Mutex<RefCell<[Option<TimerRoute>; 32]>> =
+    Mutex::new(RefCell::new([None; 32]));
+
+// ACPIのHPETテーブルからレジスタの場所を求め、メインカウンタを動かす
+pub fn initialize() -> Result<(), &'static str> {
+    let table = HpetTable::find()?;
+    if table.base_address.address_space != GenericAddress::SYSTEM_MEMORY {
+        return Err("HPET is not memory mapped");
+    }
+    let address = table.base_address.address;
+    let minimum_tick = table.minimum_tick;
+
+    let region = mmio::map_mmio(address, HPET_MMIO_SIZE).map_err(|_| "Failed to map HPET")?;
+    let mut hpet = Hpet {
+        region: region,
+        period_fs: 0,
+        timers: 0,
+        counter_mask: u64::MAX,
+    };
+
+    let capabilities = hpet.read(HPET_CAPABILITIES);
+    hpet.period_fs = capabilities >> 32;
+    hpet.timers = ((capabilities >> 8) & 0x1f) as u8 + 1;
+    if capabilities & CAPABILITIES_COUNT_SIZE == 0 {
+        hpet.counter_mask = u32::MAX as u64;
+    }
+    // 仕様では周期は100ns以下
+    if hpet.period_fs == 0 || hpet.period_fs > 100_000_000 {
+        return Err("Invalid HPET period");
+    }
+
+    // コンパレータの割り込みを止めてからカウンタを動かす (レガシー置き換えは使わない)
+    for index in 0..hpet.timers {
+        let configuration = hpet.read(Hpet::timer_configuration(index));
+        hpet.write(
+            Hpet::timer_configuration(index),
+            configuration & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC),
+        );
+    }
+    // カウンタを0から数え始める (止まっている間だけ書き込める)
+    hpet.write(HPET_CONFIGURATION, 0);
+    hpet.write(HPET_MAIN_COUNTER, 0);
+    hpet.write(HPET_CONFIGURATION, CONFIGURATION_ENABLE);
+
+    let mut buf = [0u8; 128];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "hpet: {:08x}, {}kHz, {} timers, {}-bit counter, minimum tick {}\n",
+            address,
+            FEMTOSECONDS_PER_SECOND / hpet.period_fs / 1000,
+            hpet.timers,
+            if hpet.counter_mask == u64::MAX {
+                64
+            } else {
+                32
+            },
+            minimum_tick
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+
+    HPET.set(hpet).map_err(|_| "HPET is already initialized")
+}
+
+pub fn is_available() -> bool {
+    HPET.get().is_some()
+}
+
+// 32ビットのカウンタは14MHzで5分ほどで一周する
+pub fn has_64bit_counter() -> bool {
+    HPET.get()
+        .map_or(false, |hpet| hpet.counter_mask == u64::MAX)
+}
+
+// メインカウンタの周波数 (Hz)
+pub fn frequency() -> Option<u64> {
+    HPET.get()
+        .map(|hpet| FEMTOSECONDS_PER_SECOND / hpet.period_fs)
+}
+
+pub fn read_counter() -> Option<u64> {
+    HPET.get()
+        .map(|hpet| hpet.read(HPET_MAIN_COUNTER) & hpet.counter_mask)
+}
+
+pub fn counter_to_ns(ticks: u64) -> u64 {
+    match HPET.get() {
+        Some(hpet) => (ticks as u128 * hpet.period_fs as u128 / 1_000_000) as u64,
+        None => 0,
+    }
+}
+
+// メインカウンタを見ながら待つ (他のタイマーの校正用)
+pub fn wait(duration: Duration) -> Result<(), &'static str> {
+    let hpet = HPET.get().ok_or("HPET is not initialized")?;
+    let ticks = hpet.ns_to_ticks(duration.as_nanos() as u64);
+    let start = hpet.read(HPET_MAIN_COUNTER);
+    // 32ビットのカウンタが一周しても正しく数えられるように差をとる
+    while (hpet.read(HPET_MAIN_COUNTER).wrapping_sub(start) & hpet.counter_mask) < ticks {
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+// コンパレータindexがdurationの後 (周期的なら毎回) 割り込みを起こすようにする
+pub fn start_timer(
+    index: u8,
+    mode: TimerMode,
+    duration: Duration,
+    vector: u8,
+    handler: InterruptHandler,
+) -> Result<(), &'static str> {
+    let hpet = HPET.get().ok_or("HPET is not initialized")?;
+    if index >= hpet.timers {
+        return Err("HPET timer does not exist");
+    }
+
+    let configuration = hpet.read(Hpet::timer_configuration(index));
+    if mode == TimerMode::Periodic && configuration & TIMER_PERIODIC_CAPABLE == 0 {
+        return Err("HPET timer does not support periodic mode");
+    }
+
+    // 上位32ビットはつなげられるI/O APICの入力のビットマップ
+    // ISAのIRQと重ならないように、なるべく16以上の入力を使う
+    let routes = (configuration >> 32) as u32;
+    if routes == 0 {
+        return Err("HPET timer cannot be routed to I/O APIC");
+    }
+    let gsi = match routes & !0xffff {
+        0 => routes.trailing_zeros(),
+        high => high.trailing_zeros(),
+    };
+
+    stop_timer(index)?;
+    apic::route_gsi(gsi, vector, handler)?;
+    critical_section::with(|cs| {
+        TIMER_ROUTES.borrow_ref_mut(cs)[index as usize] = Some(TimerRoute {
+            gsi: gsi,
+            vector: vector,
+        })
+    });
+
+    let ticks = hpet.ns_to_ticks(duration.as_nanos() as u64);
+    // I/O APICにはエッジトリガーとしてつなぐ
+    let mut configuration = (configuration & !(TIMER_ROUTE_MASK | TIMER_LEVEL_TRIGGERED))
+        | ((gsi as u64) << TIMER_ROUTE_SHIFT)
+        | TIMER_INTERRUPT_ENABLE;
+
+    match mode {
+        TimerMode::OneShot => {
+            configuration &= !TIMER_PERIODIC;
+            hpet.write(Hpet::timer_configuration(index), configuration);
+            let deadline = hpet.read(HPET_MAIN_COUNTER).wrapping_add(ticks) & hpet.counter_mask;
+            hpet.write(Hpet::timer_comparator(index), deadline);
+        }
+        TimerMode::Periodic => {
+            // 周期の設定中にカウンタが進まないように止めておく
+            // VALUE_SETの後の1回目の書き込みが最初の期限、2回目が周期になる
+            hpet.write(HPET_CONFIGURATION, 0);
+            configuration |= TIMER_PERIODIC | TIMER_VALUE_SET;
+            hpet.write(Hpet::timer_configuration(index), configuration);
+            let deadline = hpet.read(HPET_MAIN_COUNTER).wrapping_add(ticks) & hpet.counter_mask;
+            hpet.write(Hpet::timer_comparator(index), deadline);
+            hpet.write(Hpet::timer_comparator(index), ticks);
+            hpet.write(HPET_CONFIGURATION, CONFIGURATION_ENABLE);
+        }
+    }
+
+    Ok(())
+}
+
+// コンパレータの割り込みを止め、割り当てたベクタを解放する
+pub fn stop_timer(index: u8) -> Result<(), &'static str> {
+    let hpet = HPET.get().ok_or("HPET is not initialized")?;
+    if index >= hpet.timers {
+        return Err("HPET timer does not exist");
+    }
+
+    let configuration = hpet.read(Hpet::timer_configuration(index));
+    hpet.write(
+        Hpet::timer_configuration(index),
+        configuration & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC),
+    );
+
+    let route = critical_section::with(|cs| TIMER_ROUTES.borrow_ref_mut(cs)[index as usize].take());
+    if let Some(route) = route {
+        apic::unroute_gsi(route.gsi, route.vector)?;
+    }
+    Ok(())
+}
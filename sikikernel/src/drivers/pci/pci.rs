@@ -9,6 +9,29 @@ use x86_64::instructions::port::{Port, PortWriteOnly};
 const ConfigAddress: u16 = 0x0cf8;
 const ConfigData: u16 = 0x0cfc;
 
+const COMMAND_REGISTER: u8 = 0x04;
+const BASE_ADDRESS_REGISTER0: u8 = 0x10;
+const BASE_ADDRESS_REGISTER_COUNT: u8 = 6;
+
+// コマンドレジスタのメモリ空間のデコード
+const COMMAND_MEMORY_SPACE: u32 = 1 << 1;
+
+// BARの下位ビット
+const BAR_IO_SPACE: u32 = 1 << 0;
+const BAR_TYPE_MASK: u32 = 0b11 << 1;
+const BAR_TYPE_32BIT: u32 = 0b00 << 1;
+const BAR_TYPE_64BIT: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+const BAR_MEMORY_ADDRESS_MASK: u32 = 0xffff_fff0;
+
+// メモリ空間のBARが指す領域 (64ビットのBARは次のBARと合わせて1つ)
+#[derive(Debug, Copy, Clone)]
+pub struct MemoryBar {
+    pub address: u64,
+    pub size: u64,
+    pub prefetchable: bool,
+}
+
 #[derive(Debug, Copy, Clone, Default)]
 pub struct PCIDeviceType {
     pci_code_class: PCIClassCode,
@@ -383,6 +406,94 @@ impl PCI {
             | (register_address as u32) & 0xfc
     }
 
+    fn read_config(&mut self, bus: u8, device: u8, function: u8, register_address: u8) -> u32 {
+        unsafe {
+            self.address
+                .write(PCI::config_address(bus, device, function, register_address));
+            self.data.read()
+        }
+    }
+
+    fn write_config(
+        &mut self,
+        bus: u8,
+        device: u8,
+        function: u8,
+        register_address: u8,
+        value: u32,
+    ) {
+        unsafe {
+            self.address
+                .write(PCI::config_address(bus, device, function, register_address));
+            self.data.write(value);
+        }
+    }
+
+    // BARの種類を読み、すべて1を書いて読み戻した値から領域のサイズを求める
+    pub fn read_memory_bar(
+        &mut self,
+        pci_device: &PCIDevice,
+        index: u8,
+    ) -> Result<MemoryBar, &'static str> {
+        if index >= BASE_ADDRESS_REGISTER_COUNT {
+            return Err("Invalid BAR index");
+        }
+        let (bus, device, function) = (pci_device.bus, pci_device.device, pci_device.function);
+        let register = BASE_ADDRESS_REGISTER0 + index * 4;
+
+        let low = self.read_config(bus, device, function, register);
+        if low & BAR_IO_SPACE != 0 {
+            return Err("BAR is in I/O space");
+        }
+        let is_64bit = match low & BAR_TYPE_MASK {
+            BAR_TYPE_32BIT => false,
+            BAR_TYPE_64BIT if index + 1 < BASE_ADDRESS_REGISTER_COUNT => true,
+            _ => return Err("Unsupported BAR type"),
+        };
+        let high = if is_64bit {
+            self.read_config(bus, device, function, register + 4)
+        } else {
+            0
+        };
+
+        // 書き換えている間にデバイスが変なアドレスに応答しないように、デコードを止める
+        // (上位16ビットのステータスは1を書くとクリアされるので0を書く)
+        let command = self.read_config(bus, device, function, COMMAND_REGISTER) & 0xffff;
+        self.write_config(
+            bus,
+            device,
+            function,
+            COMMAND_REGISTER,
+            command & !COMMAND_MEMORY_SPACE,
+        );
+
+        self.write_config(bus, device, function, register, u32::MAX);
+        let size_low = self.read_config(bus, device, function, register);
+        self.write_config(bus, device, function, register, low);
+        let size_high = if is_64bit {
+            self.write_config(bus, device, function, register + 4, u32::MAX);
+            let size_high = self.read_config(bus, device, function, register + 4);
+            self.write_config(bus, device, function, register + 4, high);
+            size_high
+        } else {
+            // 32ビットのBARは4GiBを超えない
+            u32::MAX
+        };
+
+        self.write_config(bus, device, function, COMMAND_REGISTER, command);
+
+        let mask = (size_high as u64) << 32 | (size_low & BAR_MEMORY_ADDRESS_MASK) as u64;
+        if mask == 0 {
+            return Err("BAR is not implemented");
+        }
+
+        Ok(MemoryBar {
+            address: (high as u64) << 32 | (low & BAR_MEMORY_ADDRESS_MASK) as u64,
+            size: (!mask).wrapping_add(1),
+            prefetchable: low & BAR_PREFETCHABLE != 0,
+        })
+    }
+
     pub fn read_vendor_id(&mut self, bus: u8, device: u8, function: u8) -> u16 {
         unsafe {
             self.address
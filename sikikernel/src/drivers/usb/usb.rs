@@ -16,6 +16,8 @@ pub struct USBDevice {
 #[derive(Debug)]
 pub enum USBDriverError {
     AnythingErr,
+    InvalidBarErr,
+    MmioMappingErr,
 }
 
 // impl USBDriver for Xhci {
@@ -1,5 +1,6 @@
 use crate::drivers::pci::pci::*;
 use crate::drivers::usb::usb::*;
+use crate::mmio::{map_mmio, MmioRegion};
 
 #[derive(Debug)]
 pub struct CapabilityRegisters {
@@ -21,18 +22,40 @@ pub struct Xhci {
     PCIDevice: PCIDevice,
     CapabilityRegisters: CapabilityRegisters,
     MemoryMappedIOBaseAddress: u64,
+    MemoryMappedIO: MmioRegion,
 }
 
 impl Xhci {
-    pub fn new(pci_device: PCIDevice) -> Self {
-        // let mmio_base: u64 =
-        //     pci_device.pci_configuration.base_address_register0 & 0xFFFFFFF0 | (BAR1 << 32);
+    pub fn new(pci: &mut PCI, pci_device: PCIDevice) -> Result<Self, USBDriverError> {
+        // レジスタ領域はBAR0 (64ビットならBAR1と合わせて) が指す
+        let bar = pci
+            .read_memory_bar(&pci_device, 0)
+            .map_err(|_| USBDriverError::InvalidBarErr)?;
+        let mmio_base = bar.address;
 
-        // Xhci {
-        //     PCIDevice: pci_device,
-        //     MemoryMappedIOBaseAddress: mmio_base,
-        // }
-        todo!()
+        let mmio =
+            map_mmio(mmio_base, bar.size as usize).map_err(|_| USBDriverError::MmioMappingErr)?;
+
+        let capability_registers = CapabilityRegisters {
+            CapabilityRegistersLength: mmio.read::<u8>(0x00),
+            Reserved1: mmio.read::<u8>(0x01),
+            HostControllerInterfaceVersionNumber: mmio.read::<u16>(0x02),
+            StructuralParameters1: mmio.read::<u32>(0x04),
+            StructuralParameters2: mmio.read::<u32>(0x08),
+            StructuralParameters3: mmio.read::<u32>(0x0c),
+            CapabilityParameters1: mmio.read::<u32>(0x10),
+            DoorbellOffset: mmio.read::<u32>(0x14),
+            RuntimeRegisterSpaceOffset: mmio.read::<u32>(0x18),
+            CapabilityParameters2: mmio.read::<u32>(0x1c),
+            VirtualizationBasedTrustedIORegisterSpaceOffset: mmio.read::<u32>(0x20),
+        };
+
+        Ok(Xhci {
+            PCIDevice: pci_device,
+            CapabilityRegisters: capability_registers,
+            MemoryMappedIOBaseAddress: mmio_base,
+            MemoryMappedIO: mmio,
+        })
     }
 
     pub fn switch_ehci_to_xhci(&mut self) -> Result<(), USBDriverError> {
@@ -0,0 +1,118 @@
+use alloc::boxed::Box;
+
+use once_cell::sync::Lazy;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, FS, GS, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::registers::model_specific::GsBase;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+
+use crate::stack::KernelStack;
+
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+const NMI_STACK_SIZE: usize = 4096 * 5;
+const MACHINE_CHECK_STACK_SIZE: usize = 4096 * 5;
+// ユーザーモードから割り込まれたときに切り替えるスタック
+const PRIVILEGE_STACK_SIZE: usize = 4096 * 16;
+
+// CPUごとのTSS (ISTとリング0のスタックはCPUごとに別にする)
+fn create_tss() -> Result<TaskStateSegment, &'static str> {
+    let mut tss = TaskStateSegment::new();
+
+    // スタックオーバーフローでもダブルフォールトを処理できるように別のスタックを使う
+    let double_fault_stack = KernelStack::new(DOUBLE_FAULT_STACK_SIZE, "double fault")?;
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack.top();
+
+    // NMIとマシンチェックはどこで起きるかわからないので、今のスタックを信用しない
+    let nmi_stack = KernelStack::new(NMI_STACK_SIZE, "nmi")?;
+    tss.interrupt_stack_table[NMI_IST_INDEX as usize] = nmi_stack.top();
+    let machine_check_stack = KernelStack::new(MACHINE_CHECK_STACK_SIZE, "machine check")?;
+    tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = machine_check_stack.top();
+
+    let privilege_stack = KernelStack::new(PRIVILEGE_STACK_SIZE, "ring 0")?;
+    tss.privilege_stack_table[0] = privilege_stack.top();
+
+    Ok(tss)
+}
+
+static TSS: Lazy<TaskStateSegment> = Lazy::new(|| create_tss().unwrap());
+
+// SYSRETで使えるように、ユーザーのデータセグメントをコードセグメントの前に置く
+#[derive(Debug)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+pub struct Gdt {
+    table: GlobalDescriptorTable,
+    selectors: Selectors,
+}
+
+// どのCPUのGDTも同じ並びにするので、セレクタの値はCPUによらない
+fn create_gdt(tss: &'static TaskStateSegment) -> Gdt {
+    let mut table = GlobalDescriptorTable::new();
+    let kernel_code = table.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data = table.add_entry(Descriptor::kernel_data_segment());
+    let user_data = table.add_entry(Descriptor::user_data_segment());
+    let user_code = table.add_entry(Descriptor::user_code_segment());
+    let tss = table.add_entry(Descriptor::tss_segment(tss));
+    Gdt {
+        table: table,
+        selectors: Selectors {
+            kernel_code: kernel_code,
+            kernel_data: kernel_data,
+            user_data: user_data,
+            user_code: user_code,
+            tss: tss,
+        },
+    }
+}
+
+static GDT: Lazy<Gdt> = Lazy::new(|| create_gdt(&TSS));
+
+pub fn initialize() {
+    load(&GDT);
+}
+
+// BSPのGDT
+pub fn bsp() -> &'static Gdt {
+    &GDT
+}
+
+// APのGDTとTSSを作る
+// APはメモリを確保できないので、起動する前にBSPで用意しておく
+pub fn allocate() -> Result<&'static Gdt, &'static str> {
+    let tss = Box::leak(Box::new(create_tss()?));
+    Ok(Box::leak(Box::new(create_gdt(tss))))
+}
+
+pub fn load(gdt: &'static Gdt) {
+    gdt.table.load();
+
+    // GSのセレクタを読み込むとベースが0になるので、CPUごとのデータを指すベースを戻す
+    let gs_base = GsBase::read();
+
+    // ファームウェアやトランポリンのGDTのセレクタが残らないように、すべてのセグメントレジスタを読み込み直す
+    unsafe {
+        CS::set_reg(gdt.selectors.kernel_code);
+        SS::set_reg(gdt.selectors.kernel_data);
+        DS::set_reg(gdt.selectors.kernel_data);
+        ES::set_reg(gdt.selectors.kernel_data);
+        FS::set_reg(gdt.selectors.kernel_data);
+        GS::set_reg(gdt.selectors.kernel_data);
+        load_tss(gdt.selectors.tss);
+    }
+    GsBase::write(gs_base);
+}
+
+pub fn selectors() -> &'static Selectors {
+    &GDT.selectors
+}
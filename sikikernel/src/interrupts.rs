@@ -0,0 +1,495 @@
+use core::arch::{asm, global_asm};
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use once_cell::sync::Lazy;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{Entry, HandlerFunc, InterruptDescriptorTable, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+use crate::apic;
+use crate::critical_section_impl::{self, ExceptionContext};
+use crate::gdt::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX};
+use crate::graphics::{Color, Graphics};
+use crate::print_serial;
+use crate::stack;
+use crate::vma;
+use crate::write::write_to;
+use crate::BOOT_ARGS;
+
+const EXCEPTION_COUNT: usize = 32;
+const VECTOR_COUNT: usize = 256;
+
+const NMI_VECTOR: u64 = 2;
+const BREAKPOINT_VECTOR: u64 = 3;
+const DOUBLE_FAULT_VECTOR: u64 = 8;
+const PAGE_FAULT_VECTOR: u64 = 14;
+const MACHINE_CHECK_VECTOR: u64 = 18;
+
+// 各スタブはこのサイズにアラインして並べる
+const INTERRUPT_STUB_SIZE: u64 = 16;
+
+const EXCEPTION_NAMES: [&str; EXCEPTION_COUNT] = [
+    "Divide Error",
+    "Debug",
+    "Non-maskable Interrupt",
+    "Breakpoint",
+    "Overflow",
+    "Bound Range Exceeded",
+    "Invalid Opcode",
+    "Device Not Available",
+    "Double Fault",
+    "Coprocessor Segment Overrun",
+    "Invalid TSS",
+    "Segment Not Present",
+    "Stack-Segment Fault",
+    "General Protection Fault",
+    "Page Fault",
+    "Reserved",
+    "x87 Floating-Point Exception",
+    "Alignment Check",
+    "Machine Check",
+    "SIMD Floating-Point Exception",
+    "Virtualization Exception",
+    "Control Protection Exception",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Hypervisor Injection Exception",
+    "VMM Communication Exception",
+    "Security Exception",
+    "Reserved",
+];
+
+// 例外以外のベクタで呼ぶ関数 (割り込みの終了はディスパッチャがローカルAPICに通知する)
+pub type InterruptHandler = fn(&mut InterruptFrame);
+
+// 登録されたハンドラのアドレス (0なら未登録)
+static HANDLERS: [AtomicUsize; VECTOR_COUNT] = [const { AtomicUsize::new(0) }; VECTOR_COUNT];
+
+// スタブが積んだ汎用レジスタと、CPUが積んだ割り込みフレーム
+#[repr(C)]
+#[derive(Debug)]
+pub struct InterruptFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    // エラーコードを積まない例外ではスタブが0を積む
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// ベクタ番号とエラーコードの有無を揃えてから、共通の処理で汎用レジスタを保存する
+global_asm!(
+    ".macro exception_stub vector, has_error_code",
+    ".balign 16",
+    ".if \\has_error_code == 0",
+    "push 0",
+    ".endif",
+    "push \\vector",
+    "jmp exception_common",
+    ".endm",
+    "",
+    ".balign 16",
+    "exception_stubs:",
+    "exception_stub 0, 0",
+    "exception_stub 1, 0",
+    "exception_stub 2, 0",
+    "exception_stub 3, 0",
+    "exception_stub 4, 0",
+    "exception_stub 5, 0",
+    "exception_stub 6, 0",
+    "exception_stub 7, 0",
+    "exception_stub 8, 1",
+    "exception_stub 9, 0",
+    "exception_stub 10, 1",
+    "exception_stub 11, 1",
+    "exception_stub 12, 1",
+    "exception_stub 13, 1",
+    "exception_stub 14, 1",
+    "exception_stub 15, 0",
+    "exception_stub 16, 0",
+    "exception_stub 17, 1",
+    "exception_stub 18, 0",
+    "exception_stub 19, 0",
+    "exception_stub 20, 0",
+    "exception_stub 21, 1",
+    "exception_stub 22, 0",
+    "exception_stub 23, 0",
+    "exception_stub 24, 0",
+    "exception_stub 25, 0",
+    "exception_stub 26, 0",
+    "exception_stub 27, 0",
+    "exception_stub 28, 0",
+    "exception_stub 29, 1",
+    "exception_stub 30, 1",
+    "exception_stub 31, 0",
+    // 例外以外はエラーコードを積まない
+    ".set vector, 32",
+    ".rept 224",
+    "exception_stub vector, 0",
+    ".set vector, vector + 1",
+    ".endr",
+    "",
+    "exception_common:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    // 割り込みフレームとあわせて22個積んだのでスタックは16バイトにアラインされている
+    "cld",
+    "mov rdi, rsp",
+    "call {handler}",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    // ベクタ番号とエラーコードを捨てる
+    "add rsp, 16",
+    "iretq",
+    handler = sym handle_interrupt,
+);
+
+extern "C" {
+    fn exception_stubs();
+}
+
+static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
+    let mut idt = InterruptDescriptorTable::new();
+
+    // 予約済みのベクタにはフィールドがないので、すべてのエントリを配列として扱う
+    let entries = unsafe {
+        &mut *(&mut idt as *mut InterruptDescriptorTable as *mut [Entry<HandlerFunc>; VECTOR_COUNT])
+    };
+    for (vector, entry) in entries.iter_mut().enumerate() {
+        let stub = exception_stubs as *const () as u64 + vector as u64 * INTERRUPT_STUB_SIZE;
+        let options = unsafe { entry.set_handler_addr(VirtAddr::new(stub)) };
+
+        let stack_index = match vector as u64 {
+            NMI_VECTOR => NMI_IST_INDEX,
+            DOUBLE_FAULT_VECTOR => DOUBLE_FAULT_IST_INDEX,
+            MACHINE_CHECK_VECTOR => MACHINE_CHECK_IST_INDEX,
+            _ => continue,
+        };
+        unsafe {
+            options.set_stack_index(stack_index);
+        }
+    }
+
+    idt
+});
+
+pub fn initialize() {
+    IDT.load();
+}
+
+// 例外以外のベクタにハンドラを登録する
+pub fn register_handler(vector: u8, handler: InterruptHandler) -> Result<(), &'static str> {
+    if (vector as usize) < EXCEPTION_COUNT {
+        return Err("Vector is reserved for exceptions");
+    }
+    HANDLERS[vector as usize]
+        .compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+        .map(|_| ())
+        .map_err(|_| "Vector already has a handler")
+}
+
+pub fn unregister_handler(vector: u8) {
+    HANDLERS[vector as usize].store(0, Ordering::Release);
+}
+
+extern "sysv64" fn handle_interrupt(frame: &mut InterruptFrame) {
+    if frame.vector as usize >= EXCEPTION_COUNT {
+        dispatch_interrupt(frame);
+        return;
+    }
+
+    // 例外はクリティカルセクションの中でも起きる (NMIとマシンチェックは割り込みを止めていても入ってくる)
+    // 割り込まれたコードがクリティカルセクションを持っていれば、出力のロックを取らない
+    let context = critical_section_impl::enter_exception();
+    let interrupted_critical_section = context.interrupted_critical_section();
+
+    match frame.vector {
+        // 予約済みの領域へのアクセスなら、フレームを割り当てて再開する
+        // クリティカルセクションの中では、ページテーブルや領域のリストを使っているかもしれないので処理しない
+        PAGE_FAULT_VECTOR => {
+            if interrupted_critical_section {
+                context.print("page fault in a critical section\n");
+            } else if handle_page_fault(frame) {
+                return;
+            }
+        }
+        NMI_VECTOR => {
+            let mut buf = [0u8; 128];
+            let _s: &str = write_to::show(
+                &mut buf,
+                format_args!("NMI\nrip: {:016x}, rsp: {:016x}\n", frame.rip, frame.rsp),
+            )
+            .unwrap();
+            context.print(_s);
+            return;
+        }
+        // ブレークポイントはレジスタを表示して再開する
+        BREAKPOINT_VECTOR => {
+            dump_exception(frame, &context);
+            return;
+        }
+        // ガードページのリストもクリティカルセクションで守られている
+        DOUBLE_FAULT_VECTOR if !interrupted_critical_section => report_stack_overflow(frame),
+        _ => {}
+    }
+
+    dump_exception(frame, &context);
+    halt();
+}
+
+fn dispatch_interrupt(frame: &mut InterruptFrame) {
+    let vector = frame.vector as u8;
+    match HANDLERS[vector as usize].load(Ordering::Acquire) {
+        0 => {
+            if vector != apic::SPURIOUS_VECTOR {
+                let mut buf = [0u8; 64];
+                let _s: &str = write_to::show(
+                    &mut buf,
+                    format_args!("unexpected interrupt: vector {:#04x}\n", vector),
+                )
+                .unwrap();
+                print_serial(_s);
+            }
+        }
+        handler => {
+            let handler = unsafe { mem::transmute::<usize, InterruptHandler>(handler) };
+            handler(frame);
+        }
+    }
+
+    // スプリアス割り込みにはEOIを送らない
+    if vector != apic::SPURIOUS_VECTOR {
+        apic::end_of_interrupt();
+    }
+}
+
+// ページフォールトを処理できればtrueを返す
+fn handle_page_fault(frame: &InterruptFrame) -> bool {
+    let addr = Cr2::read();
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+
+    // 存在しないページへのアクセスなら、予約済みの領域にフレームを割り当てて再開する
+    let result = if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        Err("Page protection violation")
+    } else {
+        vma::handle_page_fault(addr)
+    };
+    let reason = match result {
+        Ok(()) => return true,
+        Err(reason) => reason,
+    };
+
+    // ガードページへのアクセスでも、スタックに余裕があればページフォールトとして届く
+    let (area, reason) = match stack::find_guard_page(addr) {
+        Some(task) => (task, "kernel stack overflow"),
+        None => (vma::find(addr).map_or("none", |area| area.name), reason),
+    };
+
+    let mut buf = [0u8; 512];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "PAGE FAULT\naddress: {:016x}, rip: {:016x}, rsp: {:016x}\nerror code: {:?} ({} {} in {} mode{}{})\narea: {}, reason: {}\n",
+            addr.as_u64(),
+            frame.rip,
+            frame.rsp,
+            error_code,
+            if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+                "protection violation"
+            } else {
+                "non-present page"
+            },
+            if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+                "on instruction fetch"
+            } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+                "on write"
+            } else {
+                "on read"
+            },
+            if error_code.contains(PageFaultErrorCode::USER_MODE) {
+                "user"
+            } else {
+                "kernel"
+            },
+            if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+                ", reserved bit set"
+            } else {
+                ""
+            },
+            if error_code.contains(PageFaultErrorCode::PROTECTION_KEY) {
+                ", protection key"
+            } else {
+                ""
+            },
+            area,
+            reason
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+
+    false
+}
+
+// スタックオーバーフローではページフォールトの処理でスタックを積めずにダブルフォールトになる
+fn report_stack_overflow(frame: &InterruptFrame) {
+    let addr = Cr2::read();
+    if let Some(task) = stack::find_guard_page(addr) {
+        let mut buf = [0u8; 256];
+        let _s: &str = write_to::show(
+            &mut buf,
+            format_args!(
+                "kernel stack overflow\ntask: {}, rip: {:016x}, address: {:016x}\n",
+                task,
+                frame.rip,
+                addr.as_u64()
+            ),
+        )
+        .unwrap();
+        print_serial(_s);
+    }
+}
+
+// シリアルとフレームバッファの両方に出力する
+struct ExceptionConsole<'a> {
+    graphics: Option<Graphics>,
+    y: u32,
+    context: &'a ExceptionContext,
+}
+
+impl<'a> ExceptionConsole<'a> {
+    fn new(context: &'a ExceptionContext) -> Self {
+        ExceptionConsole {
+            graphics: BOOT_ARGS.get().map(|args| Graphics {
+                frame_buffer_info: args.frame_buffer_info,
+                mode_info: args.mode_info,
+            }),
+            y: 0,
+            context: context,
+        }
+    }
+
+    fn print(&mut self, s: &str) {
+        self.context.print(s);
+
+        if let Some(graphics) = self.graphics.as_mut() {
+            let (width, _) = graphics.get_resolve();
+            for line in s.lines() {
+                graphics.draw_rect(0, self.y, width, 20, Color(128, 0, 0));
+                graphics.draw_fonts(8, self.y + 2, line, Color(255, 255, 255));
+                self.y += 20;
+            }
+        }
+    }
+}
+
+fn dump_exception(frame: &InterruptFrame, context: &ExceptionContext) {
+    let mut console = ExceptionConsole::new(context);
+
+    let mut buf = [0u8; 256];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "EXCEPTION: {} (vector {})\nerror code: {:016x}\nrip: {:016x}, rsp: {:016x}, rflags: {:016x}\ncs: {:04x}, ss: {:04x}\n",
+            EXCEPTION_NAMES[frame.vector as usize % EXCEPTION_COUNT],
+            frame.vector,
+            frame.error_code,
+            frame.rip,
+            frame.rsp,
+            frame.rflags,
+            frame.cs,
+            frame.ss
+        ),
+    )
+    .unwrap();
+    console.print(_s);
+
+    if frame.vector == PAGE_FAULT_VECTOR {
+        let mut buf = [0u8; 64];
+        let _s: &str = write_to::show(
+            &mut buf,
+            format_args!("cr2: {:016x}\n", Cr2::read().as_u64()),
+        )
+        .unwrap();
+        console.print(_s);
+    }
+
+    let mut buf = [0u8; 512];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "rax: {:016x}, rbx: {:016x}, rcx: {:016x}\nrdx: {:016x}, rsi: {:016x}, rdi: {:016x}\nrbp: {:016x}, r8:  {:016x}, r9:  {:016x}\nr10: {:016x}, r11: {:016x}, r12: {:016x}\nr13: {:016x}, r14: {:016x}, r15: {:016x}\n",
+            frame.rax,
+            frame.rbx,
+            frame.rcx,
+            frame.rdx,
+            frame.rsi,
+            frame.rdi,
+            frame.rbp,
+            frame.r8,
+            frame.r9,
+            frame.r10,
+            frame.r11,
+            frame.r12,
+            frame.r13,
+            frame.r14,
+            frame.r15
+        ),
+    )
+    .unwrap();
+    console.print(_s);
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe { asm!("cli", "hlt") }
+    }
+}
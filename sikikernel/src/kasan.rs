@@ -0,0 +1,342 @@
+use core::alloc::Layout;
+use core::cell::RefCell;
+use core::{cmp, ptr};
+
+use critical_section::Mutex;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::PhysAddr;
+
+use crate::allocator::{SimpleAlloc, ALLOC};
+use crate::memory_layout::{PhysicalMemoryLayout, PhysicalRegionKind};
+use crate::paging::{self, PAGE_SIZE};
+use crate::print_serial;
+use crate::vma;
+use crate::write::write_to;
+
+// シャドウメモリの1バイトが8バイトに対応する
+// 0: 全てアクセス可能, 1-7: 先頭のnバイトだけアクセス可能, 負の値: アクセス不可
+const SHADOW_SCALE_SHIFT: u64 = 3;
+const SHADOW_GRANULE: u64 = 1 << SHADOW_SCALE_SHIFT;
+
+const SHADOW_HEAP_REDZONE: u8 = 0xfc;
+const SHADOW_HEAP_FREED: u8 = 0xfb;
+
+// ヒープ確保の前後に置くレッドゾーンの最小サイズ
+const REDZONE_SIZE: usize = 64;
+
+// 解放したメモリをすぐには再利用せず、解放後の使用を検出できるようにする
+const QUARANTINE_SIZE: usize = 256;
+
+// シャドウをまとめてマップする単位
+const SHADOW_CHUNK_FRAMES: u64 = 512;
+
+// 計装されたコードから呼ばれるので、アトミック操作などコアライブラリの関数を通さずに読む
+static mut SHADOW_START: u64 = 0;
+// シャドウが対応している物理メモリの上限
+static mut SHADOW_LIMIT: u64 = 0;
+static mut REPORTING: bool = false;
+
+#[derive(Debug, Copy, Clone)]
+struct QuarantineEntry {
+    base: usize,
+    size: usize,
+    align: usize,
+}
+
+struct Quarantine {
+    entries: [Option<QuarantineEntry>; QUARANTINE_SIZE],
+    next: usize,
+}
+
+static QUARANTINE: Mutex<RefCell<Quarantine>> = Mutex::new(RefCell::new(Quarantine {
+    entries: [None; QUARANTINE_SIZE],
+    next: 0,
+}));
+
+// ストレートマップされた物理メモリ全体のシャドウを用意して検査を有効にする
+pub fn initialize(layout: &PhysicalMemoryLayout) {
+    let mut limit = 0;
+    for region in layout.regions() {
+        match region.kind {
+            PhysicalRegionKind::Usable
+            | PhysicalRegionKind::BootServices
+            | PhysicalRegionKind::KernelImage
+            | PhysicalRegionKind::BootArgs => limit = cmp::max(limit, region.end),
+            _ => {}
+        }
+    }
+
+    let shadow_size = (limit >> SHADOW_SCALE_SHIFT).next_multiple_of(PAGE_SIZE);
+    let area = vma::reserve(
+        shadow_size as usize,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        "kasan shadow",
+    )
+    .unwrap();
+
+    // 1ページずつ確保すると遅いので、まとめて確保したフレームをマップする
+    let mut offset = 0;
+    while offset < shadow_size {
+        let frames = cmp::min(SHADOW_CHUNK_FRAMES, (shadow_size - offset) / PAGE_SIZE);
+        let frame_addr = ALLOC.allocate_frames(frames as usize).unwrap() as u64;
+        unsafe {
+            ptr::write_bytes(frame_addr as *mut u8, 0, (frames * PAGE_SIZE) as usize);
+        }
+        for i in 0..frames {
+            paging::map(
+                Page::containing_address(area.start + offset + i * PAGE_SIZE),
+                PhysFrame::containing_address(PhysAddr::new(frame_addr + i * PAGE_SIZE)),
+                area.flags,
+            )
+            .unwrap();
+        }
+        offset += frames * PAGE_SIZE;
+    }
+
+    unsafe {
+        SHADOW_LIMIT = limit;
+        SHADOW_START = area.start.as_u64();
+    }
+
+    let mut buf = [0u8; 128];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "kasan: {}MiB shadow for {}MiB of memory\n",
+            shadow_size / 1024 / 1024,
+            limit / 1024 / 1024
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+}
+
+#[sanitize(address = "off")]
+fn shadow_address(addr: u64) -> u64 {
+    unsafe { SHADOW_START + (addr >> SHADOW_SCALE_SHIFT) }
+}
+
+#[sanitize(address = "off")]
+fn is_covered(addr: u64, size: u64) -> bool {
+    unsafe { SHADOW_START != 0 && addr < SHADOW_LIMIT && size <= SHADOW_LIMIT - addr }
+}
+
+// addrとsizeはSHADOW_GRANULEにアラインされていること
+#[sanitize(address = "off")]
+fn poison(addr: u64, size: u64, value: u8) {
+    if !is_covered(addr, size) {
+        return;
+    }
+    let mut granule = addr;
+    while granule < addr + size {
+        unsafe { *(shadow_address(granule) as *mut u8) = value };
+        granule += SHADOW_GRANULE;
+    }
+}
+
+// addrはSHADOW_GRANULEにアラインされていること
+#[sanitize(address = "off")]
+fn unpoison(addr: u64, size: u64) {
+    if !is_covered(addr, size) {
+        return;
+    }
+    let mut granule = addr;
+    while granule < addr + size {
+        let accessible = addr + size - granule;
+        let value = if accessible >= SHADOW_GRANULE {
+            0
+        } else {
+            accessible as u8
+        };
+        unsafe { *(shadow_address(granule) as *mut u8) = value };
+        granule += SHADOW_GRANULE;
+    }
+}
+
+#[sanitize(address = "off")]
+fn check_access(addr: u64, size: u64, write: bool) {
+    if unsafe { REPORTING } || size == 0 || !is_covered(addr, size) {
+        return;
+    }
+
+    let mut current = addr;
+    while current < addr + size {
+        let shadow = unsafe { *(shadow_address(current) as *const i8) };
+        if shadow == 0 {
+            current = (current | (SHADOW_GRANULE - 1)) + 1;
+            continue;
+        }
+        if (current & (SHADOW_GRANULE - 1)) as i8 >= shadow {
+            report(current, addr, size, write, shadow as u8);
+            return;
+        }
+        current += 1;
+    }
+}
+
+#[sanitize(address = "off")]
+fn report(bad_addr: u64, addr: u64, size: u64, write: bool, shadow: u8) {
+    // レポート中のアクセスは検査しない
+    unsafe { REPORTING = true };
+
+    let kind = match shadow {
+        SHADOW_HEAP_REDZONE => "heap-out-of-bounds",
+        SHADOW_HEAP_FREED => "use-after-free",
+        _ => "out-of-bounds",
+    };
+
+    let mut buf = [0u8; 256];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "KASAN: {} {} of size {} at {:016x}\nfirst bad address: {:016x}, shadow: {:02x}\n",
+            kind,
+            if write { "write" } else { "read" },
+            size,
+            addr,
+            bad_addr,
+            shadow
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+
+    unsafe { REPORTING = false };
+}
+
+fn padded_layout(layout: Layout) -> Option<(usize, Layout)> {
+    let left_redzone = cmp::max(REDZONE_SIZE, layout.align());
+    let size = left_redzone
+        .checked_add(layout.size())?
+        .checked_add(REDZONE_SIZE)?;
+    Some((
+        left_redzone,
+        Layout::from_size_align(size, layout.align()).ok()?,
+    ))
+}
+
+// 要求されたサイズの前後にレッドゾーンを付けて確保する
+pub unsafe fn allocate(alloc: &SimpleAlloc, layout: Layout) -> *mut u8 {
+    let (left_redzone, padded) = match padded_layout(layout) {
+        Some(padded) => padded,
+        None => return ptr::null_mut(),
+    };
+
+    let base = alloc.allocate(padded);
+    if base.is_null() {
+        return base;
+    }
+
+    let base = base as u64;
+    let addr = base + left_redzone as u64;
+    let object_end = (addr + layout.size() as u64).next_multiple_of(SHADOW_GRANULE);
+    let block_end = base + (padded.size() as u64).next_multiple_of(PAGE_SIZE);
+
+    poison(base, addr - base, SHADOW_HEAP_REDZONE);
+    unpoison(addr, layout.size() as u64);
+    poison(object_end, block_end - object_end, SHADOW_HEAP_REDZONE);
+
+    addr as *mut u8
+}
+
+pub unsafe fn deallocate(alloc: &SimpleAlloc, ptr: *mut u8, layout: Layout) {
+    let (left_redzone, padded) = padded_layout(layout).unwrap();
+    let addr = ptr as u64;
+    let base = addr - left_redzone as u64;
+
+    poison(
+        addr,
+        (layout.size() as u64).next_multiple_of(SHADOW_GRANULE),
+        SHADOW_HEAP_FREED,
+    );
+
+    // 隔離領域から押し出された古いブロックだけを実際に解放する
+    let evicted = critical_section::with(|cs| {
+        let mut quarantine = QUARANTINE.borrow_ref_mut(cs);
+        let next = quarantine.next;
+        quarantine.next = (next + 1) % QUARANTINE_SIZE;
+        quarantine.entries[next].replace(QuarantineEntry {
+            base: base as usize,
+            size: padded.size(),
+            align: padded.align(),
+        })
+    });
+
+    if let Some(entry) = evicted {
+        unpoison(
+            entry.base as u64,
+            (entry.size as u64).next_multiple_of(PAGE_SIZE),
+        );
+        alloc.deallocate(
+            entry.base as *mut u8,
+            Layout::from_size_align_unchecked(entry.size, entry.align),
+        );
+    }
+}
+
+macro_rules! asan_access_hooks {
+    ($($load:ident, $load_noabort:ident, $store:ident, $store_noabort:ident, $size:expr;)*) => {
+        $(
+            #[no_mangle]
+            #[sanitize(address = "off")]
+            pub extern "C" fn $load(addr: usize) {
+                check_access(addr as u64, $size, false)
+            }
+
+            #[no_mangle]
+            #[sanitize(address = "off")]
+            pub extern "C" fn $load_noabort(addr: usize) {
+                check_access(addr as u64, $size, false)
+            }
+
+            #[no_mangle]
+            #[sanitize(address = "off")]
+            pub extern "C" fn $store(addr: usize) {
+                check_access(addr as u64, $size, true)
+            }
+
+            #[no_mangle]
+            #[sanitize(address = "off")]
+            pub extern "C" fn $store_noabort(addr: usize) {
+                check_access(addr as u64, $size, true)
+            }
+        )*
+    };
+}
+
+asan_access_hooks! {
+    __asan_load1, __asan_load1_noabort, __asan_store1, __asan_store1_noabort, 1;
+    __asan_load2, __asan_load2_noabort, __asan_store2, __asan_store2_noabort, 2;
+    __asan_load4, __asan_load4_noabort, __asan_store4, __asan_store4_noabort, 4;
+    __asan_load8, __asan_load8_noabort, __asan_store8, __asan_store8_noabort, 8;
+    __asan_load16, __asan_load16_noabort, __asan_store16, __asan_store16_noabort, 16;
+}
+
+#[no_mangle]
+#[sanitize(address = "off")]
+pub extern "C" fn __asan_loadN(addr: usize, size: usize) {
+    check_access(addr as u64, size as u64, false)
+}
+
+#[no_mangle]
+#[sanitize(address = "off")]
+pub extern "C" fn __asan_loadN_noabort(addr: usize, size: usize) {
+    check_access(addr as u64, size as u64, false)
+}
+
+#[no_mangle]
+#[sanitize(address = "off")]
+pub extern "C" fn __asan_storeN(addr: usize, size: usize) {
+    check_access(addr as u64, size as u64, true)
+}
+
+#[no_mangle]
+#[sanitize(address = "off")]
+pub extern "C" fn __asan_storeN_noabort(addr: usize, size: usize) {
+    check_access(addr as u64, size as u64, true)
+}
+
+// スタックの計装は無効にしているので何もしない
+#[no_mangle]
+#[sanitize(address = "off")]
+pub extern "C" fn __asan_handle_no_return() {}
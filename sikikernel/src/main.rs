@@ -2,41 +2,91 @@
 #![no_main] // disable all Rust-level entry points
 #![feature(lang_items)]
 #![feature(strict_provenance)]
+#![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
+#![feature(allocator_api)]
+#![cfg_attr(feature = "kasan", feature(sanitize))]
 
 extern crate alloc;
 
 use allocator::ALLOC;
-use core::{arch::asm, cell::RefCell, panic::PanicInfo};
-use lib::{MemoryType, SikiOSArguments};
+use core::{arch::asm, cell::RefCell, panic::PanicInfo, ptr::addr_of};
+use lib::{MemoryType, SikiOSArguments, UEFI_PAGE_SIZE};
 
 mod allocator;
 use alloc::{boxed::Box, vec};
 
 use critical_section::Mutex;
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 use uart_16550::SerialPort;
+use x86_64::structures::paging::Page;
+use x86_64::VirtAddr;
 
+mod acpi;
+mod apic;
 mod ascii_font;
 mod critical_section_impl;
+mod dma;
 mod drivers;
+mod gdt;
 mod graphics;
+mod interrupts;
+#[cfg(feature = "kasan")]
+mod kasan;
+mod memory_layout;
+mod memtest;
+mod mmio;
+mod paging;
+mod pit;
+mod power;
+mod smp;
+mod stack;
+mod swap;
+mod timer;
+mod tsc;
+mod vma;
 mod write;
 
+use drivers::hpet::hpet;
 use drivers::pci::pci::*;
 use graphics::*;
 use write::*;
 
 const SERIAL_IO_PORT: u16 = 0x3F8;
 
+const KERNEL_MAIN_STACK_SIZE: usize = 1024 * 1024;
+
+// 最下位のページをガードページにするのでページ境界にアラインする
+#[repr(C, align(4096))]
+struct KernelMainStack([u8; KERNEL_MAIN_STACK_SIZE]);
+
+static mut KERNEL_MAIN_STACK: KernelMainStack = KernelMainStack([0; KERNEL_MAIN_STACK_SIZE]);
+
+// ローダーから受け取った引数のコピー
+static BOOT_ARGS: OnceCell<SikiOSArguments> = OnceCell::new();
+
 static SERIAL_PORT: Lazy<Mutex<RefCell<SerialPort>>> = Lazy::new(|| {
     let mut serial_port = unsafe { SerialPort::new(SERIAL_IO_PORT) };
     serial_port.init();
     Mutex::new(RefCell::new(serial_port))
 });
 
+// 複数のCPUの出力が混ざらないように、1つの文字列を送り終えるまでロックを持つ
 fn print_serial(s: &str) {
+    critical_section::with(|cs| {
+        let mut serial_port = SERIAL_PORT.borrow_ref_mut(cs);
+        for i in s.as_bytes() {
+            serial_port.send(*i);
+        }
+    })
+}
+
+// ロックを持ったまま割り込まれた例外ハンドラから使う
+// SERIAL_PORTは借りられないので、初期化済みのポートに直接送る
+fn print_serial_unlocked(s: &str) {
+    let mut serial_port = unsafe { SerialPort::new(SERIAL_IO_PORT) };
     for i in s.as_bytes() {
-        critical_section::with(|cs| SERIAL_PORT.borrow_ref_mut(cs).send(*i))
+        serial_port.send(*i);
     }
 }
 
@@ -49,6 +99,14 @@ fn panic(_info: &PanicInfo) -> ! {
     let _s: &str = write_to::show(&mut buf, format_args!("message: {}\n", _info)).unwrap();
     print_serial(_s);
 
+    // panic=rebootが指定されていれば、止まらずに再起動する
+    if BOOT_ARGS
+        .get()
+        .is_some_and(|args| args.command_line.get("panic") == Some("reboot"))
+    {
+        power::reboot();
+    }
+
     loop {
         unsafe {
             asm!("hlt");
@@ -59,12 +117,152 @@ fn panic(_info: &PanicInfo) -> ! {
 // #[no_mangle] // don't mangle the name of this function
 #[export_name = "_start"]
 pub extern "sysv64" fn _start(args: &SikiOSArguments) -> ! {
+    // クリティカルセクションはGSでCPUを見分けるので、ファームウェアが残した値を消しておく
+    smp::clear_current();
+
+    // 引数とスタックはローダーの領域にあるので、カーネル側にコピーしてスタックを切り替える
+    BOOT_ARGS.set(*args).unwrap();
+    memory_layout::set_boot_args_address(args as *const SikiOSArguments as u64);
+
+    unsafe {
+        let stack_end = addr_of!(KERNEL_MAIN_STACK) as usize + KERNEL_MAIN_STACK_SIZE;
+        asm!(
+            "mov rsp, {0}",
+            "call {1}",
+            in(reg) stack_end,
+            sym kernel_main,
+            options(noreturn)
+        );
+    }
+}
+
+extern "sysv64" fn kernel_main() -> ! {
+    let args = BOOT_ARGS.get().unwrap();
+
     let mut graphics = Graphics {
         frame_buffer_info: args.frame_buffer_info,
         mode_info: args.mode_info,
     };
 
-    ALLOC.initialize(args.memory_map);
+    let memory_layout = memory_layout::initialize(args);
+    ALLOC.initialize(memory_layout);
+    paging::initialize(args);
+
+    let main_stack_guard = VirtAddr::new(addr_of!(KERNEL_MAIN_STACK) as u64);
+    paging::unmap(Page::containing_address(main_stack_guard)).unwrap();
+    stack::register_guard_page(main_stack_guard, "main");
+
+    gdt::initialize();
+    interrupts::initialize();
+
+    // ブートサービスの領域を解放する前に、RSDPからACPIのテーブルを集める
+    match acpi::initialize(args.rsdp_address).and_then(|_| apic::initialize()) {
+        Ok(()) => {}
+        Err(reason) => {
+            let mut buf = [0u8; 128];
+            let _s: &str =
+                write_to::show(&mut buf, format_args!("interrupts: {}\n", reason)).unwrap();
+            print_serial(_s);
+        }
+    }
+    if args.command_line.contains("acpi.dump") {
+        acpi::dump();
+    }
+    // HPETがなくてもPITで校正してAPICタイマーを使える
+    if let Err(reason) = hpet::initialize() {
+        let mut buf = [0u8; 128];
+        let _s: &str = write_to::show(&mut buf, format_args!("hpet: {}\n", reason)).unwrap();
+        print_serial(_s);
+    }
+    // timer=hpetでAPICタイマーの代わりにHPETでティックを起こす
+    let force_hpet = args.command_line.get("timer") == Some("hpet");
+    match timer::initialize(force_hpet) {
+        Ok(_) => x86_64::instructions::interrupts::enable(),
+        Err(reason) => {
+            let mut buf = [0u8; 128];
+            let _s: &str = write_to::show(&mut buf, format_args!("timer: {}\n", reason)).unwrap();
+            print_serial(_s);
+        }
+    }
+    // DSDTとSSDTのAMLを読み込む (メソッドの中でタイマーを使うことがある)
+    if let Err(error) = acpi::aml::initialize() {
+        let mut buf = [0u8; 128];
+        let _s: &str = write_to::show(&mut buf, format_args!("aml: {:?}\n", error)).unwrap();
+        print_serial(_s);
+    }
+    if args.command_line.contains("aml.dump") {
+        acpi::aml::dump_namespace();
+    }
+    // 電源ボタンのSCIを受け取る (失敗してもシャットダウンと再起動は試せる)
+    if let Err(reason) = power::initialize() {
+        let mut buf = [0u8; 128];
+        let _s: &str = write_to::show(&mut buf, format_args!("power: {}\n", reason)).unwrap();
+        print_serial(_s);
+    }
+
+    // ブートサービスの領域を解放する前に、物理メモリ全体のシャドウを用意する
+    #[cfg(feature = "kasan")]
+    kasan::initialize(memory_layout);
+
+    let reclaimed_pages = ALLOC.reclaim_boot_services_memory(memory_layout);
+    let mut buf = [0u8; 256];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "reclaimed boot services memory: {}MiB\n",
+            reclaimed_pages * 4096 / 1024 / 1024
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+    memory_layout.print_summary();
+
+    // APを起動する (トランポリンは低位メモリに置くので、ローダーの領域を解放した後にする)
+    if let Err(reason) = smp::initialize(memory_layout) {
+        let mut buf = [0u8; 128];
+        let _s: &str = write_to::show(&mut buf, format_args!("smp: {}\n", reason)).unwrap();
+        print_serial(_s);
+    }
+
+    // 起動時にmemtestが指定されていれば、空きメモリをすべて検査する
+    if let Some(options) = memtest::MemtestOptions::from_command_line(&args.command_line) {
+        memtest::run(&options, memory_layout);
+        if !options.continue_boot {
+            print_serial("memtest: halted\n");
+            loop {
+                unsafe { asm!("hlt") }
+            }
+        }
+    }
+
+    // swap=ata<バス>.<ドライブ>で指定されたディスクをスワップ領域にする
+    if let Some(device) = args.command_line.get("swap") {
+        if let Err(reason) = swap::initialize(device) {
+            let mut buf = [0u8; 128];
+            let _s: &str = write_to::show(&mut buf, format_args!("swap: {}\n", reason)).unwrap();
+            print_serial(_s);
+        }
+    }
+    if let Some(size) = args
+        .command_line
+        .get("swaptest")
+        .and_then(|size| size.parse::<usize>().ok())
+    {
+        if let Err(reason) = swap::self_test(size * 1024 * 1024) {
+            let mut buf = [0u8; 128];
+            let _s: &str =
+                write_to::show(&mut buf, format_args!("swap test: {}\n", reason)).unwrap();
+            print_serial(_s);
+        }
+    }
+    if args.command_line.contains("timertest") {
+        if let Err(reason) = timer::self_test() {
+            let mut buf = [0u8; 128];
+            let _s: &str =
+                write_to::show(&mut buf, format_args!("timer test: {}\n", reason)).unwrap();
+            print_serial(_s);
+        }
+    }
     // let mut buf = [0u8; 256];
     // let _s: &str =
     //     write_to::show(&mut buf, format_args!("{}\n", unsafe { ALLOC.head.get() })).unwrap();
@@ -79,17 +277,17 @@ pub extern "sysv64" fn _start(args: &SikiOSArguments) -> ! {
     graphics.draw_rect(10, 10, 20, 20, Color(255, 255, 255));
     graphics.draw_fonts(40, 40, "Hello, World", Color(0, 0, 255));
 
-    for i in 0..args.memory_map.len {
+    for (i, descriptor) in args.memory_map.iter().enumerate() {
         let mut buf = [0u8; 256];
         let _s: &str = write_to::show(
             &mut buf,
             format_args!(
                 "{}, {:?}, {:08x}, {:x}, {:x}\n",
                 i,
-                args.memory_map.map[i].memory_type,
-                args.memory_map.map[i].physical_start,
-                args.memory_map.map[i].number_of_pages,
-                args.memory_map.map[i].attribute
+                descriptor.memory_type,
+                descriptor.physical_start,
+                descriptor.number_of_pages,
+                descriptor.attribute
             ),
         )
         .unwrap();
@@ -97,9 +295,8 @@ pub extern "sysv64" fn _start(args: &SikiOSArguments) -> ! {
         graphics.draw_fonts(40, 60 + i as u32 * 20, _s, Color(255, 255, 255));
     }
 
-    let mut total_pages = 0;
-    for i in 0..args.memory_map.len {
-        if args.memory_map.map[i].memory_type != MemoryType::CONVENTIONAL {
+    for region in args.memory_map.merged_regions() {
+        if region.memory_type != MemoryType::CONVENTIONAL {
             continue;
         }
 
@@ -107,24 +304,23 @@ pub extern "sysv64" fn _start(args: &SikiOSArguments) -> ! {
         let _s: &str = write_to::show(
             &mut buf,
             format_args!(
-                "{}, {:?}, {:08x}, {:x}, {:x}\n",
-                i,
-                args.memory_map.map[i].memory_type,
-                args.memory_map.map[i].physical_start,
-                args.memory_map.map[i].number_of_pages,
-                args.memory_map.map[i].attribute
+                "{:?}, {:08x}, {:x}\n",
+                region.memory_type,
+                region.start,
+                region.size() / UEFI_PAGE_SIZE
             ),
         )
         .unwrap();
         print_serial(_s);
-
-        total_pages += args.memory_map.map[i].number_of_pages;
     }
 
     let mut buf = [0u8; 256];
     let _s: &str = write_to::show(
         &mut buf,
-        format_args!("total: {}Mib\n", total_pages * 4096 / 1024 / 1024),
+        format_args!(
+            "total: {}Mib\n",
+            args.memory_map.total_usable_bytes() / 1024 / 1024
+        ),
     )
     .unwrap();
     print_serial(_s);
@@ -160,7 +356,16 @@ pub extern "sysv64" fn _start(args: &SikiOSArguments) -> ! {
 
     // ----ALLOC TEST----
 
+    // rebootが指定されていれば、起動が終わったところで再起動する (リセットの経路の確認用)
+    if args.command_line.contains("reboot") {
+        power::reboot();
+    }
+
     loop {
+        // 電源ボタンが押されたら、割り込みの外でシャットダウンする
+        if power::is_shutdown_requested() {
+            power::shutdown();
+        }
         unsafe { asm!("hlt") }
     }
 
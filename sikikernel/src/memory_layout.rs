@@ -0,0 +1,237 @@
+use core::mem::size_of;
+
+use lib::{MemoryType, SikiOSArguments, MEMORY_MAP_SIZE};
+use once_cell::sync::OnceCell;
+
+use crate::paging::PAGE_SIZE;
+use crate::print_serial;
+use crate::write::write_to;
+
+// メモリマップの領域に、フレームバッファなどを重ねて分割したときの最大数
+const PHYSICAL_REGION_MAX: usize = MEMORY_MAP_SIZE + 16;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PhysicalRegionKind {
+    // アロケータが使ってよい領域
+    Usable,
+    // ブートサービスとローダーの領域 (引数をコピーした後に使ってよい)
+    BootServices,
+    Reserved,
+    FrameBuffer,
+    Acpi,
+    KernelImage,
+    BootArgs,
+}
+
+// 物理アドレスの範囲 [start, end)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PhysicalRegion {
+    pub start: u64,
+    pub end: u64,
+    pub kind: PhysicalRegionKind,
+}
+
+impl PhysicalRegion {
+    pub fn pages(&self) -> u64 {
+        (self.end - self.start) / PAGE_SIZE
+    }
+}
+
+// 互いに重ならず、開始アドレス順に並んだ物理メモリの領域
+#[derive(Debug)]
+pub struct PhysicalMemoryLayout {
+    regions: [PhysicalRegion; PHYSICAL_REGION_MAX],
+    len: usize,
+}
+
+static MEMORY_LAYOUT: OnceCell<PhysicalMemoryLayout> = OnceCell::new();
+
+// ローダーが引数を置いていた物理アドレス
+static BOOT_ARGS_ADDRESS: OnceCell<u64> = OnceCell::new();
+
+pub fn set_boot_args_address(addr: u64) {
+    BOOT_ARGS_ADDRESS.set(addr).unwrap();
+}
+
+// メモリマップとローダーからの情報をまとめて、物理メモリの配置を決める
+pub fn initialize(args: &SikiOSArguments) -> &'static PhysicalMemoryLayout {
+    let mut layout = PhysicalMemoryLayout {
+        regions: [PhysicalRegion {
+            start: 0,
+            end: 0,
+            kind: PhysicalRegionKind::Reserved,
+        }; PHYSICAL_REGION_MAX],
+        len: 0,
+    };
+
+    for descriptor in args.memory_map.iter() {
+        let kind = match descriptor.memory_type {
+            MemoryType::CONVENTIONAL => PhysicalRegionKind::Usable,
+            MemoryType::LOADER_CODE
+            | MemoryType::LOADER_DATA
+            | MemoryType::BOOT_SERVICES_CODE
+            | MemoryType::BOOT_SERVICES_DATA => PhysicalRegionKind::BootServices,
+            MemoryType::ACPI_RECLAIM | MemoryType::ACPI_NON_VOLATILE => PhysicalRegionKind::Acpi,
+            _ => PhysicalRegionKind::Reserved,
+        };
+        layout.insert(descriptor.physical_start, descriptor.physical_end(), kind);
+    }
+
+    // メモリマップと重なっていても、こちらを優先する
+    let fb_start = args.frame_buffer_info.fb as u64;
+    layout.insert(
+        fb_start,
+        fb_start + args.frame_buffer_info.size as u64,
+        PhysicalRegionKind::FrameBuffer,
+    );
+
+    layout.insert(
+        args.kernel_image.physical_start,
+        args.kernel_image.physical_start + args.kernel_image.number_of_pages * PAGE_SIZE,
+        PhysicalRegionKind::KernelImage,
+    );
+
+    if let Some(&boot_args) = BOOT_ARGS_ADDRESS.get() {
+        layout.insert(
+            boot_args,
+            boot_args + size_of::<SikiOSArguments>() as u64,
+            PhysicalRegionKind::BootArgs,
+        );
+    }
+
+    layout.sort_and_merge();
+
+    MEMORY_LAYOUT.set(layout).unwrap();
+    MEMORY_LAYOUT.get().unwrap()
+}
+
+impl PhysicalMemoryLayout {
+    pub fn regions(&self) -> &[PhysicalRegion] {
+        &self.regions[..self.len]
+    }
+
+    // 範囲の一部でもアロケータに渡してはいけない領域に重なっているか
+    pub fn is_reserved(&self, start: u64, end: u64) -> bool {
+        let mut addr = start;
+        for region in self.regions() {
+            if region.end <= addr || end <= region.start {
+                continue;
+            }
+            if region.start > addr
+                || !matches!(
+                    region.kind,
+                    PhysicalRegionKind::Usable | PhysicalRegionKind::BootServices
+                )
+            {
+                return true;
+            }
+            addr = region.end;
+        }
+        addr < end
+    }
+
+    // ページ境界に広げた範囲を追加し、重なっている既存の領域から取り除く
+    fn insert(&mut self, start: u64, end: u64, kind: PhysicalRegionKind) {
+        let start = start / PAGE_SIZE * PAGE_SIZE;
+        let end = end.next_multiple_of(PAGE_SIZE);
+        if start >= end {
+            return;
+        }
+
+        let mut i = 0;
+        while i < self.len {
+            let region = self.regions[i];
+            if region.end <= start || end <= region.start {
+                i += 1;
+                continue;
+            }
+
+            // 重なった部分を除き、前後に残った部分だけを残す
+            self.len -= 1;
+            self.regions[i] = self.regions[self.len];
+            if region.start < start {
+                self.push(region.start, start, region.kind);
+            }
+            if end < region.end {
+                self.push(end, region.end, region.kind);
+            }
+        }
+
+        self.push(start, end, kind);
+    }
+
+    fn push(&mut self, start: u64, end: u64, kind: PhysicalRegionKind) {
+        if self.len == PHYSICAL_REGION_MAX {
+            panic!("Too many physical memory regions");
+        }
+        self.regions[self.len] = PhysicalRegion {
+            start: start,
+            end: end,
+            kind: kind,
+        };
+        self.len += 1;
+    }
+
+    fn sort_and_merge(&mut self) {
+        self.regions[..self.len].sort_unstable_by_key(|region| region.start);
+
+        let mut merged = 0;
+        for i in 0..self.len {
+            let region = self.regions[i];
+            if merged > 0 {
+                let last = &mut self.regions[merged - 1];
+                if last.end == region.start && last.kind == region.kind {
+                    last.end = region.end;
+                    continue;
+                }
+            }
+            self.regions[merged] = region;
+            merged += 1;
+        }
+        self.len = merged;
+    }
+
+    pub fn print_summary(&self) {
+        print_serial("physical memory layout:\n");
+        for region in self.regions() {
+            let mut buf = [0u8; 128];
+            let _s: &str = write_to::show(
+                &mut buf,
+                format_args!(
+                    "  {:016x}-{:016x} {:?} ({}KiB)\n",
+                    region.start,
+                    region.end,
+                    region.kind,
+                    region.pages() * PAGE_SIZE / 1024
+                ),
+            )
+            .unwrap();
+            print_serial(_s);
+        }
+
+        for kind in [
+            PhysicalRegionKind::Usable,
+            PhysicalRegionKind::BootServices,
+            PhysicalRegionKind::Reserved,
+            PhysicalRegionKind::FrameBuffer,
+            PhysicalRegionKind::Acpi,
+            PhysicalRegionKind::KernelImage,
+            PhysicalRegionKind::BootArgs,
+        ] {
+            let pages: u64 = self
+                .regions()
+                .iter()
+                .filter(|region| region.kind == kind)
+                .map(|region| region.pages())
+                .sum();
+
+            let mut buf = [0u8; 128];
+            let _s: &str = write_to::show(
+                &mut buf,
+                format_args!("  total {:?}: {}KiB\n", kind, pages * PAGE_SIZE / 1024),
+            )
+            .unwrap();
+            print_serial(_s);
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use core::ptr;
+
+use lib::CommandLine;
+
+use crate::allocator::ALLOC;
+use crate::memory_layout::PhysicalMemoryLayout;
+use crate::paging::PAGE_SIZE;
+use crate::print_serial;
+use crate::tsc;
+use crate::write::write_to;
+
+// 一度に確保して検査するフレーム数 (確保できなければ1フレームずつにする)
+const CHUNK_FRAMES: usize = 256;
+
+const DEFAULT_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+// シリアルに出力するエラーの最大数 (それ以降は数えるだけ)
+const MAX_REPORTED_FAILURES: u64 = 32;
+
+#[derive(Debug, Copy, Clone)]
+pub struct MemtestOptions {
+    pub seed: u64,
+    // 検査が終わったら起動を続けるか (falseなら停止する)
+    pub continue_boot: bool,
+}
+
+impl MemtestOptions {
+    // コマンドラインでmemtestが指定されていなければNone
+    pub fn from_command_line(command_line: &CommandLine) -> Option<Self> {
+        if !command_line.contains("memtest") {
+            return None;
+        }
+
+        Some(MemtestOptions {
+            seed: command_line
+                .get("memtest.seed")
+                .and_then(|seed| seed.parse().ok())
+                .unwrap_or(DEFAULT_SEED),
+            continue_boot: command_line.get("memtest") == Some("continue"),
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Pattern {
+    WalkingOnes,
+    AddressInAddress,
+    Random,
+}
+
+const PATTERNS: [Pattern; 3] = [
+    Pattern::WalkingOnes,
+    Pattern::AddressInAddress,
+    Pattern::Random,
+];
+
+#[derive(Debug, Copy, Clone, Default)]
+struct PatternStatistics {
+    // 書き込みと読み出しのバイト数の合計
+    bytes: u64,
+    ticks: u64,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct MemtestResult {
+    pub tested_frames: u64,
+    pub failures: u64,
+    // アロケータが予約済みの領域を返した回数
+    pub reserved_allocations: u64,
+}
+
+struct Xorshift64 {
+    state: u64,
+}
+
+impl Xorshift64 {
+    fn new(seed: u64) -> Self {
+        Xorshift64 {
+            state: if seed == 0 { DEFAULT_SEED } else { seed },
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
+
+// 確保済みのチャンクは先頭に次のチャンクへのリンクを書いてつなぐ (検査中はヒープを使わない)
+#[repr(C)]
+struct ChunkHeader {
+    next: u64,
+    frames: u64,
+}
+
+// アロケータから空きフレームをすべて確保して検査し、最後にまとめて返す
+pub fn run(options: &MemtestOptions, layout: &PhysicalMemoryLayout) -> MemtestResult {
+    let mut buf = [0u8; 128];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "memtest: testing {}MiB of free memory, seed: {}\n",
+            ALLOC.statistics().free_frames as u64 * PAGE_SIZE / 1024 / 1024,
+            options.seed
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+
+    let mut result = MemtestResult::default();
+    let mut statistics = [PatternStatistics::default(); PATTERNS.len()];
+    let mut chunks: u64 = 0;
+
+    loop {
+        let (addr, frames) = match ALLOC.allocate_frames(CHUNK_FRAMES) {
+            Ok(addr) => (addr as u64, CHUNK_FRAMES as u64),
+            Err(_) => match ALLOC.allocate_frames(1) {
+                Ok(addr) => (addr as u64, 1),
+                Err(_) => break,
+            },
+        };
+        let len = frames * PAGE_SIZE;
+
+        // 予約済みの領域は書き換えられないので、検査せずに確保したままにする
+        if layout.is_reserved(addr, addr + len) {
+            let mut buf = [0u8; 128];
+            let _s: &str = write_to::show(
+                &mut buf,
+                format_args!(
+                    "memtest: allocator returned reserved range {:016x}-{:016x}\n",
+                    addr,
+                    addr + len
+                ),
+            )
+            .unwrap();
+            print_serial(_s);
+            result.reserved_allocations += 1;
+            continue;
+        }
+
+        for (i, pattern) in PATTERNS.iter().enumerate() {
+            let start = tsc::read();
+            result.failures += test_range(*pattern, addr, len, options.seed, result.failures);
+            statistics[i].ticks += tsc::read() - start;
+            statistics[i].bytes += len * 2;
+        }
+        result.tested_frames += frames;
+
+        unsafe {
+            ptr::write_volatile(
+                addr as *mut ChunkHeader,
+                ChunkHeader {
+                    next: chunks,
+                    frames: frames,
+                },
+            );
+        }
+        chunks = addr;
+    }
+
+    while chunks != 0 {
+        let header = unsafe { ptr::read_volatile(chunks as *const ChunkHeader) };
+        ALLOC.deallocate_frames(chunks as usize, header.frames as usize);
+        chunks = header.next;
+    }
+
+    for (i, pattern) in PATTERNS.iter().enumerate() {
+        let ns = tsc::ticks_to_ns(statistics[i].ticks).max(1);
+        let mut buf = [0u8; 128];
+        let _s: &str = write_to::show(
+            &mut buf,
+            format_args!(
+                "memtest: {:?}: {}MiB/s\n",
+                pattern,
+                statistics[i].bytes * 1_000_000_000 / ns / 1024 / 1024
+            ),
+        )
+        .unwrap();
+        print_serial(_s);
+    }
+
+    let mut buf = [0u8; 128];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "memtest: tested {}MiB, {} failures, {} reserved allocations\n",
+            result.tested_frames * PAGE_SIZE / 1024 / 1024,
+            result.failures,
+            result.reserved_allocations
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+
+    result
+}
+
+// 範囲全体にパターンを書き込んでから読み出して確かめ、食い違った数を返す
+fn test_range(pattern: Pattern, addr: u64, len: u64, seed: u64, reported: u64) -> u64 {
+    let words = (len / 8) as usize;
+    let base = addr as *mut u64;
+
+    let mut random = Xorshift64::new(seed ^ addr);
+    for i in 0..words {
+        let value = expected_value(pattern, addr, i, &mut random);
+        unsafe { ptr::write_volatile(base.add(i), value) };
+    }
+
+    let mut failures = 0;
+    let mut random = Xorshift64::new(seed ^ addr);
+    for i in 0..words {
+        let expected = expected_value(pattern, addr, i, &mut random);
+        let actual = unsafe { ptr::read_volatile(base.add(i)) };
+        if actual != expected {
+            if reported + failures < MAX_REPORTED_FAILURES {
+                report_failure(pattern, addr + i as u64 * 8, expected, actual);
+            }
+            failures += 1;
+        }
+    }
+
+    failures
+}
+
+fn expected_value(pattern: Pattern, addr: u64, index: usize, random: &mut Xorshift64) -> u64 {
+    match pattern {
+        // 隣り合うワードで1のビットをずらしていく
+        Pattern::WalkingOnes => 1 << (index % 64),
+        Pattern::AddressInAddress => addr + index as u64 * 8,
+        Pattern::Random => random.next(),
+    }
+}
+
+fn report_failure(pattern: Pattern, addr: u64, expected: u64, actual: u64) {
+    let mut buf = [0u8; 160];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "memtest: {:?} failed at {:016x}, expected: {:016x}, actual: {:016x}\n",
+            pattern, addr, expected, actual
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+}
@@ -0,0 +1,100 @@
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::paging::{self, CacheType, PagingError, MMIO_END, MMIO_START, PAGE_SIZE};
+
+static NEXT_MMIO_ADDRESS: AtomicU64 = AtomicU64::new(MMIO_START);
+
+// デバイスのレジスタを仮想アドレスにマップした領域
+#[derive(Debug)]
+pub struct MmioRegion {
+    virtual_address: VirtAddr,
+    physical_address: PhysAddr,
+    len: usize,
+}
+
+impl MmioRegion {
+    pub fn virtual_address(&self) -> VirtAddr {
+        self.virtual_address
+    }
+
+    pub fn physical_address(&self) -> PhysAddr {
+        self.physical_address
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { read_volatile(self.register_ptr::<T>(offset)) }
+    }
+
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { write_volatile(self.register_ptr::<T>(offset), value) }
+    }
+
+    fn register_ptr<T>(&self, offset: usize) -> *mut T {
+        if offset + core::mem::size_of::<T>() > self.len {
+            panic!("MMIO access is out of range");
+        }
+        if offset % core::mem::align_of::<T>() != 0 {
+            panic!("MMIO access is not aligned");
+        }
+        (self.virtual_address + offset as u64).as_mut_ptr()
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        let start = self.virtual_address.align_down(PAGE_SIZE);
+        let end = (self.virtual_address + self.len as u64).align_up(PAGE_SIZE);
+        let mut addr = start;
+        while addr < end {
+            paging::unmap(Page::containing_address(addr)).unwrap();
+            addr += PAGE_SIZE;
+        }
+    }
+}
+
+// MMIO領域をキャッシュ無効でマップする
+pub fn map_mmio(physical_address: u64, len: usize) -> Result<MmioRegion, PagingError> {
+    map_mmio_with_cache(physical_address, len, CacheType::Uncacheable)
+}
+
+pub fn map_mmio_with_cache(
+    physical_address: u64,
+    len: usize,
+    cache_type: CacheType,
+) -> Result<MmioRegion, PagingError> {
+    let physical_address = PhysAddr::new(physical_address);
+    let physical_start = physical_address.align_down(PAGE_SIZE);
+    let offset = physical_address - physical_start;
+    let size = (offset + len as u64).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+
+    let virtual_start = NEXT_MMIO_ADDRESS.fetch_add(size, Ordering::Relaxed);
+    if virtual_start + size > MMIO_END {
+        return Err(PagingError::VirtualAddressExhausted);
+    }
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | cache_type.page_table_flags();
+    for i in 0..size / PAGE_SIZE {
+        paging::map(
+            Page::containing_address(VirtAddr::new(virtual_start + i * PAGE_SIZE)),
+            PhysFrame::containing_address(physical_start + i * PAGE_SIZE),
+            flags,
+        )?;
+    }
+
+    Ok(MmioRegion {
+        virtual_address: VirtAddr::new(virtual_start + offset),
+        physical_address: physical_address,
+        len: len,
+    })
+}
@@ -0,0 +1,502 @@
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use lib::{KernelImageInfo, MemoryType, SikiOSArguments, SEGMENT_FLAG_EXECUTE, SEGMENT_FLAG_WRITE};
+use x86_64::instructions::tlb;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr3Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags, Msr};
+use x86_64::structures::paging::mapper::{
+    FlagUpdateError, MapToError, TranslateResult, UnmapError,
+};
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame,
+    Size1GiB, Size2MiB, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::allocator::ALLOC;
+use crate::print_serial;
+use crate::write::write_to;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+// 物理メモリはストレートマップする (仮想アドレス = 物理アドレス)
+const PHYSICAL_MEMORY_OFFSET: u64 = 0;
+
+// 上位アドレスはカーネルが仮想アドレスだけ予約して使う領域
+pub const KERNEL_VMA_START: u64 = 0xffff_8000_0000_0000;
+pub const KERNEL_VMA_END: u64 = 0xffff_c000_0000_0000;
+
+// MMIOをマップする領域
+pub const MMIO_START: u64 = 0xffff_c000_0000_0000;
+pub const MMIO_END: u64 = 0xffff_d000_0000_0000;
+
+// スワップアウトしたページのエントリは存在しないページとし、アドレスの部分にスロット番号を入れる
+const SWAP_ENTRY_FLAG: PageTableFlags = PageTableFlags::BIT_9;
+
+const PML4_ADDRESS_LIMIT: usize = 0x1_0000_0000;
+
+const IA32_PAT: u32 = 0x277;
+
+// デフォルトのPATのうちPA1をWrite-ThroughからWrite-Combiningに変更する
+// PA0: WB, PA1: WC, PA2: UC-, PA3: UC (PA4-7も同じ)
+const PAT_VALUE: u64 = 0x0007_0106_0007_0106;
+
+static PAGE_TABLE: Mutex<RefCell<Option<OffsetPageTable<'static>>>> =
+    Mutex::new(RefCell::new(None));
+
+#[derive(Debug)]
+pub enum PagingError {
+    NotInitialized,
+    FrameAllocationFailed,
+    PageAlreadyMapped,
+    PageNotMapped,
+    ParentEntryHugePage,
+    InvalidFrameAddress,
+    VirtualAddressExhausted,
+}
+
+impl<S: PageSize> From<MapToError<S>> for PagingError {
+    fn from(value: MapToError<S>) -> Self {
+        match value {
+            MapToError::FrameAllocationFailed => PagingError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => PagingError::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped(_) => PagingError::PageAlreadyMapped,
+        }
+    }
+}
+
+impl From<UnmapError> for PagingError {
+    fn from(value: UnmapError) -> Self {
+        match value {
+            UnmapError::ParentEntryHugePage => PagingError::ParentEntryHugePage,
+            UnmapError::PageNotMapped => PagingError::PageNotMapped,
+            UnmapError::InvalidFrameAddress(_) => PagingError::InvalidFrameAddress,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CacheType {
+    WriteBack,
+    WriteCombining,
+    Uncacheable,
+}
+
+impl CacheType {
+    // PATのエントリを選択するためのPWT, PCDビット
+    pub fn page_table_flags(self) -> PageTableFlags {
+        match self {
+            CacheType::WriteBack => PageTableFlags::empty(),
+            CacheType::WriteCombining => PageTableFlags::WRITE_THROUGH,
+            CacheType::Uncacheable => PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE,
+        }
+    }
+}
+
+// ストレートマップに使ったページサイズごとの数
+#[derive(Debug, Copy, Clone, Default)]
+struct IdentityMapStatistics {
+    pages_1gib: u64,
+    pages_2mib: u64,
+    pages_4kib: u64,
+}
+
+// ページテーブル用のフレームをカーネルのアロケータから確保する
+pub struct KernelFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for KernelFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let addr = ALLOC.allocate_frames(1).ok()?;
+        Some(PhysFrame::containing_address(PhysAddr::new(addr as u64)))
+    }
+}
+
+// カーネル用のPML4を作成して切り替える
+pub fn initialize(args: &SikiOSArguments) {
+    // APは32ビットモードでCR3を読み込むので、PML4は4GiB未満に置く
+    let pml4_addr = ALLOC
+        .allocate_frames_with_constraints(1, PAGE_SIZE as usize, PML4_ADDRESS_LIMIT, 0)
+        .unwrap() as u64;
+    let pml4 = unsafe { &mut *((pml4_addr + PHYSICAL_MEMORY_OFFSET) as *mut PageTable) };
+    pml4.zero();
+
+    let mut page_table =
+        unsafe { OffsetPageTable::new(pml4, VirtAddr::new(PHYSICAL_MEMORY_OFFSET)) };
+    let mut statistics = IdentityMapStatistics::default();
+    let huge_1gib = is_1gib_page_supported();
+
+    let kernel_start = args.kernel_image.physical_start;
+    let kernel_end = kernel_start + args.kernel_image.number_of_pages * PAGE_SIZE;
+
+    // カーネルイメージはセグメントの属性に従ってページごとにマップする (W^X)
+    let mut addr = kernel_start;
+    while addr < kernel_end {
+        identity_map(
+            &mut page_table,
+            &mut statistics,
+            false,
+            addr,
+            addr + PAGE_SIZE,
+            kernel_page_flags(&args.kernel_image, addr),
+        );
+        addr += PAGE_SIZE;
+    }
+
+    // フレームバッファは書き込みだけなのでWrite-Combiningにする
+    let fb_start = args.frame_buffer_info.fb as u64;
+    identity_map(
+        &mut page_table,
+        &mut statistics,
+        huge_1gib,
+        fb_start,
+        fb_start + args.frame_buffer_info.size as u64,
+        PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_EXECUTE
+            | CacheType::WriteCombining.page_table_flags(),
+    );
+
+    // 物理メモリ (MMIO領域は使うときに個別にマップする)
+    // 大きなページを使えるように、隣接するディスクリプタはまとめてマップする
+    let mut run_start = 0;
+    let mut run_end = 0;
+    for descriptor in args.memory_map.iter() {
+        match descriptor.memory_type {
+            MemoryType::RESERVED
+            | MemoryType::UNUSABLE
+            | MemoryType::MMIO
+            | MemoryType::MMIO_PORT_SPACE
+            | MemoryType::PAL_CODE
+            | MemoryType::Other(_) => continue,
+            _ => {}
+        }
+
+        if descriptor.physical_start == run_end {
+            run_end = descriptor.physical_end();
+            continue;
+        }
+        identity_map_memory(
+            &mut page_table,
+            &mut statistics,
+            huge_1gib,
+            run_start,
+            run_end,
+            kernel_start,
+            kernel_end,
+        );
+        run_start = descriptor.physical_start;
+        run_end = descriptor.physical_end();
+    }
+    identity_map_memory(
+        &mut page_table,
+        &mut statistics,
+        huge_1gib,
+        run_start,
+        run_end,
+        kernel_start,
+        kernel_end,
+    );
+
+    let mut buf = [0u8; 128];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "paging: identity mapped {} 1GiB, {} 2MiB, {} 4KiB pages\n",
+            statistics.pages_1gib, statistics.pages_2mib, statistics.pages_4kib
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+
+    initialize_cpu();
+
+    unsafe {
+        Cr3::write(
+            PhysFrame::containing_address(PhysAddr::new(pml4_addr)),
+            Cr3Flags::empty(),
+        );
+    }
+
+    critical_section::with(|cs| {
+        PAGE_TABLE.borrow_ref_mut(cs).replace(page_table);
+    });
+}
+
+// CPUID.80000001h:EDX[26] 1GiBページ
+fn is_1gib_page_supported() -> bool {
+    if unsafe { __cpuid(0x8000_0000) }.eax < 0x8000_0001 {
+        return false;
+    }
+    unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0
+}
+
+// ページテーブルが前提にするCPUごとの設定 (PAT、NXE、WP)
+// ページテーブルはすべてのCPUで共有するので、APも起動したらすぐに呼ぶ
+pub fn initialize_cpu() {
+    initialize_pat();
+
+    unsafe {
+        // NXビットを有効にし、カーネルモードでも書き込み禁止ページへの書き込みでフォールトさせる
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+}
+
+fn initialize_pat() {
+    // CPUID.01h:EDX[16] PAT
+    if unsafe { __cpuid(0x1) }.edx & (1 << 16) == 0 {
+        panic!("PAT is not supported");
+    }
+
+    unsafe {
+        Msr::new(IA32_PAT).write(PAT_VALUE);
+        // キャッシュ属性を変更したのでキャッシュを書き戻して無効化する
+        asm!("wbinvd", options(nostack));
+    }
+}
+
+fn kernel_page_flags(kernel_image: &KernelImageInfo, addr: u64) -> PageTableFlags {
+    // 1つのページに複数のセグメントがかかる場合は属性の和をとる
+    // (リンカーでセグメントをページ境界に揃えているので、通常は1つだけ)
+    let mut segment_flags = 0;
+    for segment in kernel_image.segments[..kernel_image.segments_len].iter() {
+        if addr < segment.virtual_start + segment.memory_size
+            && segment.virtual_start < addr + PAGE_SIZE
+        {
+            segment_flags |= segment.flags;
+        }
+    }
+
+    let mut flags = PageTableFlags::PRESENT;
+    if segment_flags & SEGMENT_FLAG_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if segment_flags & SEGMENT_FLAG_EXECUTE == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    // 書き込みも実行もできるページは作らない
+    if segment_flags & SEGMENT_FLAG_WRITE != 0 && segment_flags & SEGMENT_FLAG_EXECUTE != 0 {
+        panic!("kernel page {:x} would be writable and executable", addr);
+    }
+
+    flags
+}
+
+fn identity_map_memory(
+    page_table: &mut OffsetPageTable,
+    statistics: &mut IdentityMapStatistics,
+    huge_1gib: bool,
+    start: u64,
+    end: u64,
+    kernel_start: u64,
+    kernel_end: u64,
+) {
+    // NULLポインタの参照を検出できるように0番地のページはマップしない
+    let start = start.max(PAGE_SIZE);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    // カーネルイメージと重なる部分は除く
+    identity_map(
+        page_table,
+        statistics,
+        huge_1gib,
+        start,
+        end.min(kernel_start),
+        flags,
+    );
+    identity_map(
+        page_table,
+        statistics,
+        huge_1gib,
+        start.max(kernel_end),
+        end,
+        flags,
+    );
+}
+
+// アラインメントが許す限り1GiB, 2MiBのページを使い、残りを4KiBのページでマップする
+fn identity_map(
+    page_table: &mut OffsetPageTable,
+    statistics: &mut IdentityMapStatistics,
+    huge_1gib: bool,
+    start: u64,
+    end: u64,
+    flags: PageTableFlags,
+) {
+    let start = start / PAGE_SIZE * PAGE_SIZE;
+    let mut addr = start;
+    while addr < end {
+        if huge_1gib && identity_map_page::<Size1GiB>(page_table, addr, end, flags) {
+            statistics.pages_1gib += 1;
+            addr += Size1GiB::SIZE;
+        } else if identity_map_page::<Size2MiB>(page_table, addr, end, flags) {
+            statistics.pages_2mib += 1;
+            addr += Size2MiB::SIZE;
+        } else {
+            if identity_map_page::<Size4KiB>(page_table, addr, end, flags) {
+                statistics.pages_4kib += 1;
+            }
+            addr += PAGE_SIZE;
+        }
+    }
+}
+
+// addrからページ1つ分をマップできればtrueを返す
+// (アラインされていない, 範囲に収まらない, すでに一部がマップされているときはfalse)
+fn identity_map_page<S: PageSize>(
+    page_table: &mut OffsetPageTable,
+    addr: u64,
+    end: u64,
+    flags: PageTableFlags,
+) -> bool
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    if addr % S::SIZE != 0 || end - addr < S::SIZE {
+        return false;
+    }
+
+    let frame = PhysFrame::<S>::containing_address(PhysAddr::new(addr));
+    let page = Page::<S>::containing_address(VirtAddr::new(addr));
+    // まだ有効なページテーブルではないのでTLBのフラッシュは不要
+    match unsafe { page_table.map_to(page, frame, flags, &mut KernelFrameAllocator) } {
+        Ok(flush) => {
+            flush.ignore();
+            true
+        }
+        Err(MapToError::PageAlreadyMapped(_)) | Err(MapToError::ParentEntryHugePage) => false,
+        Err(MapToError::FrameAllocationFailed) => panic!("Failed to map kernel page table"),
+    }
+}
+
+pub fn map(page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), PagingError> {
+    critical_section::with(|cs| {
+        let mut page_table = PAGE_TABLE.borrow_ref_mut(cs);
+        let page_table = page_table.as_mut().ok_or(PagingError::NotInitialized)?;
+        unsafe { page_table.map_to(page, frame, flags, &mut KernelFrameAllocator)? }.flush();
+        Ok(())
+    })
+}
+
+pub fn unmap(page: Page) -> Result<PhysFrame, PagingError> {
+    critical_section::with(|cs| {
+        let mut page_table = PAGE_TABLE.borrow_ref_mut(cs);
+        let page_table = page_table.as_mut().ok_or(PagingError::NotInitialized)?;
+        let (frame, flush) = page_table.unmap(page)?;
+        flush.flush();
+        Ok(frame)
+    })
+}
+
+// マップ済みのページの属性を変える
+pub fn update_flags(page: Page, flags: PageTableFlags) -> Result<(), PagingError> {
+    critical_section::with(|cs| {
+        let mut page_table = PAGE_TABLE.borrow_ref_mut(cs);
+        let page_table = page_table.as_mut().ok_or(PagingError::NotInitialized)?;
+        unsafe { page_table.update_flags(page, flags) }
+            .map_err(|error| match error {
+                FlagUpdateError::PageNotMapped => PagingError::PageNotMapped,
+                FlagUpdateError::ParentEntryHugePage => PagingError::ParentEntryHugePage,
+            })?
+            .flush();
+        Ok(())
+    })
+}
+
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    critical_section::with(|cs| {
+        let page_table = PAGE_TABLE.borrow_ref(cs);
+        match page_table.as_ref()?.translate(addr) {
+            TranslateResult::Mapped { frame, offset, .. } => Some(frame.start_address() + offset),
+            _ => None,
+        }
+    })
+}
+
+// 4KiBページのエントリを返す (途中のテーブルがなければNone)
+fn level_1_entry<'a>(
+    page_table: &'a mut OffsetPageTable<'static>,
+    page: Page,
+) -> Option<&'a mut PageTableEntry> {
+    let mut table: &'a mut PageTable = page_table.level_4_table();
+    for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT)
+            || entry.flags().contains(PageTableFlags::HUGE_PAGE)
+        {
+            return None;
+        }
+        let next = (PHYSICAL_MEMORY_OFFSET + entry.addr().as_u64()) as *mut PageTable;
+        table = unsafe { &mut *next };
+    }
+    Some(&mut table[page.p1_index()])
+}
+
+// ページをアンマップし、エントリにスワップスロットの番号を残す
+pub fn swap_out(page: Page, slot: u64) -> Result<PhysFrame, PagingError> {
+    critical_section::with(|cs| {
+        let mut page_table = PAGE_TABLE.borrow_ref_mut(cs);
+        let page_table = page_table.as_mut().ok_or(PagingError::NotInitialized)?;
+        let (frame, flush) = page_table.unmap(page)?;
+        flush.flush();
+
+        let entry = level_1_entry(page_table, page).ok_or(PagingError::PageNotMapped)?;
+        entry.set_addr(PhysAddr::new(slot * PAGE_SIZE), SWAP_ENTRY_FLAG);
+        Ok(frame)
+    })
+}
+
+// スワップアウトされたページならスロット番号を返す
+pub fn swap_slot(page: Page) -> Option<u64> {
+    critical_section::with(|cs| {
+        let mut page_table = PAGE_TABLE.borrow_ref_mut(cs);
+        let entry = level_1_entry(page_table.as_mut()?, page)?;
+        let flags = entry.flags();
+        if flags.contains(PageTableFlags::PRESENT) || !flags.contains(SWAP_ENTRY_FLAG) {
+            return None;
+        }
+        Some(entry.addr().as_u64() / PAGE_SIZE)
+    })
+}
+
+// スワップインする前やページを捨てるときに、スロット番号を残したエントリを空にする
+pub fn clear_swap_entry(page: Page) {
+    critical_section::with(|cs| {
+        let mut page_table = PAGE_TABLE.borrow_ref_mut(cs);
+        if let Some(entry) = page_table
+            .as_mut()
+            .and_then(|page_table| level_1_entry(page_table, page))
+        {
+            if !entry.flags().contains(PageTableFlags::PRESENT)
+                && entry.flags().contains(SWAP_ENTRY_FLAG)
+            {
+                entry.set_unused();
+            }
+        }
+    })
+}
+
+// 前回からアクセスされたかを返し、アクセスビットを落とす
+pub fn test_and_clear_accessed(page: Page) -> Result<bool, PagingError> {
+    critical_section::with(|cs| {
+        let mut page_table = PAGE_TABLE.borrow_ref_mut(cs);
+        let page_table = page_table.as_mut().ok_or(PagingError::NotInitialized)?;
+        let entry = level_1_entry(page_table, page).ok_or(PagingError::PageNotMapped)?;
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return Err(PagingError::PageNotMapped);
+        }
+        if !flags.contains(PageTableFlags::ACCESSED) {
+            return Ok(false);
+        }
+
+        entry.set_flags(flags - PageTableFlags::ACCESSED);
+        tlb::flush(page.start_address());
+        Ok(true)
+    })
+}
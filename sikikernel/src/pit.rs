@@ -0,0 +1,34 @@
+use x86_64::instructions::port::Port;
+
+// PITの入力クロック (Hz)
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+// 16ビットのカウンタで数えられる上限 (約54ms)
+const PIT_MAX_WAIT_MS: u64 = 0xffff * 1000 / PIT_FREQUENCY;
+
+const PIT_CHANNEL2_DATA: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+// ビット0: チャンネル2のゲート, ビット1: スピーカー, ビット5: チャンネル2の出力
+const PIT_CHANNEL2_CONTROL: u16 = 0x61;
+
+// PITのチャンネル2でmsミリ秒待つ (他のタイマーの校正用)
+pub fn wait(ms: u64) {
+    let count = PIT_FREQUENCY * ms.min(PIT_MAX_WAIT_MS) / 1000;
+
+    let mut control = Port::<u8>::new(PIT_CHANNEL2_CONTROL);
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut data = Port::<u8>::new(PIT_CHANNEL2_DATA);
+
+    unsafe {
+        // ゲートを開けてスピーカーは止める
+        let value = control.read();
+        control.write((value & !0x02) | 0x01);
+
+        // チャンネル2, 下位・上位バイトの順に書き込み, モード0 (カウント終了で出力がHigh)
+        command.write(0b1011_0000);
+        data.write((count & 0xff) as u8);
+        data.write((count >> 8) as u8);
+
+        while control.read() & 0x20 == 0 {}
+    }
+}
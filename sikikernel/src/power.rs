@@ -0,0 +1,343 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use once_cell::sync::OnceCell;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::instructions::tables::lidt;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+use crate::acpi::fadt::{Fadt, FADT_FLAG_POWER_BUTTON};
+use crate::acpi::{self, aml, GenericAddress};
+use crate::apic;
+use crate::interrupts::InterruptFrame;
+use crate::mmio;
+use crate::pit;
+use crate::print_serial;
+use crate::timer;
+use crate::write::write_to;
+
+pub const SCI_VECTOR: u8 = 0x41;
+
+// PM1イベントレジスタ (前半がステータス、後半が有効化)
+const PM1_POWER_BUTTON: u16 = 1 << 8;
+// PM1制御レジスタ
+const PM1_SCI_ENABLE: u16 = 1 << 0;
+const PM1_SLEEP_TYPE_SHIFT: u16 = 10;
+const PM1_SLEEP_TYPE_MASK: u16 = 0b111 << PM1_SLEEP_TYPE_SHIFT;
+const PM1_SLEEP_ENABLE: u16 = 1 << 13;
+
+// ACPIモードへの切り替えを待つ時間
+const ACPI_ENABLE_TIMEOUT_MS: u64 = 3000;
+
+// 8042キーボードコントローラ
+const KEYBOARD_STATUS: u16 = 0x64;
+const KEYBOARD_COMMAND: u16 = 0x64;
+const KEYBOARD_INPUT_FULL: u8 = 1 << 1;
+const KEYBOARD_PULSE_RESET: u8 = 0xfe;
+
+// GASのPCIコンフィギュレーション空間 (バス0のデバイスとファンクションのみ)
+const ADDRESS_SPACE_PCI_CONFIG: u8 = 2;
+const PCI_CONFIG_ADDRESS: u16 = 0xcf8;
+const PCI_CONFIG_DATA: u16 = 0xcfc;
+
+// AMLのオペコード
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+
+struct Power {
+    fadt: Fadt,
+    // \_S5のSLP_TYPaとSLP_TYPb
+    s5: Option<(u8, u8)>,
+}
+
+static POWER: OnceCell<Power> = OnceCell::new();
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// FADTと\_S5を読み、ACPIモードにして電源ボタンのSCIを受け取れるようにする
+pub fn initialize() -> Result<(), &'static str> {
+    let fadt = Fadt::find()?;
+    // AMLを解釈できなければ、テーブルのバイト列から探す
+    let s5 = aml::sleep_type(5).or_else(find_s5);
+    POWER
+        .set(Power { fadt: fadt, s5: s5 })
+        .map_err(|_| "Power management is already initialized")?;
+
+    if fadt.is_hardware_reduced() {
+        return Err("Hardware-reduced ACPI has no PM1 registers");
+    }
+    if fadt.pm1a_event_block.address_space != GenericAddress::SYSTEM_IO
+        || fadt.pm1a_control_block.address_space != GenericAddress::SYSTEM_IO
+    {
+        return Err("PM1 registers are not in I/O space");
+    }
+
+    enable_acpi_mode(&fadt)?;
+
+    // 使わないGPEを止め、固定機能の電源ボタンだけをSCIにつなぐ
+    disable_gpes(&fadt);
+    for (event, _) in pm1_registers(&fadt) {
+        let half = fadt.pm1_event_length as u16 / 2;
+        write_port(event + half, 0);
+        write_port(event, u16::MAX);
+    }
+    apic::route_sci(fadt.sci_interrupt as u8, SCI_VECTOR, handle_sci)?;
+    if fadt.flags & FADT_FLAG_POWER_BUTTON == 0 {
+        for (event, _) in pm1_registers(&fadt) {
+            let half = fadt.pm1_event_length as u16 / 2;
+            write_port(event + half, PM1_POWER_BUTTON);
+        }
+    }
+
+    let mut buf = [0u8; 128];
+    let _s: &str = match s5 {
+        Some((a, b)) => write_to::show(
+            &mut buf,
+            format_args!(
+                "power: SCI IRQ {}, S5 SLP_TYP {}/{}, power button {}\n",
+                fadt.sci_interrupt,
+                a,
+                b,
+                if fadt.flags & FADT_FLAG_POWER_BUTTON == 0 {
+                    "fixed"
+                } else {
+                    "control method (unsupported)"
+                }
+            ),
+        ),
+        None => write_to::show(
+            &mut buf,
+            format_args!(
+                "power: SCI IRQ {}, \\_S5 is not found\n",
+                fadt.sci_interrupt
+            ),
+        ),
+    }
+    .unwrap();
+    print_serial(_s);
+
+    Ok(())
+}
+
+// 電源ボタンが押されてシャットダウンを待っているか
+pub fn is_shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::Relaxed)
+}
+
+// S5 (ソフトオフ) に入る
+pub fn shutdown() -> ! {
+    print_serial("power: shutting down\n");
+    interrupts::disable();
+
+    if let Some(power) = POWER.get() {
+        if let Some((a, b)) = power.s5 {
+            let mut registers = pm1_registers(&power.fadt);
+            if let Some((_, control)) = registers.next() {
+                enter_sleep_state(control, a);
+            }
+            if let Some((_, control)) = registers.next() {
+                enter_sleep_state(control, b);
+            }
+            pit::wait(50);
+        }
+    }
+
+    print_serial("power: shutdown failed, halting\n");
+    halt();
+}
+
+// FADTのリセットレジスタ、キーボードコントローラ、トリプルフォルトの順に試す
+pub fn reboot() -> ! {
+    print_serial("power: rebooting\n");
+    interrupts::disable();
+
+    if let Some(reset) = POWER.get().and_then(|power| {
+        power
+            .fadt
+            .reset_register
+            .map(|register| (register, power.fadt.reset_value))
+    }) {
+        write_reset_register(&reset.0, reset.1);
+        pit::wait(50);
+    }
+
+    unsafe {
+        let mut status: Port<u8> = Port::new(KEYBOARD_STATUS);
+        let mut command: Port<u8> = Port::new(KEYBOARD_COMMAND);
+        for _ in 0..0x10000 {
+            if status.read() & KEYBOARD_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        command.write(KEYBOARD_PULSE_RESET);
+    }
+    pit::wait(50);
+
+    // 空のIDTで例外を起こすとトリプルフォルトでリセットされる
+    unsafe {
+        lidt(&DescriptorTablePointer {
+            limit: 0,
+            base: VirtAddr::new(0),
+        });
+        asm!("int3");
+    }
+    halt();
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe { asm!("cli; hlt", options(nomem, nostack)) };
+    }
+}
+
+fn handle_sci(_frame: &mut InterruptFrame) {
+    let power = match POWER.get() {
+        Some(power) => power,
+        None => return,
+    };
+
+    // レベルトリガーなので、EOIの前にステータスを1を書いて消す
+    let mut pressed = false;
+    for (event, _) in pm1_registers(&power.fadt) {
+        if read_port(event) & PM1_POWER_BUTTON != 0 {
+            write_port(event, PM1_POWER_BUTTON);
+            pressed = true;
+        }
+    }
+
+    if pressed && !SHUTDOWN_REQUESTED.swap(true, Ordering::Relaxed) {
+        print_serial("power: power button pressed\n");
+    }
+}
+
+// SCI_ENが立っていなければ、SMIコマンドポートでファームウェアにACPIモードへの切り替えを頼む
+fn enable_acpi_mode(fadt: &Fadt) -> Result<(), &'static str> {
+    let control = fadt.pm1a_control_block.address as u16;
+    if read_port(control) & PM1_SCI_ENABLE != 0 {
+        return Ok(());
+    }
+    if fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+        return Err("ACPI mode cannot be enabled");
+    }
+
+    unsafe { Port::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable) };
+    for _ in 0..ACPI_ENABLE_TIMEOUT_MS {
+        if read_port(control) & PM1_SCI_ENABLE != 0 {
+            return Ok(());
+        }
+        timer::sleep(Duration::from_millis(1));
+    }
+    Err("Timed out enabling ACPI mode")
+}
+
+fn disable_gpes(fadt: &Fadt) {
+    if !fadt.gpe0_block.is_present() || fadt.gpe0_block.address_space != GenericAddress::SYSTEM_IO {
+        return;
+    }
+    let half = fadt.gpe0_block_length as u16 / 2;
+    let base = fadt.gpe0_block.address as u16;
+    for i in 0..half {
+        unsafe {
+            Port::<u8>::new(base + half + i).write(0);
+            Port::<u8>::new(base + i).write(u8::MAX);
+        }
+    }
+}
+
+// 存在するPM1a, PM1bの (イベントブロック, 制御ブロック) のポート
+fn pm1_registers(fadt: &Fadt) -> impl Iterator<Item = (u16, u16)> {
+    [
+        (fadt.pm1a_event_block, fadt.pm1a_control_block),
+        (fadt.pm1b_event_block, fadt.pm1b_control_block),
+    ]
+    .into_iter()
+    .filter(|(event, control)| event.is_present() && control.is_present())
+    .map(|(event, control)| (event.address as u16, control.address as u16))
+}
+
+fn enter_sleep_state(control: u16, sleep_type: u8) {
+    let value = read_port(control) & !(PM1_SLEEP_TYPE_MASK | PM1_SLEEP_ENABLE);
+    write_port(
+        control,
+        value | ((sleep_type as u16) << PM1_SLEEP_TYPE_SHIFT) | PM1_SLEEP_ENABLE,
+    );
+}
+
+fn read_port(port: u16) -> u16 {
+    unsafe { Port::<u16>::new(port).read() }
+}
+
+fn write_port(port: u16, value: u16) {
+    unsafe { Port::<u16>::new(port).write(value) }
+}
+
+fn write_reset_register(register: &GenericAddress, value: u8) {
+    match register.address_space {
+        GenericAddress::SYSTEM_IO => unsafe {
+            Port::<u8>::new(register.address as u16).write(value)
+        },
+        GenericAddress::SYSTEM_MEMORY => {
+            if let Ok(region) = mmio::map_mmio(register.address, 1) {
+                region.write::<u8>(0, value);
+            }
+        }
+        ADDRESS_SPACE_PCI_CONFIG => {
+            // アドレスはデバイス (ビット32-47), ファンクション (ビット16-31), オフセット (ビット0-15)
+            let device = ((register.address >> 32) & 0x1f) as u32;
+            let function = ((register.address >> 16) & 0x7) as u32;
+            let offset = (register.address & 0xff) as u32;
+            unsafe {
+                Port::<u32>::new(PCI_CONFIG_ADDRESS)
+                    .write((1 << 31) | (device << 11) | (function << 8) | (offset & 0xfc));
+                Port::<u8>::new(PCI_CONFIG_DATA + (offset & 3) as u16).write(value);
+            }
+        }
+        _ => {}
+    }
+}
+
+// DSDTとSSDTのAMLから「Name(_S5, Package(){a, b, ...})」を探す
+// (AMLを解釈せず、名前の後ろのパッケージの最初の2つの整数を読む。AMLの読み込みに失敗したとき用)
+fn find_s5() -> Option<(u8, u8)> {
+    acpi::find_tables(b"DSDT")
+        .chain(acpi::find_tables(b"SSDT"))
+        .find_map(|table| {
+            let aml = table.body();
+            aml.windows(4)
+                .enumerate()
+                .filter(|(_, name)| name == b"_S5_")
+                .find_map(|(offset, _)| parse_s5_package(&aml[offset + 4..]))
+        })
+}
+
+fn parse_s5_package(aml: &[u8]) -> Option<(u8, u8)> {
+    if *aml.first()? != AML_PACKAGE_OP {
+        return None;
+    }
+    // PkgLengthの先頭バイトの上位2ビットが後続のバイト数
+    let length_bytes = (*aml.get(1)? >> 6) as usize;
+    let mut offset = 2 + length_bytes;
+    // 要素数
+    offset += 1;
+
+    let mut next = || -> Option<u8> {
+        let value = match *aml.get(offset)? {
+            AML_ZERO_OP => 0,
+            AML_ONE_OP => 1,
+            AML_BYTE_PREFIX => {
+                offset += 1;
+                *aml.get(offset)?
+            }
+            _ => return None,
+        };
+        offset += 1;
+        Some(value)
+    };
+    let a = next()?;
+    let b = next()?;
+    Some((a, b))
+}
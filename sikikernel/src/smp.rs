@@ -0,0 +1,387 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::arch::{asm, global_asm};
+use core::ptr::{self, addr_of};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use once_cell::sync::OnceCell;
+use x86_64::registers::control::{Cr0, Cr3, Cr4};
+use x86_64::registers::model_specific::{Efer, GsBase};
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::acpi::madt::Madt;
+use crate::apic;
+use crate::gdt::{self, Gdt};
+use crate::interrupts;
+use crate::memory_layout::PhysicalMemoryLayout;
+use crate::paging::{self, PagingError, PAGE_SIZE};
+use crate::print_serial;
+use crate::stack::KernelStack;
+use crate::timer;
+use crate::write::write_to;
+
+// トランポリンを置く物理アドレス (1MiB未満はアロケータが使わない)
+// SIPIのベクタはこのページの番号
+const TRAMPOLINE_ADDRESS: u64 = 0x8000;
+
+const AP_STACK_SIZE: usize = 4096 * 16;
+
+// INIT-SIPI-SIPIの待ち時間 (Intel SDMのMultiple-Processor Initialization)
+const INIT_DELAY: Duration = Duration::from_millis(10);
+const STARTUP_DELAY: Duration = Duration::from_micros(200);
+const ONLINE_TIMEOUT: Duration = Duration::from_millis(100);
+
+// xAPICのICRで宛先に指定できるのは0xfeまで (0xffはブロードキャスト)
+const XAPIC_MAX_ID: u32 = 0xfe;
+
+// APはリアルモードで起動し、32ビットのプロテクトモードを経てロングモードに入る
+// このページがどこに置かれてもよいように、ページの物理アドレスをebxに入れて相対的に参照する
+global_asm!(
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_data",
+    ".global ap_trampoline_end",
+    ".code16",
+    "ap_trampoline_start:",
+    "    cli",
+    "    cld",
+    "    mov %cs, %ax",
+    "    mov %ax, %ds",
+    "    xor %ebx, %ebx",
+    "    mov %ax, %bx",
+    "    shl $4, %ebx",
+    // GDTRのベースとファージャンプの飛び先を、このページの物理アドレスに合わせる
+    "    lea (ap_gdt - ap_trampoline_start)(%ebx), %eax",
+    "    mov %eax, (ap_gdtr - ap_trampoline_start + 2)",
+    "    lea (ap_protected_mode - ap_trampoline_start)(%ebx), %eax",
+    "    mov %eax, (ap_far_pointer32 - ap_trampoline_start)",
+    "    lea (ap_long_mode - ap_trampoline_start)(%ebx), %eax",
+    "    mov %eax, (ap_far_pointer64 - ap_trampoline_start)",
+    "    lgdtl (ap_gdtr - ap_trampoline_start)",
+    "    mov %cr0, %eax",
+    "    or $1, %eax",
+    "    mov %eax, %cr0",
+    "    ljmpl *(ap_far_pointer32 - ap_trampoline_start)",
+    ".code32",
+    "ap_protected_mode:",
+    "    mov $0x10, %ax",
+    "    mov %ax, %ds",
+    "    mov %ax, %es",
+    "    mov %ax, %ss",
+    // PAEを有効にし、BSPと同じPML4を読み込む
+    "    mov %cr4, %eax",
+    "    or $(1 << 5), %eax",
+    "    mov %eax, %cr4",
+    "    mov (ap_trampoline_data - ap_trampoline_start)(%ebx), %eax",
+    "    mov %eax, %cr3",
+    // EFERはBSPの値 (LME, NXEなど) をそのまま使う (LMAは読み出し専用)
+    "    mov $0xc0000080, %ecx",
+    "    mov (ap_trampoline_data - ap_trampoline_start + 24)(%ebx), %eax",
+    "    and $~(1 << 10), %eax",
+    "    mov (ap_trampoline_data - ap_trampoline_start + 28)(%ebx), %edx",
+    "    wrmsr",
+    "    mov %cr0, %eax",
+    "    or $0x80000000, %eax",
+    "    mov %eax, %cr0",
+    "    ljmpl *(ap_far_pointer64 - ap_trampoline_start)(%ebx)",
+    ".code64",
+    "ap_long_mode:",
+    // CR0とCR4もBSPに揃えてから、カーネルのスタックでap_entryを呼ぶ
+    "    mov (ap_trampoline_data - ap_trampoline_start + 8)(%rbx), %rax",
+    "    mov %rax, %cr0",
+    "    mov (ap_trampoline_data - ap_trampoline_start + 16)(%rbx), %rax",
+    "    mov %rax, %cr4",
+    "    mov (ap_trampoline_data - ap_trampoline_start + 32)(%rbx), %rsp",
+    "    mov (ap_trampoline_data - ap_trampoline_start + 48)(%rbx), %rdi",
+    "    mov (ap_trampoline_data - ap_trampoline_start + 40)(%rbx), %rax",
+    "    xor %ebp, %ebp",
+    "    call *%rax",
+    "    ud2",
+    ".align 8",
+    // ヌル, 32ビットのコード, データ, 64ビットのコード
+    "ap_gdt:",
+    "    .quad 0",
+    "    .quad 0x00cf9a000000ffff",
+    "    .quad 0x00cf92000000ffff",
+    "    .quad 0x00af9a000000ffff",
+    "ap_gdtr:",
+    "    .word 4 * 8 - 1",
+    "    .long 0",
+    "ap_far_pointer32:",
+    "    .long 0",
+    "    .word 0x08",
+    "ap_far_pointer64:",
+    "    .long 0",
+    "    .word 0x18",
+    ".align 8",
+    // TrampolineDataと同じ並び
+    "ap_trampoline_data:",
+    "    .fill 7, 8, 0",
+    "ap_trampoline_end:",
+    options(att_syntax)
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+// トランポリンに渡す値 (アセンブリのオフセットと合わせること)
+#[repr(C)]
+struct TrampolineData {
+    cr3: u64,
+    cr0: u64,
+    cr4: u64,
+    efer: u64,
+    stack_top: u64,
+    entry: u64,
+    per_cpu: u64,
+}
+
+// CPUごとのデータ (GSのベースがこの構造体を指す)
+#[repr(C)]
+pub struct PerCpu {
+    // gs:0から自身のアドレスを読めるように先頭に置く
+    this: u64,
+    pub index: usize,
+    pub apic_id: u32,
+    gdt: &'static Gdt,
+    online: AtomicBool,
+}
+
+impl PerCpu {
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+}
+
+static CPUS: OnceCell<Vec<&'static PerCpu>> = OnceCell::new();
+
+// MADTに書かれたAPを1つずつ起動する
+pub fn initialize(memory_layout: &PhysicalMemoryLayout) -> Result<(), &'static str> {
+    let madt = Madt::find()?;
+    let bsp_id = apic::local_apic_id().ok_or("Local APIC is not initialized")?;
+
+    // BSPもGSからCPUごとのデータを引けるようにする
+    let bsp = new_per_cpu(0, bsp_id, gdt::bsp());
+    set_current(bsp);
+    bsp.online.store(true, Ordering::Release);
+
+    let mut cpus = Vec::new();
+    cpus.push(bsp);
+    for entry in madt.local_apics.iter() {
+        if !entry.enabled || entry.apic_id == bsp_id {
+            continue;
+        }
+        if !apic::is_x2apic() && entry.apic_id > XAPIC_MAX_ID {
+            continue;
+        }
+        let per_cpu = new_per_cpu(cpus.len(), entry.apic_id, gdt::allocate()?);
+        cpus.push(per_cpu);
+    }
+
+    if cpus.len() > 1 {
+        install_trampoline(memory_layout)?;
+        for per_cpu in cpus.iter().skip(1) {
+            if let Err(reason) = start_ap(per_cpu) {
+                let mut buf = [0u8; 128];
+                let _s: &str = write_to::show(
+                    &mut buf,
+                    format_args!(
+                        "smp: CPU {} (APIC ID {}): {}\n",
+                        per_cpu.index, per_cpu.apic_id, reason
+                    ),
+                )
+                .unwrap();
+                print_serial(_s);
+            }
+        }
+        remove_trampoline()?;
+    }
+
+    let online = cpus.iter().filter(|per_cpu| per_cpu.is_online()).count();
+    let mut buf = [0u8; 64];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!("smp: {} of {} CPUs online\n", online, cpus.len()),
+    )
+    .unwrap();
+    print_serial(_s);
+
+    CPUS.set(cpus).map_err(|_| "SMP is already initialized")
+}
+
+fn new_per_cpu(index: usize, apic_id: u32, gdt: &'static Gdt) -> &'static PerCpu {
+    let per_cpu = Box::leak(Box::new(PerCpu {
+        this: 0,
+        index: index,
+        apic_id: apic_id,
+        gdt: gdt,
+        online: AtomicBool::new(false),
+    }));
+    per_cpu.this = per_cpu as *const PerCpu as u64;
+    per_cpu
+}
+
+fn set_current(per_cpu: &'static PerCpu) {
+    GsBase::write(VirtAddr::new(per_cpu.this));
+}
+
+// 最初のクリティカルセクションより前に呼ぶ
+pub fn clear_current() {
+    GsBase::write(VirtAddr::zero());
+}
+
+// 今のCPUのデータ (smp::initializeの前はNone)
+pub fn current() -> Option<&'static PerCpu> {
+    if GsBase::read().as_u64() == 0 {
+        return None;
+    }
+    let this: u64;
+    unsafe { asm!("mov {}, gs:[0]", out(reg) this, options(nostack, readonly)) };
+    Some(unsafe { &*(this as *const PerCpu) })
+}
+
+// 今のCPUの番号 (BSPは0、smp::initializeの前も0)
+pub fn current_index() -> usize {
+    current().map_or(0, |per_cpu| per_cpu.index)
+}
+
+pub fn cpus() -> &'static [&'static PerCpu] {
+    CPUS.get().map_or(&[], |cpus| cpus.as_slice())
+}
+
+// トランポリンをコピーし、そのページを一時的に実行可能にする
+fn install_trampoline(memory_layout: &PhysicalMemoryLayout) -> Result<(), &'static str> {
+    let start = addr_of!(ap_trampoline_start) as u64;
+    let size = addr_of!(ap_trampoline_end) as u64 - start;
+    if size > PAGE_SIZE {
+        return Err("AP trampoline does not fit in a page");
+    }
+    if memory_layout.is_reserved(TRAMPOLINE_ADDRESS, TRAMPOLINE_ADDRESS + PAGE_SIZE) {
+        return Err("AP trampoline page is reserved by the firmware");
+    }
+
+    let page = Page::containing_address(VirtAddr::new(TRAMPOLINE_ADDRESS));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match paging::update_flags(page, flags) {
+        Ok(()) => {}
+        Err(PagingError::PageNotMapped) => paging::map(
+            page,
+            PhysFrame::containing_address(PhysAddr::new(TRAMPOLINE_ADDRESS)),
+            flags,
+        )
+        .map_err(|_| "Failed to map AP trampoline")?,
+        Err(_) => return Err("Failed to map AP trampoline"),
+    }
+
+    unsafe {
+        ptr::copy_nonoverlapping(
+            start as *const u8,
+            TRAMPOLINE_ADDRESS as *mut u8,
+            size as usize,
+        );
+    }
+    Ok(())
+}
+
+// APがすべて起動したら、トランポリンのページを実行できないように戻す
+fn remove_trampoline() -> Result<(), &'static str> {
+    let page = Page::containing_address(VirtAddr::new(TRAMPOLINE_ADDRESS));
+    paging::update_flags(
+        page,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )
+    .map_err(|_| "Failed to unmap AP trampoline")
+}
+
+fn start_ap(per_cpu: &'static PerCpu) -> Result<(), &'static str> {
+    // スタックはAPが確保できないのでここで用意する
+    // (APICへの送信に失敗したときはAPがスタックを使っていないとは言えないので、解放せずに残す)
+    let stack = KernelStack::new(AP_STACK_SIZE, "ap")?;
+
+    let offset = addr_of!(ap_trampoline_data) as u64 - addr_of!(ap_trampoline_start) as u64;
+    let data = (TRAMPOLINE_ADDRESS + offset) as *mut TrampolineData;
+    let (pml4, _) = Cr3::read();
+    unsafe {
+        ptr::write_volatile(
+            data,
+            TrampolineData {
+                cr3: pml4.start_address().as_u64(),
+                cr0: Cr0::read_raw(),
+                cr4: Cr4::read_raw(),
+                efer: Efer::read_raw(),
+                stack_top: stack.top().as_u64(),
+                entry: ap_entry as usize as u64,
+                per_cpu: per_cpu.this,
+            },
+        );
+    }
+
+    let page = (TRAMPOLINE_ADDRESS / PAGE_SIZE) as u8;
+    apic::send_init(per_cpu.apic_id)?;
+    timer::sleep(INIT_DELAY);
+    for _ in 0..2 {
+        apic::send_startup(per_cpu.apic_id, page)?;
+        timer::sleep(STARTUP_DELAY);
+        if per_cpu.is_online() {
+            return Ok(());
+        }
+    }
+
+    let deadline = timer::uptime() + ONLINE_TIMEOUT;
+    while timer::uptime() < deadline {
+        if per_cpu.is_online() {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+
+    // 遅れて起動したAPが、再利用したトランポリンのページを実行しないように
+    // INITでリセットしてSIPIを待つ状態に戻す
+    // リセットしたAPはもうスタックを使わないので解放する
+    apic::send_init(per_cpu.apic_id)?;
+    per_cpu.online.store(false, Ordering::Release);
+    stack.release()?;
+    Err("did not come online")
+}
+
+// トランポリンから呼ばれるAPのエントリポイント
+// メモリの確保はできないので、必要なものはBSPが用意したものを使う
+extern "sysv64" fn ap_entry(per_cpu: &'static PerCpu) -> ! {
+    // クリティカルセクションはGSでCPUを見分けるので、最初に設定する
+    set_current(per_cpu);
+    // BSPのページテーブルはPATのWCやNXビットを使っているので、同じ設定にする
+    paging::initialize_cpu();
+    gdt::load(per_cpu.gdt);
+    interrupts::initialize();
+
+    if let Err(reason) = apic::initialize_ap() {
+        let mut buf = [0u8; 128];
+        let _s: &str = write_to::show(
+            &mut buf,
+            format_args!("smp: CPU {}: {}\n", per_cpu.index, reason),
+        )
+        .unwrap();
+        print_serial(_s);
+    }
+
+    let mut buf = [0u8; 64];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "CPU {} online (APIC ID {})\n",
+            per_cpu.index, per_cpu.apic_id
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+    per_cpu.online.store(true, Ordering::Release);
+
+    // 仕事を割り当てる仕組みはまだないので、割り込みを止めて休ませる
+    loop {
+        unsafe { asm!("cli", "hlt", options(nomem, nostack)) };
+    }
+}
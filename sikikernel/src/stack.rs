@@ -0,0 +1,78 @@
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::paging::PAGE_SIZE;
+use crate::vma::{self, VirtualMemoryArea};
+
+// スタックの下にあるガードページと、そのスタックを使っているタスクの名前
+#[derive(Debug, Copy, Clone)]
+struct GuardPage {
+    start: VirtAddr,
+    name: &'static str,
+}
+
+static GUARD_PAGES: Mutex<RefCell<Vec<GuardPage>>> = Mutex::new(RefCell::new(Vec::new()));
+
+// 最下位にガードページを持つカーネルスタック
+// ドロップしても解放しない (使い終わったことが確かなときだけreleaseで解放する)
+#[derive(Debug)]
+pub struct KernelStack {
+    area: VirtualMemoryArea,
+}
+
+impl KernelStack {
+    pub fn new(size: usize, name: &'static str) -> Result<Self, &'static str> {
+        // スタックではページフォールトを処理できないので、先にフレームを割り当てておく
+        let area = vma::reserve(
+            size,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            name,
+        )?;
+        vma::populate(&area)?;
+
+        // vma::reserveは領域の手前にマップしないページを残している
+        register_guard_page(area.start - PAGE_SIZE, name);
+
+        Ok(KernelStack { area: area })
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.area.end
+    }
+
+    // ガードページの登録を外し、スタックの領域を解放する
+    pub fn release(self) -> Result<(), &'static str> {
+        let guard = self.area.start - PAGE_SIZE;
+        critical_section::with(|cs| {
+            GUARD_PAGES
+                .borrow_ref_mut(cs)
+                .retain(|guard_page| guard_page.start != guard)
+        });
+        vma::release(self.area.start)
+    }
+}
+
+pub fn register_guard_page(start: VirtAddr, name: &'static str) {
+    critical_section::with(|cs| {
+        GUARD_PAGES.borrow_ref_mut(cs).push(GuardPage {
+            start: start,
+            name: name,
+        })
+    });
+}
+
+// アドレスがガードページ内なら、そのスタックを使っているタスクの名前を返す
+pub fn find_guard_page(addr: VirtAddr) -> Option<&'static str> {
+    critical_section::with(|cs| {
+        // ダブルフォールト中に呼ばれるので、借用中でもパニックしないようにする
+        let guard_pages = GUARD_PAGES.borrow(cs).try_borrow().ok()?;
+        guard_pages
+            .iter()
+            .find(|guard| guard.start <= addr && addr < guard.start + PAGE_SIZE)
+            .map(|guard| guard.name)
+    })
+}
@@ -0,0 +1,391 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bitvec::vec::BitVec;
+use core::cell::RefCell;
+use core::{ptr, slice};
+
+use critical_section::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::allocator::ALLOC;
+use crate::drivers::block::ata::AtaPio;
+use crate::drivers::block::block::{BlockDevice, SECTOR_SIZE};
+use crate::paging::{self, PAGE_SIZE};
+use crate::print_serial;
+use crate::vma;
+use crate::write::write_to;
+
+const SECTORS_PER_PAGE: u64 = PAGE_SIZE / SECTOR_SIZE as u64;
+
+// フレームが足りないときに一度に追い出すページ数
+const EVICT_BATCH: usize = 16;
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SwapStatistics {
+    pub total_slots: u64,
+    pub used_slots: u64,
+    // スワップアウトの候補になっているメモリ上のページ数
+    pub resident_pages: u64,
+    pub swap_outs: u64,
+    pub swap_ins: u64,
+    // アクセスされていたので追い出さなかった回数
+    pub second_chances: u64,
+}
+
+struct Swap {
+    // ディスクの入出力はクリティカルセクションの外で行う
+    // その間はデバイスを取り出しておき、ほかのCPUは戻されるまで待つ
+    device: Option<Box<dyn BlockDevice + Send>>,
+    // スロットはディスク上の1ページ分の領域
+    slots: BitVec,
+    // スワップアウトの候補を時計の文字盤のように並べ、handの位置から順に調べる
+    resident: Vec<Page>,
+    hand: usize,
+    statistics: SwapStatistics,
+}
+
+static SWAP: Mutex<RefCell<Option<Swap>>> = Mutex::new(RefCell::new(None));
+
+// デバイスは ata<バス>.<ドライブ> で指定する (例: ata0.1 はプライマリのスレーブ)
+// ディスク全体をスワップ領域として使うので、中身は壊れる
+pub fn initialize(device: &str) -> Result<(), &'static str> {
+    let (bus, drive) = device
+        .strip_prefix("ata")
+        .and_then(|device| device.split_once('.'))
+        .ok_or("Invalid swap device")?;
+    let bus = bus.parse().map_err(|_| "Invalid swap device")?;
+    let slave = match drive {
+        "0" => false,
+        "1" => true,
+        _ => return Err("Invalid swap device"),
+    };
+
+    let ata = AtaPio::new(bus, slave).map_err(|_| "Swap device is not found")?;
+    let total_slots = ata.sector_count() / SECTORS_PER_PAGE;
+
+    critical_section::with(|cs| {
+        *SWAP.borrow_ref_mut(cs) = Some(Swap {
+            device: Some(Box::new(ata)),
+            slots: BitVec::repeat(false, total_slots as usize),
+            resident: Vec::new(),
+            hand: 0,
+            statistics: SwapStatistics {
+                total_slots: total_slots,
+                ..Default::default()
+            },
+        })
+    });
+
+    let mut buf = [0u8; 128];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "swap: {}MiB on {}\n",
+            total_slots * PAGE_SIZE / 1024 / 1024,
+            device
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+
+    Ok(())
+}
+
+// スワップアウトしてよいページとして登録する
+pub fn track(page: Page) {
+    critical_section::with(|cs| {
+        if let Some(swap) = SWAP.borrow_ref_mut(cs).as_mut() {
+            swap.resident.push(page);
+        }
+    })
+}
+
+// フレームを確保する (足りなければページを追い出してから確保する)
+// ページフォールトの中は割り込みが止まっているので、アロケータは追い出さずに失敗する
+pub fn allocate_frame() -> Result<usize, &'static str> {
+    if let Ok(addr) = ALLOC.allocate_frames(1) {
+        return Ok(addr);
+    }
+
+    evict(EVICT_BATCH)?;
+    ALLOC.allocate_frames(1)
+}
+
+// アロケータがフレームを確保できなかったときに呼ばれ、追い出せたらtrueを返す
+// 割り込みハンドラやクリティカルセクションの中 (割り込みが止まっているとき) は、
+// 割り込まれたコードがデバイスやページテーブルを使っているかもしれないので追い出さない
+pub fn relieve_memory_pressure() -> bool {
+    if !interrupts::are_enabled() {
+        return false;
+    }
+    evict(EVICT_BATCH).is_ok()
+}
+
+// スワップアウトされたページをディスクから読み戻してマップする
+pub fn swap_in(page: Page, slot: u64, flags: PageTableFlags) -> Result<(), &'static str> {
+    let frame_addr = allocate_frame()?;
+
+    // 追い出し中のページなら、書き込みが終わってデバイスが戻されるまで待つ
+    let (mut device, ()) = take_device(|_| Ok(()))?;
+    let buf = unsafe { slice::from_raw_parts_mut(frame_addr as *mut u8, PAGE_SIZE as usize) };
+    let result = device.read_sectors(slot * SECTORS_PER_PAGE, buf);
+
+    return_device(device, |swap| {
+        // 待っている間にほかのCPUが読み戻したか、領域が解放された
+        if paging::swap_slot(page) != Some(slot) {
+            ALLOC.deallocate_frames(frame_addr, 1);
+            return Ok(());
+        }
+        if result.is_err() {
+            ALLOC.deallocate_frames(frame_addr, 1);
+            return Err("Failed to read swapped out page");
+        }
+
+        paging::clear_swap_entry(page);
+        let frame = PhysFrame::containing_address(PhysAddr::new(frame_addr as u64));
+        if paging::map(page, frame, flags).is_err() {
+            ALLOC.deallocate_frames(frame_addr, 1);
+            return Err("Failed to map swapped in page");
+        }
+
+        swap.slots.set(slot as usize, false);
+        swap.resident.push(page);
+        swap.statistics.swap_ins += 1;
+        Ok(())
+    })
+}
+
+// クロック方式で、最近アクセスされていないページを追い出す
+// ページは1つずつ、選んでアンマップするところと書き込みを終えたところだけロックを取る
+fn evict(count: usize) -> Result<usize, &'static str> {
+    let mut evicted = 0;
+    while evicted < count {
+        let (mut device, (page, slot, frame)) = match take_device(|swap| swap.unmap_victim()) {
+            Ok(taken) => taken,
+            Err(reason) if evicted == 0 => return Err(reason),
+            Err(_) => break,
+        };
+
+        let buf = unsafe {
+            slice::from_raw_parts(
+                frame.start_address().as_u64() as *const u8,
+                PAGE_SIZE as usize,
+            )
+        };
+        let result = device.write_sectors(slot * SECTORS_PER_PAGE, buf);
+
+        return_device(device, |swap| {
+            swap.finish_swap_out(page, slot, frame, result.is_ok())
+        })?;
+        evicted += 1;
+    }
+    Ok(evicted)
+}
+
+// デバイスを取り出し、同じロックの中でfも実行する
+// ほかのCPUがデバイスを使っていれば、割り込みを止めずに待つ
+fn take_device<R>(
+    mut f: impl FnMut(&mut Swap) -> Result<R, &'static str>,
+) -> Result<(Box<dyn BlockDevice + Send>, R), &'static str> {
+    loop {
+        let taken = critical_section::with(|cs| {
+            let mut swap = SWAP.borrow_ref_mut(cs);
+            let swap = swap.as_mut().ok_or("Swap is not enabled")?;
+            if swap.device.is_none() {
+                return Ok(None);
+            }
+            let value = f(swap)?;
+            Ok(swap.device.take().map(|device| (device, value)))
+        })?;
+        if let Some(taken) = taken {
+            return Ok(taken);
+        }
+        core::hint::spin_loop();
+    }
+}
+
+// デバイスを戻し、同じロックの中で入出力の結果を反映する
+fn return_device<R>(device: Box<dyn BlockDevice + Send>, f: impl FnOnce(&mut Swap) -> R) -> R {
+    critical_section::with(|cs| {
+        let mut swap = SWAP.borrow_ref_mut(cs);
+        // デバイスを取り出せたならスワップは有効になっている (無効に戻すことはない)
+        let swap = swap.as_mut().unwrap();
+        swap.device = Some(device);
+        f(swap)
+    })
+}
+
+// 領域を解放する前に、候補から外してスワップアウト済みのスロットを返す
+pub fn forget(start: VirtAddr, end: VirtAddr) {
+    critical_section::with(|cs| {
+        let mut swap = SWAP.borrow_ref_mut(cs);
+        let swap = match swap.as_mut() {
+            Some(swap) => swap,
+            None => return,
+        };
+
+        swap.resident
+            .retain(|page| page.start_address() < start || end <= page.start_address());
+        swap.hand = 0;
+
+        let mut addr = start;
+        while addr < end {
+            let page = Page::containing_address(addr);
+            if let Some(slot) = paging::swap_slot(page) {
+                paging::clear_swap_entry(page);
+                swap.slots.set(slot as usize, false);
+            }
+            addr += PAGE_SIZE;
+        }
+    })
+}
+
+pub fn statistics() -> Option<SwapStatistics> {
+    critical_section::with(|cs| {
+        SWAP.borrow_ref(cs).as_ref().map(|swap| SwapStatistics {
+            used_slots: swap.slots.count_ones() as u64,
+            resident_pages: swap.resident.len() as u64,
+            ..swap.statistics
+        })
+    })
+}
+
+pub fn print_statistics() {
+    let statistics = match statistics() {
+        Some(statistics) => statistics,
+        None => return,
+    };
+
+    let mut buf = [0u8; 256];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "swap: used: {}/{} slots, resident: {} pages, out: {}, in: {}, second chances: {}\n",
+            statistics.used_slots,
+            statistics.total_slots,
+            statistics.resident_pages,
+            statistics.swap_outs,
+            statistics.swap_ins,
+            statistics.second_chances
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+}
+
+// 領域に書き込んでから読み戻し、スワップを通しても内容が変わらないことを確かめる
+// (空きメモリより大きなサイズを指定する)
+pub fn self_test(size: usize) -> Result<(), &'static str> {
+    let area = vma::reserve_swappable(
+        size,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        "swap test",
+    )?;
+    let pages = (area.end - area.start) / PAGE_SIZE;
+    let words = PAGE_SIZE / 8;
+
+    for i in 0..pages {
+        let page = (area.start + i * PAGE_SIZE).as_mut_ptr::<u64>();
+        for j in 0..words {
+            unsafe { ptr::write_volatile(page.add(j as usize), (i << 32) | j) };
+        }
+    }
+
+    let mut errors = 0;
+    for i in 0..pages {
+        let page = (area.start + i * PAGE_SIZE).as_ptr::<u64>();
+        for j in 0..words {
+            if unsafe { ptr::read_volatile(page.add(j as usize)) } != (i << 32) | j {
+                errors += 1;
+            }
+        }
+    }
+
+    print_statistics();
+    vma::release(area.start)?;
+
+    let mut buf = [0u8; 128];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "swap test: {}MiB, {} errors\n",
+            pages * PAGE_SIZE / 1024 / 1024,
+            errors
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+
+    if errors > 0 {
+        return Err("Swap test failed");
+    }
+    Ok(())
+}
+
+impl Swap {
+    // 追い出すページを選び、スロットを確保してアンマップする
+    // 書き込みが終わるまでフレームは解放しない (その間のページフォールトはデバイスが戻るのを待つ)
+    fn unmap_victim(&mut self) -> Result<(Page, u64, PhysFrame), &'static str> {
+        let slot = self.slots.first_zero().ok_or("Swap space is full")?;
+        let mut scanned = 0;
+
+        // 一周すればアクセスビットはすべて落ちているので、二周までで諦める
+        while !self.resident.is_empty() && scanned < self.resident.len() * 2 {
+            if self.hand >= self.resident.len() {
+                self.hand = 0;
+            }
+            let page = self.resident[self.hand];
+            scanned += 1;
+
+            match paging::test_and_clear_accessed(page) {
+                Ok(true) => {
+                    self.statistics.second_chances += 1;
+                    self.hand += 1;
+                }
+                Ok(false) => {
+                    let frame = paging::swap_out(page, slot as u64)
+                        .map_err(|_| "Failed to unmap swapped out page")?;
+                    self.resident.remove(self.hand);
+                    self.slots.set(slot, true);
+                    return Ok((page, slot as u64, frame));
+                }
+                // すでにマップされていないページは候補から外す
+                Err(_) => {
+                    self.resident.remove(self.hand);
+                }
+            }
+        }
+
+        Err("No page can be swapped out")
+    }
+
+    fn finish_swap_out(
+        &mut self,
+        page: Page,
+        slot: u64,
+        frame: PhysFrame,
+        written: bool,
+    ) -> Result<(), &'static str> {
+        // 書き込み中に領域が解放されていれば、スロットはもう返されている
+        if written || paging::swap_slot(page) != Some(slot) {
+            ALLOC.deallocate_frames(frame.start_address().as_u64() as usize, 1);
+            if written {
+                self.statistics.swap_outs += 1;
+            }
+            return Ok(());
+        }
+
+        // 書き込めなかったので、元のフレームをマップし直す
+        paging::clear_swap_entry(page);
+        self.slots.set(slot as usize, false);
+        let flags = vma::find(page.start_address())
+            .map(|area| area.flags)
+            .ok_or("Swapped out page is not in any virtual memory area")?;
+        paging::map(page, frame, flags)
+            .map_err(|_| "Failed to map page back after swap write error")?;
+        self.resident.push(page);
+        Err("Failed to write page to swap")
+    }
+}
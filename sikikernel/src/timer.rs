@@ -0,0 +1,422 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use critical_section::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::apic::{
+    self, LAPIC_LVT_TIMER, LAPIC_TIMER_CURRENT_COUNT, LAPIC_TIMER_DIVIDE, LAPIC_TIMER_INITIAL_COUNT,
+};
+use crate::drivers::hpet::hpet::{self, TimerMode};
+use crate::interrupts::{register_handler, InterruptFrame};
+use crate::pit;
+use crate::print_serial;
+use crate::tsc;
+use crate::write::write_to;
+
+pub const TIMER_VECTOR: u8 = 0x40;
+
+// 1秒あたりのティック数
+pub const TICK_HZ: u64 = 1000;
+const NS_PER_TICK: u64 = 1_000_000_000 / TICK_HZ;
+
+const CALIBRATION_MS: u64 = 10;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 1 << 17;
+// 分周比16
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+// 同時に登録できるソフトウェアタイマーの数
+// ティック割り込みの中でメモリを確保しないように、この分のリストを先に確保しておく
+const MAX_TIMERS: usize = 64;
+
+// self_testで自分自身を取り消すタイマーが呼ばれる回数
+const SELF_TEST_SELF_CANCEL_CALLS: u64 = 3;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+static BOOT_HPET: AtomicU64 = AtomicU64::new(0);
+// 経過時間を数えるクロック (ClockSourceの値、初期化の前は0)
+static CLOCK_SOURCE: AtomicU8 = AtomicU8::new(0);
+// 分周後のAPICタイマーの周波数 (Hz)
+static APIC_TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(1);
+// 登録されているタイマーの数
+static TIMER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+// コールバックはティック割り込みの中 (割り込みが止まった状態) で呼ばれるので、
+// 短く済ませ、メモリの確保や待ち (sleepなど) はしないこと
+struct SoftwareTimer {
+    id: TimerId,
+    // 起動からの経過時間 (ns) で表した次の期限
+    deadline: u64,
+    // Noneなら一度だけ
+    period: Option<u64>,
+    // コールバックを呼んでいる間は取り出してNoneにしておく
+    callback: Option<Box<dyn FnMut() + Send>>,
+}
+
+static TIMERS: Mutex<RefCell<Vec<SoftwareTimer>>> = Mutex::new(RefCell::new(Vec::new()));
+
+// 経過時間を数えるクロック
+// 途中で切り替えると時刻が戻ることがあるので、初期化のときに1つ選んで使い続ける
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+enum ClockSource {
+    // 周波数が一定のTSC
+    Tsc = 1,
+    // 64ビットのHPETのカウンタ
+    Hpet,
+    // ティック数 (割り込みが止まっている間は進まない)
+    Ticks,
+}
+
+// ティック割り込みの元になるタイマー
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TickSource {
+    Apic,
+    Hpet,
+}
+
+// TSCとAPICタイマーを校正し、TICK_HZの周期でティック割り込みを起こす
+// APICタイマーが使えないとき (またはforce_hpetのとき) はHPETのコンパレータを使う
+pub fn initialize(force_hpet: bool) -> Result<TickSource, &'static str> {
+    BOOT_TSC.store(tsc::read(), Ordering::Relaxed);
+    BOOT_HPET.store(hpet::read_counter().unwrap_or(0), Ordering::Relaxed);
+    let tsc_frequency = tsc::calibrate();
+
+    if !apic::is_initialized() {
+        return Err("Local APIC is not initialized");
+    }
+
+    let clock = if tsc::is_invariant() {
+        ClockSource::Tsc
+    } else if hpet::has_64bit_counter() {
+        ClockSource::Hpet
+    } else {
+        ClockSource::Ticks
+    };
+    critical_section::with(|cs| TIMERS.borrow_ref_mut(cs).reserve_exact(MAX_TIMERS));
+
+    let source = if force_hpet {
+        start_hpet_tick().map(|_| TickSource::Hpet)
+    } else {
+        start_apic_tick()
+            .map(|_| TickSource::Apic)
+            .or_else(|reason| {
+                let mut buf = [0u8; 128];
+                let _s: &str = write_to::show(
+                    &mut buf,
+                    format_args!("timer: {}, falling back to HPET\n", reason),
+                )
+                .unwrap();
+                print_serial(_s);
+                start_hpet_tick().map(|_| TickSource::Hpet)
+            })
+    }?;
+    // ティック数を使うときは、ティックが始まってから切り替える
+    CLOCK_SOURCE.store(clock as u8, Ordering::Relaxed);
+
+    let mut buf = [0u8; 128];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "timer: TSC {}MHz{}, {} tick {}Hz (calibrated with {}), clock: {:?}\n",
+            tsc_frequency / 1_000_000,
+            if clock == ClockSource::Tsc {
+                " (invariant)"
+            } else {
+                ""
+            },
+            match source {
+                TickSource::Apic => "APIC timer",
+                TickSource::Hpet => "HPET",
+            },
+            TICK_HZ,
+            if hpet::is_available() { "HPET" } else { "PIT" },
+            clock
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+
+    Ok(source)
+}
+
+fn start_apic_tick() -> Result<(), &'static str> {
+    apic::write_register(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    apic::write_register(LAPIC_LVT_TIMER, LVT_MASKED);
+    apic::write_register(LAPIC_TIMER_INITIAL_COUNT, u32::MAX);
+    calibration_wait(CALIBRATION_MS);
+    let remaining = apic::read_register(LAPIC_TIMER_CURRENT_COUNT).unwrap_or(u32::MAX);
+    apic::write_register(LAPIC_TIMER_INITIAL_COUNT, 0);
+
+    let frequency = (u32::MAX - remaining) as u64 * 1000 / CALIBRATION_MS;
+    if frequency < TICK_HZ {
+        return Err("Failed to calibrate APIC timer");
+    }
+    APIC_TIMER_FREQUENCY.store(frequency, Ordering::Relaxed);
+
+    register_handler(TIMER_VECTOR, handle_tick)?;
+    apic::write_register(LAPIC_LVT_TIMER, LVT_PERIODIC | TIMER_VECTOR as u32);
+    apic::write_register(LAPIC_TIMER_INITIAL_COUNT, (frequency / TICK_HZ) as u32);
+    Ok(())
+}
+
+// HPETのコンパレータ0を周期モードにしてI/O APIC経由でティックを受け取る
+fn start_hpet_tick() -> Result<(), &'static str> {
+    hpet::start_timer(
+        0,
+        TimerMode::Periodic,
+        Duration::from_nanos(NS_PER_TICK),
+        TIMER_VECTOR,
+        handle_tick,
+    )
+}
+
+// 校正の基準にする待ち (HPETがあればHPET、なければPITで数える)
+pub fn calibration_wait(ms: u64) {
+    if hpet::wait(Duration::from_millis(ms)).is_err() {
+        pit::wait(ms);
+    }
+}
+
+fn clock_source() -> Option<ClockSource> {
+    match CLOCK_SOURCE.load(Ordering::Relaxed) {
+        1 => Some(ClockSource::Tsc),
+        2 => Some(ClockSource::Hpet),
+        3 => Some(ClockSource::Ticks),
+        _ => None,
+    }
+}
+
+// 起動してからのティック数 (単調増加)
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// 起動してからの経過時間 (ns、単調増加)
+// 周波数が一定のTSCがあればそれを使い、なければ64ビットのHPETのカウンタ、ティック数の順に使う
+// (timer::initializeの前は0)
+pub fn uptime_ns() -> u64 {
+    match clock_source() {
+        Some(ClockSource::Tsc) => tsc::ticks_to_ns(tsc::read() - BOOT_TSC.load(Ordering::Relaxed)),
+        Some(ClockSource::Hpet) => {
+            let counter = hpet::read_counter().unwrap_or(0);
+            hpet::counter_to_ns(counter - BOOT_HPET.load(Ordering::Relaxed))
+        }
+        Some(ClockSource::Ticks) => ticks() * NS_PER_TICK,
+        None => 0,
+    }
+}
+
+pub fn uptime() -> Duration {
+    Duration::from_nanos(uptime_ns())
+}
+
+// 指定した時間が経つまで待つ
+// 割り込みが有効ならティックごとにhltで休み、無効ならクロックを見ながら待つ
+pub fn sleep(duration: Duration) {
+    // ティック数のクロックは割り込みが止まっていると進まないので、TSCで数えて待つ
+    // (周波数が変わるTSCでも、待ちの長さがずれるだけで時刻には使わない)
+    let clock = clock_source();
+    if clock.is_none() || (clock == Some(ClockSource::Ticks) && !interrupts::are_enabled()) {
+        let start = tsc::read();
+        let duration = duration.as_nanos() as u64;
+        while tsc::ticks_to_ns(tsc::read() - start) < duration {
+            core::hint::spin_loop();
+        }
+        return;
+    }
+
+    let deadline = uptime_ns().saturating_add(duration.as_nanos() as u64);
+    while uptime_ns() < deadline {
+        if interrupts::are_enabled() && ticks() > 0 {
+            unsafe { asm!("hlt", options(nomem, nostack)) };
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+// delayの後に一度だけcallbackを呼ぶ
+// MAX_TIMERSまで登録されていればエラーを返す
+pub fn add_one_shot<F>(delay: Duration, callback: F) -> Result<TimerId, &'static str>
+where
+    F: FnMut() + Send + 'static,
+{
+    add_timer(delay, None, Box::new(callback))
+}
+
+// periodごとにcallbackを呼ぶ
+pub fn add_periodic<F>(period: Duration, callback: F) -> Result<TimerId, &'static str>
+where
+    F: FnMut() + Send + 'static,
+{
+    let period = (period.as_nanos() as u64).max(NS_PER_TICK);
+    add_timer(
+        Duration::from_nanos(period),
+        Some(period),
+        Box::new(callback),
+    )
+}
+
+fn add_timer(
+    delay: Duration,
+    period: Option<u64>,
+    callback: Box<dyn FnMut() + Send>,
+) -> Result<TimerId, &'static str> {
+    TIMER_COUNT
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+            (count < MAX_TIMERS).then_some(count + 1)
+        })
+        .map_err(|_| "Too many software timers")?;
+
+    let id = TimerId(NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed));
+    let timer = SoftwareTimer {
+        id: id,
+        deadline: uptime_ns().saturating_add(delay.as_nanos() as u64),
+        period: period,
+        callback: Some(callback),
+    };
+    critical_section::with(|cs| TIMERS.borrow_ref_mut(cs).push(timer));
+    Ok(id)
+}
+
+// タイマーを取り消す (すでに期限が来て消えていればfalse)
+// コールバックの最中に取り消したときは、そのコールバックが最後の呼び出しになる
+pub fn cancel(id: TimerId) -> bool {
+    critical_section::with(|cs| {
+        let mut timers = TIMERS.borrow_ref_mut(cs);
+        match timers.iter().position(|timer| timer.id == id) {
+            Some(index) => {
+                timers.swap_remove(index);
+                TIMER_COUNT.fetch_sub(1, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+fn handle_tick(_frame: &mut InterruptFrame) {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    run_timers(uptime_ns());
+}
+
+fn run_timers(now: u64) {
+    // コールバックの中でタイマーを追加・取り消しできるように、期限が来たもののコールバックを1つずつ取り出して呼ぶ
+    // タイマーはリストに残しておき、呼んでいる間にcancelされたら戻さずに捨てる
+    // (割り込まれた処理がリストを使っていれば次のティックに回す)
+    loop {
+        let timer = critical_section::with(|cs| {
+            let mut timers = TIMERS.borrow(cs).try_borrow_mut().ok()?;
+            let timer = timers
+                .iter_mut()
+                .find(|timer| timer.deadline <= now && timer.callback.is_some())?;
+            Some((timer.id, timer.callback.take()?))
+        });
+        let (id, mut callback) = match timer {
+            Some(timer) => timer,
+            None => return,
+        };
+
+        callback();
+        critical_section::with(|cs| {
+            let mut timers = TIMERS.borrow_ref_mut(cs);
+            let index = match timers.iter().position(|timer| timer.id == id) {
+                Some(index) => index,
+                // 取り消されていた
+                None => return,
+            };
+            match timers[index].period {
+                Some(period) => {
+                    // 遅れた分はまとめて飛ばす
+                    let timer = &mut timers[index];
+                    while timer.deadline <= now {
+                        timer.deadline += period;
+                    }
+                    timer.callback = Some(callback);
+                }
+                None => {
+                    timers.swap_remove(index);
+                    TIMER_COUNT.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
+        });
+    }
+}
+
+// 一度だけのタイマーと周期タイマーを登録し、呼ばれることと取り消せることを確かめる
+// (割り込みが有効な状態で呼ぶ)
+pub fn self_test() -> Result<(), &'static str> {
+    if clock_source().is_none() || !interrupts::are_enabled() {
+        return Err("Timer is not running");
+    }
+    let timers = TIMER_COUNT.load(Ordering::Relaxed);
+
+    let one_shot_calls = Arc::new(AtomicU64::new(0));
+    let calls = one_shot_calls.clone();
+    let one_shot = add_one_shot(Duration::from_millis(10), move || {
+        calls.fetch_add(1, Ordering::Relaxed);
+    })?;
+
+    let periodic_calls = Arc::new(AtomicU64::new(0));
+    let calls = periodic_calls.clone();
+    let periodic = add_periodic(Duration::from_millis(5), move || {
+        calls.fetch_add(1, Ordering::Relaxed);
+    })?;
+
+    // コールバックの中で自分自身を取り消す
+    let self_cancel_id = Arc::new(AtomicU64::new(0));
+    let self_cancel_calls = Arc::new(AtomicU64::new(0));
+    let (id, calls) = (self_cancel_id.clone(), self_cancel_calls.clone());
+    let self_cancel = add_periodic(Duration::from_millis(5), move || {
+        if calls.fetch_add(1, Ordering::Relaxed) + 1 == SELF_TEST_SELF_CANCEL_CALLS {
+            cancel(TimerId(id.load(Ordering::Relaxed)));
+        }
+    })?;
+    self_cancel_id.store(self_cancel.0, Ordering::Relaxed);
+
+    sleep(Duration::from_millis(100));
+    let cancelled = cancel(periodic);
+    let fired = periodic_calls.load(Ordering::Relaxed);
+    sleep(Duration::from_millis(20));
+
+    let mut buf = [0u8; 128];
+    let _s: &str = write_to::show(
+        &mut buf,
+        format_args!(
+            "timer test: one-shot {} calls, periodic {} calls, self-cancel {} calls\n",
+            one_shot_calls.load(Ordering::Relaxed),
+            fired,
+            self_cancel_calls.load(Ordering::Relaxed)
+        ),
+    )
+    .unwrap();
+    print_serial(_s);
+
+    if one_shot_calls.load(Ordering::Relaxed) != 1 || cancel(one_shot) {
+        return Err("One-shot timer did not fire exactly once");
+    }
+    if fired < 2 || !cancelled || periodic_calls.load(Ordering::Relaxed) != fired {
+        return Err("Periodic timer did not fire or could not be cancelled");
+    }
+    if self_cancel_calls.load(Ordering::Relaxed) != SELF_TEST_SELF_CANCEL_CALLS {
+        return Err("Periodic timer could not cancel itself");
+    }
+    if TIMER_COUNT.load(Ordering::Relaxed) != timers {
+        return Err("Timer count did not return to its initial value");
+    }
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::timer;
+
+const CALIBRATION_MS: u64 = 10;
+
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+// HPET (なければPITのチャンネル2) で一定時間を数え、その間に進んだTSCから周波数を求める
+pub fn calibrate() -> u64 {
+    let start = read();
+    timer::calibration_wait(CALIBRATION_MS);
+    let end = read();
+
+    let frequency = (end - start) * 1000 / CALIBRATION_MS;
+    TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+    frequency
+}
+
+// 校正済みのTSCの周波数 (Hz)
+pub fn frequency() -> u64 {
+    match TSC_FREQUENCY.load(Ordering::Relaxed) {
+        0 => calibrate(),
+        frequency => frequency,
+    }
+}
+
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    (ticks as u128 * 1_000_000_000 / frequency() as u128) as u64
+}
+
+// CPUID.80000007h:EDX[8] 省電力状態でも一定の速さで進むTSC
+pub fn is_invariant() -> bool {
+    if unsafe { __cpuid(0x8000_0000) }.eax < 0x8000_0007 {
+        return false;
+    }
+    unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
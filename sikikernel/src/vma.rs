@@ -0,0 +1,175 @@
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::allocator::ALLOC;
+use crate::paging::{self, KERNEL_VMA_END, KERNEL_VMA_START, PAGE_SIZE};
+use crate::swap;
+
+// 仮想アドレスだけ予約し、最初にアクセスされたときにゼロ埋めしたフレームを割り当てる領域
+#[derive(Debug, Copy, Clone)]
+pub struct VirtualMemoryArea {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub flags: PageTableFlags,
+    pub name: &'static str,
+    // フレームが足りなくなったらスワップアウトしてよいか
+    pub swappable: bool,
+}
+
+impl VirtualMemoryArea {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+struct VirtualMemoryAreas {
+    areas: Vec<VirtualMemoryArea>,
+    next_address: u64,
+}
+
+static AREAS: Mutex<RefCell<VirtualMemoryAreas>> = Mutex::new(RefCell::new(VirtualMemoryAreas {
+    areas: Vec::new(),
+    next_address: KERNEL_VMA_START,
+}));
+
+// 仮想アドレス空間を予約する (物理フレームは割り当てない)
+pub fn reserve(
+    size: usize,
+    flags: PageTableFlags,
+    name: &'static str,
+) -> Result<VirtualMemoryArea, &'static str> {
+    reserve_area(size, flags, name, false)
+}
+
+// ページをスワップアウトしてよい領域を予約する
+// (スタックなど、ページフォールトを起こせない用途には使わない)
+pub fn reserve_swappable(
+    size: usize,
+    flags: PageTableFlags,
+    name: &'static str,
+) -> Result<VirtualMemoryArea, &'static str> {
+    reserve_area(size, flags, name, true)
+}
+
+fn reserve_area(
+    size: usize,
+    flags: PageTableFlags,
+    name: &'static str,
+    swappable: bool,
+) -> Result<VirtualMemoryArea, &'static str> {
+    let size = (size as u64).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+
+    critical_section::with(|cs| {
+        let mut areas = AREAS.borrow_ref_mut(cs);
+
+        // 領域の手前にはマップしないページを1つ挟む (ガードページ)
+        let start = areas.next_address + PAGE_SIZE;
+        let end = start + size;
+        if end > KERNEL_VMA_END {
+            return Err("Virtual address space is exhausted");
+        }
+
+        let area = VirtualMemoryArea {
+            start: VirtAddr::new(start),
+            end: VirtAddr::new(end),
+            flags: flags | PageTableFlags::PRESENT,
+            name: name,
+            swappable: swappable,
+        };
+        areas.areas.push(area);
+        areas.next_address = end;
+
+        Ok(area)
+    })
+}
+
+// 領域全体にあらかじめフレームを割り当てる
+// (スタックのようにページフォールトを起こせない領域に使う)
+pub fn populate(area: &VirtualMemoryArea) -> Result<(), &'static str> {
+    let mut addr = area.start;
+    while addr < area.end {
+        handle_page_fault(addr)?;
+        addr += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+// 領域を解放し、割り当て済みのフレームを返す
+pub fn release(start: VirtAddr) -> Result<(), &'static str> {
+    let area = critical_section::with(|cs| {
+        let mut areas = AREAS.borrow_ref_mut(cs);
+        let index = areas
+            .areas
+            .iter()
+            .position(|area| area.start == start)
+            .ok_or("Virtual memory area is not found")?;
+        Ok(areas.areas.remove(index))
+    })?;
+
+    // スワップアウトされているページはスロットを返す
+    if area.swappable {
+        swap::forget(area.start, area.end);
+    }
+
+    let mut addr = area.start;
+    while addr < area.end {
+        if let Ok(frame) = paging::unmap(Page::containing_address(addr)) {
+            ALLOC.deallocate_frames(frame.start_address().as_u64() as usize, 1);
+        }
+        addr += PAGE_SIZE;
+    }
+
+    Ok(())
+}
+
+pub fn find(addr: VirtAddr) -> Option<VirtualMemoryArea> {
+    critical_section::with(|cs| {
+        AREAS
+            .borrow_ref(cs)
+            .areas
+            .iter()
+            .find(|area| area.contains(addr))
+            .copied()
+    })
+}
+
+// ページフォールトしたアドレスが予約済みの領域内ならフレームを割り当てる
+pub fn handle_page_fault(addr: VirtAddr) -> Result<(), &'static str> {
+    let area = find(addr).ok_or("Address is not in any virtual memory area")?;
+    let page = Page::containing_address(addr);
+
+    if !area.swappable {
+        let frame_addr = ALLOC.allocate_frames(1)?;
+        return map_zeroed_frame(page, frame_addr, area.flags);
+    }
+
+    // スワップアウトされたページならディスクから読み戻す
+    if let Some(slot) = paging::swap_slot(page) {
+        return swap::swap_in(page, slot, area.flags);
+    }
+
+    let frame_addr = swap::allocate_frame()?;
+    map_zeroed_frame(page, frame_addr, area.flags)?;
+    swap::track(page);
+    Ok(())
+}
+
+fn map_zeroed_frame(
+    page: Page,
+    frame_addr: usize,
+    flags: PageTableFlags,
+) -> Result<(), &'static str> {
+    unsafe {
+        core::ptr::write_bytes(frame_addr as *mut u8, 0, PAGE_SIZE as usize);
+    }
+
+    let frame = PhysFrame::containing_address(PhysAddr::new(frame_addr as u64));
+    paging::map(page, frame, flags).map_err(|_| {
+        ALLOC.deallocate_frames(frame_addr, 1);
+        "Failed to map demand paged frame"
+    })
+}
@@ -13,8 +13,8 @@ use core::u8;
 
 use alloc::vec::Vec;
 
-use lib::{FrameBufferInfo, MemoryDescriptor, MemoryMap, ModeInfo};
-use lib::{SikiOSArguments, MEMORY_MAP_SIZE};
+use lib::{CommandLine, FrameBufferInfo, KernelImageInfo, KernelSegment, MemoryMap, ModeInfo};
+use lib::{SikiOSArguments, KERNEL_SEGMENT_MAX, MEMORY_MAP_SIZE};
 
 use goblin::elf::{self};
 
@@ -23,6 +23,7 @@ use uefi::proto::media::file::Directory;
 use uefi::proto::media::file::RegularFile;
 use uefi::table::boot::MemoryMapIter;
 use uefi::table::boot::MemoryMapSize;
+use uefi::table::cfg::{ACPI2_GUID, ACPI_GUID};
 use uefi::{
     global_allocator::exit_boot_services,
     prelude::*,
@@ -113,15 +114,78 @@ fn load_file(dir: &mut Directory, file_name: &uefi::CStr16) -> RegularFile {
     .unwrap()
 }
 
-fn entry_kernel(entry: u64, args: &SikiOSArguments) {
-    let _start: extern "sysv64" fn(args: &SikiOSArguments) = unsafe { mem::transmute(entry) };
+// ルートディレクトリのcmdline.txtをカーネルのコマンドラインとして読む (なければ空)
+fn load_command_line(dir: &mut Directory) -> CommandLine {
+    let file = dir.open(
+        cstr16!("\\cmdline.txt"),
+        uefi::proto::media::file::FileMode::Read,
+        FileAttribute::from_bits(0).unwrap(),
+    );
+    let mut file = match file.ok().and_then(|file| file.into_regular_file()) {
+        Some(file) => file,
+        None => return CommandLine::default(),
+    };
+
+    let mut buffer = [0u8; lib::COMMAND_LINE_SIZE];
+    let len = file.read(&mut buffer).unwrap_or(0);
+    CommandLine::new(&buffer[..len])
+}
+
+// ACPI 2.0以降のRSDPを優先し、なければ1.0のRSDPを探す (見つからなければ0)
+fn find_rsdp(system_table: &SystemTable<Boot>) -> u64 {
+    for guid in [ACPI2_GUID, ACPI_GUID] {
+        if let Some(entry) = system_table
+            .config_table()
+            .iter()
+            .find(|entry| entry.guid == guid)
+        {
+            return entry.address as u64;
+        }
+    }
+    0
+}
+
+// ExitBootServicesで受け取ったメモリマップをカーネルに渡す形にする
+fn convert_memory_map(memory_map_iter: MemoryMapIter) -> MemoryMap {
+    let mut memory_map = MemoryMap {
+        map: [Default::default(); MEMORY_MAP_SIZE],
+        len: 0,
+    };
+    for (descriptor, value) in memory_map.map.iter_mut().zip(memory_map_iter) {
+        *descriptor = (*value).into();
+        memory_map.len += 1;
+    }
+    memory_map
+}
+
+// ブートサービスを終了してカーネルに入る
+// 終了した後はprintln!もメモリの確保・解放もできないので、必要なものは先に用意しておく
+fn entry_kernel(
+    entry: u64,
+    handle: Handle,
+    system_table: SystemTable<Boot>,
+    mut args: SikiOSArguments,
+) -> ! {
+    let _start: extern "sysv64" fn(args: &SikiOSArguments) -> ! = unsafe { mem::transmute(entry) };
+
+    // このバッファの確保で記述子が増えても収まるように余裕を持たせる
+    let memory_map_size = get_memory_map_size(system_table.boot_services());
+    let mut memory_map_buffer =
+        vec![0 as u8; memory_map_size.map_size + 8 * memory_map_size.entry_size];
 
     println!("Exit Boot Services");
+    println!("Enter Entry Point");
+
+    // メモリマップのキーが古ければ、取り直して成功するまで呼び直す
+    let (_runtime_table, memory_map_iter) = system_table
+        .exit_boot_services(handle, &mut memory_map_buffer)
+        .unwrap();
     exit_boot_services();
 
-    println!("Enter Entry Point");
+    // カーネルが解放するブートサービスの領域は、最後のメモリマップから求める
+    args.memory_map = convert_memory_map(memory_map_iter);
 
-    _start(args);
+    _start(&args);
 }
 
 #[entry]
@@ -142,6 +206,12 @@ fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     print_memory_map(&memory_map_iter);
     save_memory_map(&memory_map_iter, &mut root_dir);
 
+    let command_line = load_command_line(&mut root_dir);
+    println!("Command Line: {}", command_line.as_str());
+
+    let rsdp_address = find_rsdp(&system_table);
+    println!("RSDP: 0x{:x}", rsdp_address);
+
     println!("Load Kernel");
 
     let mut elf_file = load_file(&mut root_dir, cstr16!("\\kernel.elf"));
@@ -164,12 +234,21 @@ fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     // ロードする位置の最小値と最大値を求める destは目標の位置という意味
     let mut dest_first = usize::MAX;
     let mut dest_last = 0;
+    let mut segment_count = 0;
     for ph in elf.program_headers.iter() {
         if ph.p_type != elf::program_header::PT_LOAD {
             continue;
         }
         dest_first = dest_first.min(ph.p_vaddr as usize);
         dest_last = dest_last.max((ph.p_vaddr + ph.p_memsz) as usize);
+        segment_count += 1;
+    }
+    // セグメントの情報は固定長の配列でカーネルに渡す
+    if segment_count > KERNEL_SEGMENT_MAX {
+        panic!(
+            "Kernel has {} PT_LOAD segments, but at most {} are supported",
+            segment_count, KERNEL_SEGMENT_MAX
+        );
     }
 
     let load_size = dest_last as usize - dest_first;
@@ -191,6 +270,13 @@ fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
 
     println!("Kernel physical addr: 0x{:x}", _kernel_physical_addr);
 
+    let mut kernel_image = KernelImageInfo {
+        physical_start: _kernel_physical_addr,
+        number_of_pages: n_of_pages as u64,
+        segments: [Default::default(); KERNEL_SEGMENT_MAX],
+        segments_len: 0,
+    };
+
     // 内容をコピー
     for ph in elf.program_headers.iter() {
         if ph.p_type != elf::program_header::PT_LOAD {
@@ -202,6 +288,14 @@ fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
         let dest = unsafe { from_raw_parts_mut(ph.p_vaddr as *mut u8, msize) };
         dest[..fsize].copy_from_slice(&elf_buffer[ofs..ofs + fsize]);
         dest[fsize..].fill(0);
+
+        // カーネルがページの属性を決められるようにセグメントの情報を渡す
+        kernel_image.segments[kernel_image.segments_len] = KernelSegment {
+            virtual_start: ph.p_vaddr,
+            memory_size: ph.p_memsz,
+            flags: ph.p_flags,
+        };
+        kernel_image.segments_len += 1;
     }
 
     println!("Entry Point: 0x{:x}", elf.entry);
@@ -232,26 +326,22 @@ fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
         size: frame_buffer.size(),
     };
 
-    let mut memory_map: [MemoryDescriptor; MEMORY_MAP_SIZE] = [Default::default(); MEMORY_MAP_SIZE];
-
-    for (i, value) in memory_map_iter.clone().enumerate() {
-        memory_map[i].memory_type = value.ty.into();
-        memory_map[i].physical_start = value.phys_start;
-        memory_map[i].virtual_start = value.virt_start;
-        memory_map[i].number_of_pages = value.page_count;
-        memory_map[i].attribute = value.att.bits();
-    }
-
+    // メモリマップはブートサービスを終了するときに取り直す
     let args = SikiOSArguments {
         frame_buffer_info: frame_buffer_info,
         mode_info: mode_info,
         memory_map: MemoryMap {
-            map: memory_map,
-            len: memory_map_iter.len(),
+            map: [Default::default(); MEMORY_MAP_SIZE],
+            len: 0,
         },
+        kernel_image: kernel_image,
+        command_line: command_line,
+        rsdp_address: rsdp_address,
     };
 
-    entry_kernel(elf.entry, &args);
+    // プロトコルはブートサービスを終了する前に閉じる
+    drop(root_dir);
+    drop(simple_file_system);
 
-    Status::SUCCESS
+    entry_kernel(elf.entry, handle, system_table, args);
 }